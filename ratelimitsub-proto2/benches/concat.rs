@@ -0,0 +1,59 @@
+// Times stream_sub on deep Concat chains, where the case count blows up: a
+// chain of k phases has a case for every contiguous run of phases, and a Par
+// of chains has one for every combination of those. Everything runs on one
+// thread, so the numbers are just the solver's. Run with cargo bench.
+use ratelimitsub_proto2::{SolverConfig, parse, stream_sub_report_with};
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 3;
+
+// (. 10/5 20/6 30/7 ...), with depth phases.
+fn chain(depth: usize, offset: usize) -> String {
+    let phases: Vec<String> = (1..=depth)
+        .map(|i| format!("{}/{}", (i + offset) * 10, i + offset + 4))
+        .collect();
+    format!("(. {})", phases.join(" "))
+}
+
+fn bench(judgment: &str, config: &SolverConfig) {
+    let (lhs, rhs) = parse(judgment).unwrap();
+    let mut best = Duration::MAX;
+    let mut report = None;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        report = Some(stream_sub_report_with(&lhs, &rhs, config));
+        best = best.min(start.elapsed());
+    }
+    let report = report.unwrap();
+    println!(
+        "{:>10.1}ms  {:>5} cases  {:<5}  {}",
+        best.as_secs_f64() * 1000.0,
+        report.smt_cases,
+        report.holds(),
+        judgment
+    );
+}
+
+fn main() {
+    let config = SolverConfig {
+        threads: 1,
+        ..SolverConfig::default()
+    };
+    println!("best of {} runs", RUNS);
+    for depth in [2, 4, 6, 8] {
+        bench(
+            &format!("{} <: (|| 1000/10 100/1)", chain(depth, 0)),
+            &config,
+        );
+    }
+    for depth in [2, 3, 4] {
+        bench(
+            &format!(
+                "(|| {} {}) <: (|| 1000/10 100/1)",
+                chain(depth, 0),
+                chain(depth, 1)
+            ),
+            &config,
+        );
+    }
+}
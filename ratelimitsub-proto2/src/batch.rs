@@ -0,0 +1,305 @@
+use crate::json::{self, Json};
+use ratelimitsub_proto2::{ParseError, SolverConfig, SubResult, SubtypeCache, parse};
+use std::time::{Duration, Instant};
+
+// Batch mode: check a whole file of judgments, one per line, e.g.
+//
+//   # Comments start with #, and blank lines are skipped.
+//   (|| 10/5 12/4) <: 40/5 expect true
+//   10/5 <: 5/5 expect false
+//   10/5 <: 10/5
+//
+// Lines without an expect just get checked and reported.
+
+// One judgment line out of a batch file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Entry<'a> {
+    // Counting from 1, like an editor.
+    pub line_no: usize,
+    pub judgment: &'a str,
+    pub expect: Option<bool>,
+}
+
+pub enum Outcome {
+    Checked { holds: bool, time: Duration },
+    // The solver gave up, e.g. on a timeout.
+    Unknown { reason: String, time: Duration },
+    ParseFailed(ParseError),
+    BadLine(String),
+}
+
+pub struct BatchResult<'a> {
+    pub line_no: usize,
+    pub judgment: &'a str,
+    pub expect: Option<bool>,
+    pub outcome: Outcome,
+}
+
+impl BatchResult<'_> {
+    // A line passes if it parsed and matched its expect (if it has one). An
+    // unknown doesn't match either expect.
+    pub fn passed(&self) -> bool {
+        match self.outcome {
+            Outcome::Checked { holds, .. } => self.expect.is_none_or(|e| e == holds),
+            Outcome::Unknown { .. } => self.expect.is_none(),
+            Outcome::ParseFailed(_) | Outcome::BadLine(_) => false,
+        }
+    }
+}
+
+// Splits a line into the judgment and its expect. Returns None for comments
+// and blank lines.
+pub fn parse_line(line_no: usize, line: &str) -> Option<Result<Entry<'_>, String>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let entry = match split_expect(line) {
+        None => Ok(Entry {
+            line_no,
+            judgment: line,
+            expect: None,
+        }),
+        Some((judgment, expect)) => match expect {
+            "true" => Ok(Entry {
+                line_no,
+                judgment: judgment.trim(),
+                expect: Some(true),
+            }),
+            "false" => Ok(Entry {
+                line_no,
+                judgment: judgment.trim(),
+                expect: Some(false),
+            }),
+            other => Err(format!(
+                "expected expect true or expect false, found expect {}",
+                other
+            )),
+        },
+    };
+    Some(entry)
+}
+
+// Splits off the last expect on the line, as a word of its own (at the start
+// or after whitespace, and followed by whitespace or the end), along with
+// whatever comes after it.
+fn split_expect(line: &str) -> Option<(&str, &str)> {
+    line.rmatch_indices("expect").find_map(|(i, word)| {
+        let (judgment, rest) = (&line[..i], &line[i + word.len()..]);
+        let starts = judgment.is_empty() || judgment.ends_with(char::is_whitespace);
+        let ends = rest.is_empty() || rest.starts_with(char::is_whitespace);
+        (starts && ends).then(|| (judgment.trim(), rest.trim()))
+    })
+}
+
+pub fn run_batch<'a>(
+    input: &'a str,
+    config: &SolverConfig,
+    cache: &SubtypeCache,
+) -> Vec<BatchResult<'a>> {
+    input
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| parse_line(i + 1, line).map(|entry| (i + 1, line, entry)))
+        .map(|(line_no, line, entry)| match entry {
+            Err(msg) => BatchResult {
+                line_no,
+                judgment: line.trim(),
+                expect: None,
+                outcome: Outcome::BadLine(msg),
+            },
+            Ok(entry) => {
+                let outcome = match parse(entry.judgment) {
+                    Err(err) => Outcome::ParseFailed(err),
+                    Ok((lhs, rhs)) => {
+                        let start = Instant::now();
+                        let result = cache.check(&lhs, &rhs, config);
+                        let time = start.elapsed();
+                        match result {
+                            SubResult::Unknown(reason) => Outcome::Unknown { reason, time },
+                            SubResult::Holds | SubResult::Refuted(_) => Outcome::Checked {
+                                holds: result.holds(),
+                                time,
+                            },
+                        }
+                    }
+                };
+                BatchResult {
+                    line_no,
+                    judgment: entry.judgment,
+                    expect: entry.expect,
+                    outcome,
+                }
+            }
+        })
+        .collect()
+}
+
+pub fn print_table(results: &[BatchResult]) {
+    println!(
+        "{:>5}  {:<6}  {:<6}  {:<6}  {:>10}  judgment",
+        "line", "result", "expect", "status", "time"
+    );
+    let mut total = Duration::ZERO;
+    for res in results {
+        let expect = res.expect.map_or("-".to_string(), |e| e.to_string());
+        let status = if res.passed() { "ok" } else { "FAIL" };
+        match &res.outcome {
+            Outcome::Checked { holds, time } => {
+                total += *time;
+                println!(
+                    "{:>5}  {:<6}  {:<6}  {:<6}  {:>10}  {}",
+                    res.line_no,
+                    holds,
+                    expect,
+                    status,
+                    format_time(*time),
+                    res.judgment
+                );
+            }
+            Outcome::Unknown { reason, time } => {
+                total += *time;
+                println!(
+                    "{:>5}  {:<6}  {:<6}  {:<6}  {:>10}  {} ({})",
+                    res.line_no,
+                    "?",
+                    expect,
+                    status,
+                    format_time(*time),
+                    res.judgment,
+                    reason
+                );
+            }
+            Outcome::ParseFailed(err) => println!(
+                "{:>5}  {:<6}  {:<6}  {:<6}  {:>10}  {} ({})",
+                res.line_no, "error", expect, status, "-", res.judgment, err
+            ),
+            Outcome::BadLine(msg) => println!(
+                "{:>5}  {:<6}  {:<6}  {:<6}  {:>10}  {} ({})",
+                res.line_no, "error", expect, status, "-", res.judgment, msg
+            ),
+        }
+    }
+    let failed = results.iter().filter(|res| !res.passed()).count();
+    println!(
+        "{} judgments, {} passed, {} failed, {} total",
+        results.len(),
+        results.len() - failed,
+        failed,
+        format_time(total)
+    );
+}
+
+pub fn to_json(results: &[BatchResult]) -> Json {
+    Json::Array(
+        results
+            .iter()
+            .map(|res| {
+                let (holds, elapsed_ms, error) = match &res.outcome {
+                    Outcome::Checked { holds, time } => (
+                        Json::Bool(*holds),
+                        Json::Float(time.as_secs_f64() * 1000.0),
+                        Json::Null,
+                    ),
+                    // Neither true nor false, so holds is null, but it isn't
+                    // an error either.
+                    Outcome::Unknown { time, .. } => (
+                        Json::Null,
+                        Json::Float(time.as_secs_f64() * 1000.0),
+                        Json::Null,
+                    ),
+                    Outcome::ParseFailed(err) => (Json::Null, Json::Null, json::parse_error(err)),
+                    Outcome::BadLine(msg) => (
+                        Json::Null,
+                        Json::Null,
+                        Json::Object(vec![("message", Json::Str(msg.clone()))]),
+                    ),
+                };
+                Json::Object(vec![
+                    ("line", Json::Int(res.line_no)),
+                    ("judgment", Json::Str(res.judgment.to_string())),
+                    ("expect", res.expect.map_or(Json::Null, Json::Bool)),
+                    ("holds", holds),
+                    (
+                        "unknown_reason",
+                        match &res.outcome {
+                            Outcome::Unknown { reason, .. } => Json::Str(reason.clone()),
+                            _ => Json::Null,
+                        },
+                    ),
+                    ("passed", Json::Bool(res.passed())),
+                    ("elapsed_ms", elapsed_ms),
+                    ("error", error),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn format_time(time: Duration) -> String {
+    format!("{:.1}ms", time.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line(1, "  # a comment"), None);
+        assert_eq!(parse_line(2, "   "), None);
+        assert_eq!(
+            parse_line(3, "(|| 10/5 12/4) <: 40/5 expect true"),
+            Some(Ok(Entry {
+                line_no: 3,
+                judgment: "(|| 10/5 12/4) <: 40/5",
+                expect: Some(true),
+            }))
+        );
+        assert_eq!(
+            parse_line(4, "10/5 <: 10/5"),
+            Some(Ok(Entry {
+                line_no: 4,
+                judgment: "10/5 <: 10/5",
+                expect: None,
+            }))
+        );
+        assert!(matches!(
+            parse_line(5, "10/5 <: 10/5 expect maybe"),
+            Some(Err(_))
+        ));
+        // expect has to be a word of its own, followed by exactly one value.
+        assert!(matches!(
+            parse_line(6, "10/5 <: 10/5 expect true false"),
+            Some(Err(_))
+        ));
+        assert!(matches!(parse_line(7, "10/5 <: 10/5 expect"), Some(Err(_))));
+        assert_eq!(
+            parse_line(8, "10/5 <: 10/5unexpected true"),
+            Some(Ok(Entry {
+                line_no: 8,
+                judgment: "10/5 <: 10/5unexpected true",
+                expect: None,
+            }))
+        );
+        assert_eq!(
+            parse_line(9, "10/5 <: 10/5\texpect   false"),
+            Some(Ok(Entry {
+                line_no: 9,
+                judgment: "10/5 <: 10/5",
+                expect: Some(false),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let input =
+            "# contracts\n10/5 <: 20/5 expect true\n10/5 <: 5/5 expect true\n10/5 <: (|| 1/1\n";
+        let passed: Vec<bool> = run_batch(input, &SolverConfig::default(), &SubtypeCache::new(16))
+            .iter()
+            .map(|res| res.passed())
+            .collect();
+        assert_eq!(passed, vec![true, false, false]);
+    }
+}
@@ -0,0 +1,456 @@
+use crate::parse::parse_side;
+use crate::streamrate::{
+    Counterexample, ENCODING, LeafAssignment, SolverConfig, StreamRate, SubReport, SubResult,
+    Window, canonicalize, printed_normal_forms, stream_sub_report_with,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Memoizing stream_sub across calls, for callers (like a planner) that keep
+// asking the same questions. Entries are keyed on the canonical forms of both
+// sides (see canonicalize), so e.g. (|| a b) <: c and (|| b a) <: c share one.
+// Equal canonical forms are the same type, since canonicalize doesn't change
+// which traces fit, but stream_sub doesn't always give the same answer for
+// both (it can depend on the order of operands, say). So what gets checked is
+// always the canonical judgment, and the answer only depends on the key, not
+// on which of the equivalent judgments got asked first.
+//
+// NOTE: Only results that don't depend on the SolverConfig get cached, i.e.
+// not Unknowns, which come from the limits. A counterexample is for the
+// canonical judgment, so its leaves can come in a different order than in the
+// one being asked about.
+
+type Key = (StreamRate, StreamRate);
+
+/// A memo table of subtyping results, keyed on canonicalized pairs of
+/// StreamRates. It holds at most a fixed number of results, and evicts the
+/// least recently used one to make room. All the methods take &self, so one
+/// cache can be shared between threads (e.g. in an Arc).
+pub struct SubtypeCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    // Each result, and when it was last used.
+    results: HashMap<Key, (SubResult, u64)>,
+    // The keys by when they were last used, oldest first.
+    by_use: BTreeMap<u64, Key>,
+    clock: u64,
+    hits: usize,
+    misses: usize,
+}
+
+impl Entries {
+    fn get(&mut self, key: &Key) -> Option<SubResult> {
+        let (result, last_used) = self.results.get_mut(key)?;
+        self.by_use.remove(last_used);
+        self.clock += 1;
+        *last_used = self.clock;
+        self.by_use.insert(self.clock, key.clone());
+        Some(result.clone())
+    }
+
+    fn insert(&mut self, key: Key, result: SubResult, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        if let Some((_, last_used)) = self.results.remove(&key) {
+            self.by_use.remove(&last_used);
+        }
+        while self.results.len() >= capacity {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            self.results.remove(&oldest);
+        }
+        self.clock += 1;
+        self.by_use.insert(self.clock, key.clone());
+        self.results.insert(key, (result, self.clock));
+    }
+}
+
+impl SubtypeCache {
+    /// An empty cache that holds at most capacity results.
+    pub fn new(capacity: usize) -> Self {
+        SubtypeCache {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Like stream_sub_explain, with the given SolverConfig, but answers from
+    /// the cache if it can. Either way, the answer is for the canonical forms
+    /// of sr1 and sr2.
+    pub fn check(&self, sr1: &StreamRate, sr2: &StreamRate, config: &SolverConfig) -> SubResult {
+        let key = (canonicalize(sr1), canonicalize(sr2));
+        if let Some(result) = self.lookup(&key) {
+            return result;
+        }
+        let result = stream_sub_report_with(&key.0, &key.1, config).result;
+        self.remember(key, &result);
+        result
+    }
+
+    /// Like stream_sub_report_with, but answers from the cache if it can, as
+    /// check does. The normal forms are still sr1's and sr2's, but a report
+    /// from the cache has smt_cases 0, since the solver didn't have to do
+    /// anything.
+    pub fn report(&self, sr1: &StreamRate, sr2: &StreamRate, config: &SolverConfig) -> SubReport {
+        let key = (canonicalize(sr1), canonicalize(sr2));
+        let (lhs_normal, rhs_normal) = printed_normal_forms(sr1, sr2);
+        if let Some(result) = self.lookup(&key) {
+            return SubReport {
+                lhs_normal,
+                rhs_normal,
+                smt_cases: 0,
+                result,
+            };
+        }
+        let report = stream_sub_report_with(&key.0, &key.1, config);
+        self.remember(key, &report.result);
+        SubReport {
+            lhs_normal,
+            rhs_normal,
+            ..report
+        }
+    }
+
+    // NOTE: The lock isn't held while we solve, so two threads asking the same
+    // new question at once will both solve it. That's still the right answer,
+    // and better than making everyone else wait.
+    fn lookup(&self, key: &Key) -> Option<SubResult> {
+        let mut entries = self.entries.lock().unwrap();
+        let result = entries.get(key);
+        match result {
+            Some(_) => entries.hits += 1,
+            None => entries.misses += 1,
+        }
+        result
+    }
+
+    fn remember(&self, key: Key, result: &SubResult) {
+        if let SubResult::Unknown(_) = result {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key, result.clone(), self.capacity);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many checks were answered from the cache.
+    pub fn hits(&self) -> usize {
+        self.entries.lock().unwrap().hits
+    }
+
+    /// How many checks had to go to the solver.
+    pub fn misses(&self) -> usize {
+        self.entries.lock().unwrap().misses
+    }
+
+    /// Writes every entry out to path, as text, least recently used first.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let entries = self.entries.lock().unwrap();
+        let mut out = header();
+        out.push('\n');
+        for key in entries.by_use.values() {
+            let (result, _) = &entries.results[key];
+            if let Some(line) = write_entry(key, result) {
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+        fs::write(path, out)
+    }
+
+    /// Reads a cache back in from a file that save wrote. It's only a cache,
+    /// so entries that don't read back (e.g. ones from a different version)
+    /// are skipped rather than treated as errors. If there are more than
+    /// capacity entries, the least recently used ones go.
+    pub fn load(path: &Path, capacity: usize) -> io::Result<Self> {
+        let input = fs::read_to_string(path)?;
+        let cache = SubtypeCache::new(capacity);
+        if input.lines().next() != Some(header().as_str()) {
+            return Ok(cache);
+        }
+        {
+            let mut entries = cache.entries.lock().unwrap();
+            for (key, result) in input.lines().skip(1).filter_map(read_entry) {
+                entries.insert(key, result, capacity);
+            }
+        }
+        Ok(cache)
+    }
+}
+
+// The file format is one line per entry, with tab-separated fields:
+//
+//   holds    lhs  rhs
+//   refuted  lhs  rhs  window  lhs_events  rhs_events  lhs_leaves  rhs_leaves  events
+//
+// Rates are in the parser's syntax, leaves are space-separated source=assigned
+// pairs of raw rates, and the window and event times are exact fractions like
+// 3/2 (or just - if the counterexample has no trace). v1 didn't have the - for
+// no trace, so those files don't get read any more.
+//
+// The header also says which ENCODING the answers came from, since a change
+// to that can flip them, and files from any other one don't get read either.
+const HEADER: &str = "# ratelimitsub cache v2";
+
+fn header() -> String {
+    format!("{} encoding {}", HEADER, ENCODING)
+}
+
+fn write_entry((lhs, rhs): &Key, result: &SubResult) -> Option<String> {
+    match result {
+        SubResult::Holds => Some(format!("holds\t{}\t{}", lhs, rhs)),
+        SubResult::Refuted(cex) => {
+            let leaves = |leaves: &[LeafAssignment]| {
+                let pairs: Vec<String> = leaves
+                    .iter()
+                    .map(|leaf| format!("{}={}", leaf.source, leaf.assigned))
+                    .collect();
+                pairs.join(" ")
+            };
+            let events = match &cex.events {
+                Some(events) => {
+                    let events: Vec<String> = events.iter().map(|e| e.to_string()).collect();
+                    events.join(" ")
+                }
+                None => "-".to_string(),
+            };
+            Some(format!(
+                "refuted\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                lhs,
+                rhs,
+                cex.window,
+                cex.lhs_events,
+                cex.rhs_events,
+                leaves(&cex.lhs_leaves),
+                leaves(&cex.rhs_leaves),
+                events
+            ))
+        }
+        SubResult::Unknown(_) => None,
+    }
+}
+
+fn read_entry(line: &str) -> Option<(Key, SubResult)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let side = |s: &str| parse_side(s, 0).ok().map(|sr| canonicalize(&sr));
+    let rate = |s: &str| match parse_side(s, 0) {
+        Ok(StreamRate::Raw(r)) => Some(r),
+        _ => None,
+    };
+    let leaves = |s: &str| -> Option<Vec<LeafAssignment>> {
+        s.split_whitespace()
+            .map(|pair| {
+                let (source, assigned) = pair.split_once('=')?;
+                Some(LeafAssignment {
+                    source: rate(source)?,
+                    assigned: rate(assigned)?,
+                })
+            })
+            .collect()
+    };
+    match fields[..] {
+        ["holds", lhs, rhs] => Some(((side(lhs)?, side(rhs)?), SubResult::Holds)),
+        [
+            "refuted",
+            lhs,
+            rhs,
+            window,
+            lhs_events,
+            rhs_events,
+            lhs_leaves,
+            rhs_leaves,
+            events,
+        ] => {
+            let cex = Counterexample {
+                window: window.parse().ok()?,
+                lhs_leaves: leaves(lhs_leaves)?,
+                rhs_leaves: leaves(rhs_leaves)?,
+                lhs_events: lhs_events.parse().ok()?,
+                rhs_events: rhs_events.parse().ok()?,
+                events: match events {
+                    "-" => None,
+                    events => Some(
+                        events
+                            .split_whitespace()
+                            .map(|e| e.parse().ok())
+                            .collect::<Option<Vec<Window>>>()?,
+                    ),
+                },
+            };
+            Some(((side(lhs)?, side(rhs)?), SubResult::Refuted(cex)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streamrate::{Rate, stream_sub};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn sr(s: &str) -> StreamRate {
+        parse_side(s, 0).unwrap()
+    }
+
+    fn one_thread() -> SolverConfig {
+        SolverConfig {
+            threads: 1,
+            ..SolverConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = SubtypeCache::new(2);
+        let config = one_thread();
+        let holds = cache.check(&sr("(|| 10/5 12/4)"), &sr("40/5"), &config);
+        assert!(holds.holds());
+        // Same judgment up to canonicalization, so it comes from the cache.
+        assert_eq!(
+            cache.check(&sr("(|| 12/4 10/5)"), &sr("40/5"), &config),
+            holds
+        );
+        let report = cache.report(&sr("(|| 12/4 10/5)"), &sr("40/5"), &config);
+        assert_eq!(report.smt_cases, 0);
+        assert_eq!(report.lhs_normal, "(|| 12/4 10/5)");
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+        // Witnesses get cached too.
+        let refuted = cache.check(&sr("(. 10/5 10/5)"), &sr("5/5"), &config);
+        assert!(refuted.counterexample().is_some());
+        assert_eq!(
+            cache.check(&sr("(. 10/5 10/5)"), &sr("5/5"), &config),
+            refuted
+        );
+        assert_eq!((cache.hits(), cache.misses()), (3, 2));
+        // The first judgment was used last, so it stays, and the second goes.
+        cache.check(&sr("(|| 10/5 12/4)"), &sr("40/5"), &config);
+        cache.check(&sr("10/5"), &sr("20/5"), &config);
+        assert_eq!(cache.len(), 2);
+        cache.check(&sr("(|| 10/5 12/4)"), &sr("40/5"), &config);
+        assert_eq!(cache.misses(), 3);
+        cache.check(&sr("(. 10/5 10/5)"), &sr("5/5"), &config);
+        assert_eq!(cache.misses(), 4);
+        // Unknowns depend on the limits, so they don't get kept.
+        let limited = SolverConfig {
+            rlimit: Some(1),
+            ..one_thread()
+        };
+        let lhs = sr("(. 10/5 (|| 1/1 2/2))");
+        let rhs = sr("(|| 300/50 40/10)");
+        assert!(matches!(
+            cache.check(&lhs, &rhs, &limited),
+            SubResult::Unknown(_)
+        ));
+        assert!(cache.check(&lhs, &rhs, &config).holds());
+    }
+
+    #[test]
+    fn test_cache_equivalent() {
+        // Two ways of writing the same judgment, which stream_sub only proves
+        // for the second, since it pairs up the operands as they come.
+        let lhs = sr("(|| 2/2 3/1 3/2)");
+        let rhs = sr("(|| 4/1 5/2)");
+        let canonical = (canonicalize(&lhs), canonicalize(&rhs));
+        assert!(!stream_sub(&lhs, &rhs));
+        assert!(stream_sub(&canonical.0, &canonical.1));
+        // The cache gives the same answer whichever gets asked first.
+        for first_as_written in [true, false] {
+            let cache = SubtypeCache::new(2);
+            let config = one_thread();
+            let (first, second) = if first_as_written {
+                ((&lhs, &rhs), (&canonical.0, &canonical.1))
+            } else {
+                ((&canonical.0, &canonical.1), (&lhs, &rhs))
+            };
+            assert!(cache.check(first.0, first.1, &config).holds());
+            assert!(cache.check(second.0, second.1, &config).holds());
+            assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        }
+    }
+
+    #[test]
+    fn test_cache_threads() {
+        let cache = Arc::new(SubtypeCache::new(16));
+        let judgments = [
+            ("(|| 10/5 12/4)", "40/5"),
+            ("10/5", "5/5"),
+            ("1/1s", "60/1m"),
+        ];
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let cache = Arc::clone(&cache);
+                scope.spawn(move || {
+                    for (lhs, rhs) in judgments {
+                        cache.check(&sr(lhs), &sr(rhs), &one_thread());
+                    }
+                });
+            }
+        });
+        assert_eq!(cache.len(), judgments.len());
+        assert_eq!(cache.hits() + cache.misses(), 4 * judgments.len());
+    }
+
+    #[test]
+    fn test_cache_save_load() {
+        let cache = SubtypeCache::new(16);
+        let config = one_thread();
+        // 3/[1/3] doesn't print as a decimal, but it still reads back.
+        let third = StreamRate::Raw(Rate {
+            events: 3,
+            window: Window::new(1, 3),
+            timed: false,
+        });
+        let judgments = [
+            (sr("(|| 10/5 12/4)"), sr("40/5")),
+            (sr("(. 10/5 3/1.5)"), sr("5/5")),
+            (sr("(+ 100/1m (* 2/1s))"), sr("(|| 1/1s 5/250ms)")),
+            (third, sr("1/1")),
+        ];
+        let results: Vec<SubResult> = judgments
+            .iter()
+            .map(|(lhs, rhs)| cache.check(lhs, rhs, &config))
+            .collect();
+        let path = std::env::temp_dir().join(format!("subtype-cache-{}", std::process::id()));
+        cache.save(&path).unwrap();
+        let loaded = SubtypeCache::load(&path, 16).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 4);
+        for ((lhs, rhs), result) in judgments.iter().zip(results.iter()) {
+            assert_eq!(&loaded.check(lhs, rhs, &config), result);
+        }
+        assert_eq!(loaded.misses(), 0);
+        // A file from another version, or from another encoding, is as good
+        // as empty.
+        for stale in [HEADER.to_string(), format!("{} encoding 0", HEADER)] {
+            fs::write(&path, format!("{}\nholds\t1/1\t1/1\n", stale)).unwrap();
+            let loaded = SubtypeCache::load(&path, 16).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert!(loaded.is_empty());
+        }
+        fs::write(&path, format!("{}\nholds\t1/1\t1/1\n", header())).unwrap();
+        let loaded = SubtypeCache::load(&path, 16).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        // Anything else just gets skipped.
+        assert_eq!(read_entry("holds\t(|| 1/1\t1/1"), None);
+        assert_eq!(read_entry("maybe\t1/1\t1/1"), None);
+    }
+}
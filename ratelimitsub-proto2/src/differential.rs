@@ -0,0 +1,624 @@
+use crate::streamrate::{Rate, StreamRate, SubResult, Window, stream_sub_explain};
+use std::collections::HashMap;
+use std::fmt;
+
+// Differential testing for stream_sub: decide small judgments by brute force,
+// checking every small trace against both sides, and see whether the solver
+// agrees.
+//
+// The semantics, which the Enforcer, trace_satisfies and generate_trace all
+// follow too: a trace is in a type if it can be split up the way the type says.
+//
+// - n/t: at most n events in any window [s, s + t).
+// - (+ a b): in a and in b at once (see the TODO in convert_to_ba_rec), not
+//   either. So on the left-hand side, it's enough for one operand to be a
+//   subtype, and on the right-hand side, every operand has to be a supertype.
+// - (|| a b): shared out between a and b, each getting a trace in its type.
+// - (. a b): cut into consecutive blocks, the first in a and the rest in b.
+// - (* a): cut into any number of consecutive blocks, each in a.
+//
+// Streams don't have to last as long as their windows, so it's everything a
+// stream could have sent so far, which is what a rate limiter gets to see. A
+// Concat phase or Star repetition lasts as long as its shortest leaf window
+// before the next one starts, and the next one starts then or at the last
+// event before it, sharing that instant.
+//
+// Membership is decided here from scratch, by counting events in windows and
+// trying every way to split a trace up, rather than with trace_satisfies, so
+// that a bug there can't hide one in the solver. stream_sub never gets to
+// say a judgment holds when some trace is in the left side and not the right
+// (assert_sound), and the judgments that hold but that it can't show are
+// tracked as should_panic tests. The brute force only looks at discrete
+// timelines (whole number times) up to some horizon, with at most a few events
+// at each time, so it can find real counterexamples, but "none found" only
+// means none that small.
+
+// How many events there are at each time, from 0 up to the last event (so
+// the last entry is never 0, and the empty timeline is empty).
+type Timeline = Vec<u8>;
+
+fn trim(mut timeline: Timeline) -> Timeline {
+    while timeline.last() == Some(&0) {
+        timeline.pop();
+    }
+    timeline
+}
+
+fn events(timeline: &Timeline) -> Vec<Window> {
+    timeline
+        .iter()
+        .enumerate()
+        .flat_map(|(t, n)| std::iter::repeat_n(Window::from_integer(t), *n as usize))
+        .collect()
+}
+
+fn allows(sr: &StreamRate, timeline: &Timeline) -> bool {
+    fits(sr, timeline, Window::from_integer(0))
+}
+
+// The most events in any window [t, t + window).
+fn most_in_window(timeline: &[u8], window: &Window) -> usize {
+    (0..timeline.len())
+        .map(|t| {
+            timeline[t..]
+                .iter()
+                .enumerate()
+                .take_while(|(dt, _)| Window::from_integer(*dt) < *window)
+                .map(|(_, n)| *n as usize)
+                .sum()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// How long a phase or repetition of sr lasts at least: its shortest leaf
+// window.
+fn shortest_window(sr: &StreamRate) -> Window {
+    match sr {
+        StreamRate::Raw(r) => r.window,
+        StreamRate::Sum(srs) | StreamRate::Par(srs) | StreamRate::Concat(srs) => srs
+            .iter()
+            .map(shortest_window)
+            .min()
+            .expect("operators have at least one operand"),
+        StreamRate::Star(body) => shortest_window(body),
+    }
+}
+
+// Every way to share a timeline out between two branches.
+fn shares(timeline: &[u8]) -> Vec<(Timeline, Timeline)> {
+    let mut all = Vec::new();
+    let mut mine = vec![0; timeline.len()];
+    loop {
+        let theirs = timeline.iter().zip(&mine).map(|(n, m)| n - m).collect();
+        all.push((mine.clone(), theirs));
+        let Some(i) = (0..mine.len()).find(|i| mine[*i] < timeline[*i]) else {
+            return all;
+        };
+        mine[i] += 1;
+        mine[..i].fill(0);
+    }
+}
+
+// Every way to cut a timeline into a phase that starts at start and lasts at
+// least length, and the rest, with when the rest starts: once the phase has
+// lasted length, or at its last event if that's later. Events at that instant
+// can go either way.
+fn phases(timeline: &[u8], start: Window, length: Window) -> Vec<(Timeline, Timeline, Window)> {
+    let ready = start + length;
+    let cut = |t: usize, k: u8| {
+        let mut phase = timeline.to_vec();
+        phase[t + 1..].fill(0);
+        phase[t] = k;
+        let rest = timeline.iter().zip(&phase).map(|(n, m)| n - m).collect();
+        (phase, rest)
+    };
+    let mut all = Vec::new();
+    if timeline
+        .iter()
+        .enumerate()
+        .all(|(t, _)| Window::from_integer(t) < ready)
+    {
+        all.push((timeline.to_vec(), vec![0; timeline.len()], ready));
+    }
+    for (t, n) in timeline.iter().enumerate() {
+        let at = Window::from_integer(t);
+        if at == ready {
+            all.extend((0..=*n).map(|k| {
+                let (phase, rest) = cut(t, k);
+                (phase, rest, ready)
+            }));
+        } else if at > ready {
+            if t > 0 && Window::from_integer(t - 1) < ready {
+                // Ready fell between two times.
+                let (phase, rest) = cut(t - 1, timeline[t - 1]);
+                all.push((phase, rest, ready));
+            }
+            all.extend((1..=*n).map(|k| {
+                let (phase, rest) = cut(t, k);
+                (phase, rest, at)
+            }));
+        }
+    }
+    all
+}
+
+// Whether a timeline fits sr, if sr starts at start.
+fn fits(sr: &StreamRate, timeline: &[u8], start: Window) -> bool {
+    let empty = timeline.iter().all(|n| *n == 0);
+    match sr {
+        StreamRate::Raw(r) => most_in_window(timeline, &r.window) <= r.events,
+        StreamRate::Sum(srs) => srs.iter().all(|sr| fits(sr, timeline, start)),
+        StreamRate::Par(srs) => match srs.split_first() {
+            Some((first, [])) => fits(first, timeline, start),
+            Some((first, rest)) => shares(timeline).iter().any(|(mine, theirs)| {
+                fits(first, mine, start) && fits(&StreamRate::Par(rest.to_vec()), theirs, start)
+            }),
+            None => empty,
+        },
+        StreamRate::Concat(srs) => match srs.split_first() {
+            Some((first, [])) => fits(first, timeline, start),
+            Some((first, rest)) => phases(timeline, start, shortest_window(first)).iter().any(
+                |(phase, after, next)| {
+                    fits(first, phase, start)
+                        && fits(&StreamRate::Concat(rest.to_vec()), after, *next)
+                },
+            ),
+            None => empty,
+        },
+        // An empty repetition only makes the next one start later, so only
+        // the ones with events need trying.
+        StreamRate::Star(body) => {
+            empty
+                || phases(timeline, start, shortest_window(body)).iter().any(
+                    |(phase, after, next)| {
+                        phase.iter().any(|n| *n > 0)
+                            && fits(body, phase, start)
+                            && fits(sr, after, *next)
+                    },
+                )
+        }
+    }
+}
+
+// How far the brute force looks.
+#[derive(Clone, Copy, Debug)]
+struct Bounds {
+    // Events happen at 0, 1, ..., horizon - 1.
+    horizon: usize,
+    per_tick: u8,
+}
+
+// Every timeline within bounds, fewest events first, so that the first
+// counterexample we find is a small one.
+fn timelines(bounds: Bounds) -> Vec<Timeline> {
+    let mut all = Vec::new();
+    let mut timeline = vec![0; bounds.horizon];
+    loop {
+        all.push(trim(timeline.clone()));
+        let Some(i) = (0..timeline.len()).find(|i| timeline[*i] < bounds.per_tick) else {
+            break;
+        };
+        timeline[i] += 1;
+        timeline[..i].fill(0);
+    }
+    all.sort_by_key(|timeline| {
+        (
+            timeline.iter().map(|n| *n as usize).sum::<usize>(),
+            timeline.len(),
+        )
+    });
+    all
+}
+
+// Decides judgments by brute force, remembering which timelines each left-hand
+// side allows, since a sweep asks about the same ones over and over.
+struct Brute {
+    timelines: Vec<Timeline>,
+    allowed: HashMap<StreamRate, Vec<Timeline>>,
+}
+
+impl Brute {
+    fn new(bounds: Bounds) -> Brute {
+        Brute {
+            timelines: timelines(bounds),
+            allowed: HashMap::new(),
+        }
+    }
+
+    // A timeline (within bounds) that lhs allows but rhs doesn't, if there is
+    // one.
+    fn counterexample(&mut self, lhs: &StreamRate, rhs: &StreamRate) -> Option<Timeline> {
+        if !self.allowed.contains_key(lhs) {
+            let allowed = self
+                .timelines
+                .iter()
+                .filter(|timeline| allows(lhs, timeline))
+                .cloned()
+                .collect();
+            self.allowed.insert(lhs.clone(), allowed);
+        }
+        self.allowed[lhs]
+            .iter()
+            .find(|timeline| !allows(rhs, timeline))
+            .cloned()
+    }
+}
+
+// A judgment that stream_sub and the brute force disagree on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Disagreement {
+    lhs: StreamRate,
+    rhs: StreamRate,
+    // What stream_sub said. If it said it holds, the brute force found a
+    // counterexample, and if not, it found none.
+    smt: bool,
+    counterexample: Option<Timeline>,
+}
+
+impl fmt::Display for Disagreement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} <: {}: stream_sub says {}",
+            self.lhs, self.rhs, self.smt
+        )?;
+        match &self.counterexample {
+            Some(timeline) => {
+                let times: Vec<String> = events(timeline).iter().map(|t| t.to_string()).collect();
+                write!(
+                    f,
+                    ", but lhs allows events at [{}] and rhs doesn't",
+                    times.join(", ")
+                )
+            }
+            None => write!(f, ", but there's no counterexample that small"),
+        }
+    }
+}
+
+// Whether stream_sub and the brute force disagree on lhs <: rhs. Nothing if
+// they agree, or if Z3 gave up.
+fn compare(lhs: &StreamRate, rhs: &StreamRate, brute: &mut Brute) -> Option<Disagreement> {
+    let smt = match stream_sub_explain(lhs, rhs) {
+        SubResult::Holds => true,
+        SubResult::Refuted(_) => false,
+        SubResult::Unknown(_) => return None,
+    };
+    let counterexample = brute.counterexample(lhs, rhs);
+    (smt == counterexample.is_some()).then(|| Disagreement {
+        lhs: lhs.clone(),
+        rhs: rhs.clone(),
+        smt,
+        counterexample,
+    })
+}
+
+// Shrink a disagreement as far as it goes, one step at a time, keeping each
+// step only if stream_sub still says the same thing and the brute force still
+// disagrees with it. Every step makes the judgment strictly smaller (fewer
+// nodes, or smaller numbers), so this stops.
+fn minimize(mut found: Disagreement, brute: &mut Brute) -> Disagreement {
+    'shrink: loop {
+        let lhs_steps = shrinks(&found.lhs)
+            .into_iter()
+            .map(|lhs| (lhs, found.rhs.clone()));
+        let rhs_steps = shrinks(&found.rhs)
+            .into_iter()
+            .map(|rhs| (found.lhs.clone(), rhs));
+        for (lhs, rhs) in lhs_steps.chain(rhs_steps).collect::<Vec<_>>() {
+            if let Some(smaller) = compare(&lhs, &rhs, brute)
+                && smaller.smt == found.smt
+            {
+                found = smaller;
+                continue 'shrink;
+            }
+        }
+        return found;
+    }
+}
+
+// Everything one step smaller than sr: a node replaced by one of its operands,
+// an operand dropped, or a leaf with one event fewer or a window one shorter.
+fn shrinks(sr: &StreamRate) -> Vec<StreamRate> {
+    match sr {
+        StreamRate::Raw(r) => {
+            let mut smaller = Vec::new();
+            if r.events > 0 {
+                smaller.push(StreamRate::Raw(Rate {
+                    events: r.events - 1,
+                    ..r.clone()
+                }));
+            }
+            if r.window > Window::from_integer(1) {
+                smaller.push(StreamRate::Raw(Rate {
+                    window: r.window - 1,
+                    ..r.clone()
+                }));
+            }
+            smaller
+        }
+        StreamRate::Sum(srs) | StreamRate::Par(srs) | StreamRate::Concat(srs) => {
+            let rebuild = |srs: Vec<StreamRate>| match sr {
+                StreamRate::Sum(_) => StreamRate::Sum(srs),
+                StreamRate::Par(_) => StreamRate::Par(srs),
+                _ => StreamRate::Concat(srs),
+            };
+            let mut smaller = srs.clone();
+            if srs.len() > 2 {
+                for i in 0..srs.len() {
+                    let mut fewer = srs.clone();
+                    fewer.remove(i);
+                    smaller.push(rebuild(fewer));
+                }
+            }
+            for (i, operand) in srs.iter().enumerate() {
+                for shrunk in shrinks(operand) {
+                    let mut srs = srs.clone();
+                    srs[i] = shrunk;
+                    smaller.push(rebuild(srs));
+                }
+            }
+            smaller
+        }
+        StreamRate::Star(body) => {
+            let mut smaller = vec![(**body).clone()];
+            smaller.extend(
+                shrinks(body)
+                    .into_iter()
+                    .map(|body| StreamRate::Star(Box::new(body))),
+            );
+            smaller
+        }
+    }
+}
+
+// Every rate made of one leaf, a Star of one leaf, or two leaves under a Sum,
+// Par or Concat.
+fn small_rates(leaves: &[Rate]) -> Vec<StreamRate> {
+    let leaves: Vec<StreamRate> = leaves.iter().cloned().map(StreamRate::Raw).collect();
+    let mut rates = leaves.clone();
+    rates.extend(
+        leaves
+            .iter()
+            .map(|leaf| StreamRate::Star(Box::new(leaf.clone()))),
+    );
+    for a in leaves.iter() {
+        for b in leaves.iter() {
+            let pair = vec![a.clone(), b.clone()];
+            rates.push(StreamRate::Sum(pair.clone()));
+            rates.push(StreamRate::Par(pair.clone()));
+            rates.push(StreamRate::Concat(pair));
+        }
+    }
+    rates
+}
+
+// Compare every small_rates(leaves) against every one of singles, both ways
+// round, and minimize whatever disagrees. Each minimized disagreement is only
+// reported once.
+// NOTE: A tick can only hold bounds.per_tick events, so if something on either
+// side can put more than that at one time, the brute force can't see it.
+// Keep the leaves' events down to half of per_tick (two leaves in a Par, or
+// both sides of a Concat's boundary), and the singles' to per_tick.
+fn sweep(leaves: &[Rate], singles: &[Rate], bounds: Bounds) -> Vec<Disagreement> {
+    let singles: Vec<StreamRate> = singles.iter().cloned().map(StreamRate::Raw).collect();
+    let rates = small_rates(leaves);
+    let pairs = rates
+        .iter()
+        .flat_map(|lhs| singles.iter().map(move |rhs| (lhs, rhs)))
+        .chain(
+            singles
+                .iter()
+                .flat_map(|lhs| rates.iter().map(move |rhs| (lhs, rhs))),
+        );
+    let mut brute = Brute::new(bounds);
+    let mut found: Vec<Disagreement> = Vec::new();
+    for (lhs, rhs) in pairs {
+        if let Some(disagreement) = compare(lhs, rhs, &mut brute) {
+            let minimized = minimize(disagreement, &mut brute);
+            if !found.contains(&minimized) {
+                found.push(minimized);
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_side;
+
+    const BOUNDS: Bounds = Bounds {
+        horizon: 6,
+        per_tick: 4,
+    };
+
+    fn timeline(times: &[usize]) -> Timeline {
+        let mut timeline = Vec::new();
+        for t in times {
+            timeline.resize(timeline.len().max(t + 1), 0);
+            timeline[*t] += 1;
+        }
+        timeline
+    }
+
+    fn fits(sr: &str, times: &[usize]) -> bool {
+        allows(&parse_side(sr, 0).unwrap(), &timeline(times))
+    }
+
+    fn counterexample(judgment: &str) -> Option<Timeline> {
+        let (lhs, rhs) = crate::parse::parse(judgment).unwrap();
+        let bounds = Bounds {
+            horizon: 5,
+            per_tick: 3,
+        };
+        Brute::new(bounds).counterexample(&lhs, &rhs)
+    }
+
+    #[test]
+    fn test_membership() {
+        // A stream doesn't have to last as long as its window.
+        assert!(fits("2/2", &[0, 0]));
+        assert!(!fits("2/2", &[0, 0, 1]));
+        // The second phase starts once the first has lasted its window, or at
+        // its last event, so both can have an event then.
+        assert!(fits("(. 1/1 1/1)", &[0, 1, 1]));
+        assert!(fits("(. 1/1 1/1)", &[1, 1]));
+        assert!(!fits("(. 1/1 1/1)", &[0, 0]));
+        // Same for repetitions.
+        assert!(fits("(* 1/1)", &[]));
+        assert!(fits("(* 1/1)", &[1, 1, 2, 2]));
+        assert!(!fits("(* 1/1)", &[0, 0]));
+        // Including the inner repetitions of a Star of a Star.
+        assert!(fits("(* (* 1/2))", &[1, 2, 2]));
+        assert!(!fits("(* 1/2)", &[1, 2, 2]));
+        // A phase lasts as long as its shortest window, not its own.
+        assert!(fits("(. (|| 1/1 1/3) 2/1)", &[0, 0, 1, 1]));
+        assert!(fits("(|| 1/2 1/3)", &[0, 1, 2]));
+        assert!(!fits("(|| 1/2 1/3)", &[0, 1, 2, 3]));
+        assert!(fits("(+ 2/1 1/2)", &[0, 2]));
+        assert!(!fits("(+ 2/1 1/2)", &[0, 0]));
+    }
+
+    #[test]
+    fn test_brute_sub() {
+        assert_eq!(counterexample("2/1 <: 4/2"), None);
+        assert_eq!(counterexample("(|| 1/1 1/1) <: 2/1"), None);
+        assert_eq!(
+            counterexample("(|| 2/1 1/1) <: 2/1"),
+            Some(timeline(&[0, 0, 0]))
+        );
+        assert_eq!(
+            counterexample("1/1 <: (|| 1/2 1/3)"),
+            Some(timeline(&[0, 1, 2, 3]))
+        );
+        assert_eq!(
+            counterexample("(* 1/1) <: 3/2"),
+            Some(timeline(&[1, 1, 2, 2]))
+        );
+    }
+
+    #[test]
+    fn test_minimize() {
+        let (lhs, rhs) = crate::parse::parse("(+ 1/1 2/3) <: (. (|| 1/1 1/3) 1/2)").unwrap();
+        let mut brute = Brute::new(BOUNDS);
+        let found = compare(&lhs, &rhs, &mut brute).unwrap();
+        assert!(!found.smt);
+        let minimized = minimize(found, &mut brute);
+        assert_eq!(
+            (minimized.lhs.to_string(), minimized.rhs.to_string()),
+            ("1/1".to_string(), "(. 1/1 0/1)".to_string())
+        );
+        assert_eq!(minimized.counterexample, None);
+    }
+
+    // Judgments the solver has to get right and that are easy to get wrong.
+    // Concat phases share the instant where one ends and the next starts, so a
+    // window across phases gets every phase's events from all of it. A Star's
+    // repetitions last as long as its whole body, not the leaves left after
+    // its Ors are split up, and a Star of a Star is not a Star: 1, 2, 2 fits
+    // (* (* 1/2)) but not (* 1/2).
+    const REGRESSIONS: [&str; 14] = [
+        "(. 1/1 1/1) <: 2/2",
+        "(. 1/1 1/1) <: 3/3",
+        "(. 1/1 2/1) <: 4/2",
+        "(. 1/1 2/2) <: 3/2",
+        "(. 1/1 2/2) <: 4/3",
+        "(. 1/2 1/2) <: 2/3",
+        "(. 2/1 1/1) <: 4/2",
+        "(. 2/2 1/1) <: 3/2",
+        "(. 2/2 1/1) <: 4/3",
+        "(* (+ 2/1 1/5)) <: 2/5",
+        "(* (* 1/2)) <: 2/2",
+        "(* 1/2) <: (* 1/2)",
+        "(* (+ 1/3 5/1)) <: (* 1/3)",
+        "(* 1/1) <: (|| (* 3/1) 1/1)",
+    ];
+
+    fn rates(n: usize, t: usize) -> Vec<Rate> {
+        (0..=n)
+            .flat_map(|n| (1..=t).map(move |t| Rate::new(n, t)))
+            .collect()
+    }
+
+    // stream_sub never says a judgment holds when the brute force has a
+    // counterexample. It can say no when there isn't one, which is what
+    // the tests below keep track of.
+    fn assert_sound(found: Vec<Disagreement>) {
+        let unsound: Vec<String> = found
+            .iter()
+            .filter(|d| d.smt)
+            .map(|d| d.to_string())
+            .collect();
+        assert!(unsound.is_empty(), "found:\n{}", unsound.join("\n"));
+    }
+
+    fn assert_agrees(judgment: &str) {
+        let (lhs, rhs) = crate::parse::parse(judgment).unwrap();
+        if let Some(disagreement) = compare(&lhs, &rhs, &mut Brute::new(BOUNDS)) {
+            panic!("{}", disagreement);
+        }
+    }
+
+    #[test]
+    fn test_regressions() {
+        for judgment in REGRESSIONS {
+            assert_agrees(judgment);
+        }
+    }
+
+    // What the sweeps turn up, minimized: judgments that hold, but that
+    // stream_sub can't show. A window across phases on the Lhs gets
+    // everything each phase could have in all of it, and a Concat on the Rhs
+    // is checked as every phase at once. Once one of these goes through, its
+    // test fails, and should become a plain assert_agrees.
+
+    #[test]
+    #[should_panic(expected = "no counterexample")]
+    fn test_lhs_concat_two_windows() {
+        assert_agrees("(. 1/1 1/1) <: 3/2");
+    }
+
+    #[test]
+    #[should_panic(expected = "no counterexample")]
+    fn test_lhs_concat_three_windows() {
+        assert_agrees("(. 1/1 1/1) <: 4/3");
+    }
+
+    #[test]
+    #[should_panic(expected = "no counterexample")]
+    fn test_lhs_concat_long_phases() {
+        assert_agrees("(. 1/2 1/2) <: 3/3");
+    }
+
+    #[test]
+    #[should_panic(expected = "no counterexample")]
+    fn test_rhs_concat_empty_phase() {
+        assert_agrees("1/1 <: (. 1/1 0/1)");
+    }
+
+    #[test]
+    #[should_panic(expected = "no counterexample")]
+    fn test_rhs_concat_as_every_phase() {
+        assert_agrees("1/1 <: (. 1/1 1/2)");
+    }
+
+    // Small enough to run every time, so anything new shows up here first.
+    #[test]
+    fn test_sweep_small() {
+        let bounds = Bounds {
+            horizon: 6,
+            per_tick: 2,
+        };
+        assert_sound(sweep(&rates(1, 3), &rates(2, 3), bounds));
+    }
+
+    // NOTE: Takes a while, so run it with cargo test --release -- --ignored.
+    #[test]
+    #[ignore]
+    fn test_sweep() {
+        assert_sound(sweep(&rates(2, 3), &rates(4, 3), BOUNDS));
+    }
+}
@@ -0,0 +1,436 @@
+use crate::streamrate::Rate;
+use crate::streamrate::StreamRate;
+use crate::streamrate::Window;
+use crate::streamrate::{checked_add, checked_sub};
+use std::collections::VecDeque;
+use std::fmt;
+
+// Enforcing a StreamRate at runtime: each event either goes through now, or
+// gets told how long to wait.
+//
+// The enforcer only ever admits a stream that's in the type, exactly in the
+// sense of trace_satisfies (which test_enforcer_sound checks). It gets there by
+// building the stream's decomposition as it goes:
+//
+// - A Raw leaf keeps the events it has admitted in the last window, and takes
+//   another one if that leaves at most n in any window of t.
+// - A Par hands each event to the first branch that will take it.
+// - A Concat stays in its current phase for as long as that phase will take
+//   events, and moves on to the next one when it won't. A Star does the same,
+//   starting a new repetition of its body instead.
+// - A Sum only takes an event if every branch would, and gives it to all of
+//   them. That's stricter than "either a or b", but stream_sub treats a Sum on
+//   the Lhs as a subtype of anything either side is, so the stream has to
+//   satisfy both for the enforcer to be sound.
+//
+// NOTE: The semantics also say a stream of n/t has to last at least t, which
+// we can't do anything about for a stream that's still going. What we do keep
+// is the part the solver relies on: a Concat phase (or Star repetition) only
+// ends once it's lasted at least as long as its shortest leaf window, so that
+// e.g. (* 10/5) can't just start a new repetition for every 10 events. The
+// next one starts right then, or at the last event of the one before, if that's
+// later (phases share that instant, as in the semantics), even if its own
+// first event comes later still. That matters for phases that are themselves
+// Concats with an empty first phase, which has to last too.
+// TODO: This is greedy, so it won't wait for a phase to free up if the next
+// one can take the event now, even when waiting would leave more room later.
+//
+// A time that doesn't fit in a Window (too late, or too fine-grained, e.g. an
+// event plus a window with a huge coprime denominator) never comes: the event
+// stays in its leaf's window, or the phase never ends. So running out of room
+// only ever makes the enforcer turn down more.
+
+/// Admits or turns down events as they arrive, so that the admitted stream
+/// satisfies a [`StreamRate`].
+///
+/// Times are in the rate's units (seconds for timed rates, ticks otherwise),
+/// measured from the start of the stream, and shouldn't go backwards; an
+/// earlier time than the last one is treated as the last one.
+///
+/// ```
+/// use num_rational::Ratio;
+/// use ratelimitsub_proto2::{Enforcer, RetryAfter, parse_side};
+///
+/// let mut enforcer = Enforcer::new(&parse_side("2/5", 0).unwrap());
+/// assert!(enforcer.try_acquire(Ratio::from(0)).is_ok());
+/// assert!(enforcer.try_acquire(Ratio::from(1)).is_ok());
+/// assert_eq!(
+///     enforcer.try_acquire(Ratio::from(2)),
+///     Err(RetryAfter(Some(Ratio::from(3))))
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct Enforcer {
+    root: Node,
+    last: Window,
+}
+
+/// Why [`Enforcer::try_acquire`] turned an event down: how long to wait before
+/// it would go through, or `None` if it never will (e.g. for a `0/t` rate), or
+/// if the wait doesn't fit in a [`Window`].
+///
+/// The wait assumes nothing else gets admitted in the meantime.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryAfter(pub Option<Window>);
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(wait) => write!(f, "retry after {}", wait),
+            None => write!(f, "no more events allowed"),
+        }
+    }
+}
+
+impl Enforcer {
+    /// An enforcer for sr, with the stream starting at time 0.
+    pub fn new(sr: &StreamRate) -> Enforcer {
+        let zero = Window::from_integer(0);
+        let mut root = Node::new(sr);
+        root.start(zero);
+        Enforcer { root, last: zero }
+    }
+
+    /// Admit an event at now, or say how long until one would be admitted.
+    pub fn try_acquire(&mut self, now: Window) -> Result<(), RetryAfter> {
+        let now = now.max(self.last);
+        self.last = now;
+        match self.root.earliest(now) {
+            Some(at) if at == now => {
+                self.root.admit(now);
+                Ok(())
+            }
+            Some(at) => Err(RetryAfter(checked_sub(&at, &now))),
+            None => Err(RetryAfter(None)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    // The events admitted within the last window, oldest first.
+    Raw {
+        rate: Rate,
+        recent: VecDeque<Window>,
+    },
+    Sum(Vec<Node>),
+    Par(Vec<Node>),
+    // ready is the earliest the next phase could start: once the current one
+    // has lasted long enough, and no earlier than its last event. None if
+    // that doesn't fit in a Window, so it never will.
+    Concat {
+        phases: Vec<Node>,
+        current: usize,
+        ready: Option<Window>,
+    },
+    // body is kept fresh, for starting new repetitions from.
+    Star {
+        body: Box<Node>,
+        current: Box<Node>,
+        ready: Option<Window>,
+    },
+}
+
+impl Node {
+    fn new(sr: &StreamRate) -> Node {
+        let zero = Window::from_integer(0);
+        let nodes = |srs: &[StreamRate]| srs.iter().map(Node::new).collect();
+        match sr {
+            StreamRate::Raw(r) => Node::Raw {
+                rate: r.clone(),
+                recent: VecDeque::new(),
+            },
+            StreamRate::Sum(srs) => Node::Sum(nodes(srs)),
+            StreamRate::Par(srs) => Node::Par(nodes(srs)),
+            StreamRate::Concat(srs) => Node::Concat {
+                phases: nodes(srs),
+                current: 0,
+                ready: Some(zero),
+            },
+            StreamRate::Star(sr) => Node::Star {
+                body: Box::new(Node::new(sr)),
+                current: Box::new(Node::new(sr)),
+                ready: Some(zero),
+            },
+        }
+    }
+
+    // Start a fresh node's stream at time at.
+    fn start(&mut self, at: Window) {
+        match self {
+            Node::Raw { .. } => {}
+            Node::Sum(nodes) | Node::Par(nodes) => nodes.iter_mut().for_each(|n| n.start(at)),
+            Node::Concat {
+                phases,
+                current,
+                ready,
+            } => {
+                phases[*current].start(at);
+                *ready = checked_add(&at, &phases[*current].min_length());
+            }
+            Node::Star { current, ready, .. } => {
+                current.start(at);
+                *ready = checked_add(&at, &current.min_length());
+            }
+        }
+    }
+
+    // How long the node's stream has to last before a Concat or Star can move
+    // on from it: its shortest leaf window, same as the solver assumes.
+    fn min_length(&self) -> Window {
+        match self {
+            Node::Raw { rate, .. } => rate.window,
+            Node::Sum(nodes) | Node::Par(nodes) | Node::Concat { phases: nodes, .. } => nodes
+                .iter()
+                .map(Node::min_length)
+                .min()
+                .expect("operators have at least one operand"),
+            Node::Star { body, .. } => body.min_length(),
+        }
+    }
+
+    // The earliest time (no earlier than now) that the node would take another
+    // event, if nothing else is admitted before then. None if never.
+    fn earliest(&self, now: Window) -> Option<Window> {
+        match self {
+            Node::Raw { rate, recent } => {
+                if rate.events == 0 {
+                    return None;
+                }
+                let in_window = recent
+                    .iter()
+                    .filter(|e| checked_add(e, &rate.window).is_none_or(|end| end > now))
+                    .count();
+                if in_window < rate.events {
+                    Some(now)
+                } else {
+                    // Wait for the oldest of the last n to drop out.
+                    checked_add(&recent[recent.len() - rate.events], &rate.window)
+                }
+            }
+            Node::Sum(nodes) => {
+                let mut latest = now;
+                for n in nodes {
+                    latest = latest.max(n.earliest(now)?);
+                }
+                // NOTE: Every branch being free at a different time doesn't
+                // mean they're all free at the latest of those, since some
+                // branch could be a Concat that's moved on by then. It's only
+                // a hint, and the caller just tries again.
+                Some(latest)
+            }
+            Node::Par(nodes) => nodes.iter().filter_map(|n| n.earliest(now)).min(),
+            Node::Concat {
+                phases,
+                current,
+                ready,
+            } => {
+                let stay = phases[*current].earliest(now);
+                let next = phases
+                    .get(*current + 1)
+                    .zip(*ready)
+                    .and_then(|(next, ready)| started(next, ready).earliest(now.max(ready)));
+                stay.into_iter().chain(next).min()
+            }
+            Node::Star {
+                body,
+                current,
+                ready,
+            } => {
+                let stay = current.earliest(now);
+                let next = ready.and_then(|ready| started(body, ready).earliest(now.max(ready)));
+                stay.into_iter().chain(next).min()
+            }
+        }
+    }
+
+    // Take an event at now, which earliest(now) said is fine.
+    fn admit(&mut self, now: Window) {
+        match self {
+            Node::Raw { rate, recent } => {
+                while recent
+                    .front()
+                    .and_then(|e| checked_add(e, &rate.window))
+                    .is_some_and(|end| end <= now)
+                {
+                    recent.pop_front();
+                }
+                recent.push_back(now);
+            }
+            Node::Sum(nodes) => nodes.iter_mut().for_each(|n| n.admit(now)),
+            Node::Par(nodes) => {
+                if let Some(n) = nodes.iter_mut().find(|n| n.earliest(now) == Some(now)) {
+                    n.admit(now);
+                }
+            }
+            Node::Concat {
+                phases,
+                current,
+                ready,
+            } => {
+                if phases[*current].earliest(now) != Some(now) {
+                    // earliest(now) only moves on once the phase can end.
+                    let start = ready.expect("the phase can end");
+                    *current += 1;
+                    phases[*current].start(start);
+                    *ready = checked_add(&start, &phases[*current].min_length());
+                }
+                phases[*current].admit(now);
+                *ready = ready.map(|ready| ready.max(now));
+            }
+            Node::Star {
+                body,
+                current,
+                ready,
+            } => {
+                if current.earliest(now) != Some(now) {
+                    let start = ready.expect("the repetition can end");
+                    **current = started(body, start);
+                    *ready = checked_add(&start, &current.min_length());
+                }
+                current.admit(now);
+                *ready = ready.map(|ready| ready.max(now));
+            }
+        }
+    }
+}
+
+// A copy of a fresh node, started at at.
+fn started(node: &Node, at: Window) -> Node {
+    let mut node = node.clone();
+    node.start(at);
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_side;
+    use crate::strategies::{self, Shape};
+    use crate::trace::trace_satisfies;
+    use proptest::prelude::*;
+
+    fn enforcer(s: &str) -> Enforcer {
+        Enforcer::new(&parse_side(s, 0).unwrap())
+    }
+
+    fn at(t: usize) -> Window {
+        Window::from_integer(t)
+    }
+
+    // Try an event at each time, and return which ones got through.
+    fn admitted(enforcer: &mut Enforcer, times: &[usize]) -> Vec<usize> {
+        times
+            .iter()
+            .copied()
+            .filter(|t| enforcer.try_acquire(at(*t)).is_ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_enforcer_raw() {
+        let mut e = enforcer("2/5");
+        assert_eq!(admitted(&mut e, &[0, 0, 1]), vec![0, 0]);
+        assert_eq!(e.try_acquire(at(3)), Err(RetryAfter(Some(at(2)))));
+        assert_eq!(admitted(&mut e, &[5, 5, 6, 10]), vec![5, 5, 10]);
+        // Time doesn't go backwards.
+        assert_eq!(e.try_acquire(at(2)), Ok(()));
+        assert_eq!(e.try_acquire(at(2)), Err(RetryAfter(Some(at(5)))));
+
+        let mut e = enforcer("3/1.5");
+        let half = Window::new(1, 2);
+        assert!((0..3).all(|_| e.try_acquire(half).is_ok()));
+        assert_eq!(e.try_acquire(at(1)), Err(RetryAfter(Some(at(1)))));
+
+        assert_eq!(enforcer("0/5").try_acquire(at(0)), Err(RetryAfter(None)));
+    }
+
+    #[test]
+    fn test_enforcer_par_sum() {
+        let mut e = enforcer("(|| 1/5 2/10)");
+        assert_eq!(admitted(&mut e, &[0, 1, 2, 3]), vec![0, 1, 2]);
+        assert_eq!(e.try_acquire(at(4)), Err(RetryAfter(Some(at(1)))));
+
+        // Both sides have to be happy.
+        let mut e = enforcer("(+ 3/5 1/1)");
+        assert_eq!(admitted(&mut e, &[0, 0, 1, 2, 3, 4]), vec![0, 1, 2]);
+        assert_eq!(
+            enforcer("(+ 3/5 0/1)").try_acquire(at(0)),
+            Err(RetryAfter(None))
+        );
+    }
+
+    #[test]
+    fn test_enforcer_concat_star() {
+        // The first phase fills up, but has to last 5 before the second can
+        // start.
+        let mut e = enforcer("(. 2/5 3/10)");
+        assert_eq!(admitted(&mut e, &[0, 0, 1]), vec![0, 0]);
+        assert_eq!(e.try_acquire(at(4)), Err(RetryAfter(Some(at(1)))));
+        // At 5, the first phase has room again, so we stay there.
+        assert_eq!(admitted(&mut e, &[5, 5, 5, 5, 5, 5]), vec![5, 5, 5, 5, 5]);
+        // ...and now we're in the second phase for good.
+        assert_eq!(e.try_acquire(at(10)), Err(RetryAfter(Some(at(5)))));
+
+        let mut e = enforcer("(* 2/5)");
+        assert_eq!(admitted(&mut e, &[0, 0, 0, 4]), vec![0, 0]);
+        // At 5 the first repetition has room again, and then a second one can
+        // start, since the first has lasted 5.
+        assert_eq!(admitted(&mut e, &[5, 5, 5, 6, 7]), vec![5, 5, 5, 6]);
+        assert_eq!(e.try_acquire(at(7)), Err(RetryAfter(Some(at(3)))));
+    }
+
+    #[test]
+    fn test_enforcer_overflow() {
+        // The first event's window ends later than a Window can hold, so
+        // there's never room for another one.
+        let max = usize::MAX;
+        let mut e = enforcer(&format!("1/{}", max));
+        assert_eq!(admitted(&mut e, &[1, 2]), vec![1]);
+        assert_eq!(e.try_acquire(at(max)), Err(RetryAfter(None)));
+
+        // Nor for another repetition, after the one that lasts until max.
+        let mut e = enforcer(&format!("(* 1/{})", max));
+        assert_eq!(admitted(&mut e, &[1, 2]), vec![1]);
+        assert_eq!(e.try_acquire(at(2)), Err(RetryAfter(Some(at(max - 2)))));
+        assert_eq!(admitted(&mut e, &[max, max]), vec![max]);
+
+        let mut e = enforcer(&format!("(. 1/{} 1/1)", max));
+        assert_eq!(admitted(&mut e, &[1, max, max]), vec![1, max]);
+
+        // An event at 1/(max - 1) plus a window of 1/max doesn't fit in a
+        // Window either, so that event never drops out.
+        let mut e = enforcer(&format!("1/[1/{}]", max));
+        let t = Window::new(1, max - 1);
+        assert_eq!(e.try_acquire(t), Ok(()));
+        assert_eq!(e.try_acquire(t), Err(RetryAfter(None)));
+        assert_eq!(e.try_acquire(at(1)), Err(RetryAfter(None)));
+    }
+
+    // A small StreamRate to enforce, with whole windows.
+    fn arb_stream_rate() -> impl Strategy<Value = StreamRate> {
+        strategies::arb_stream_rate(strategies::arb_rate(0..4, 1..7usize), Shape::default())
+    }
+
+    proptest! {
+        // Whatever the enforcer lets through has to fit the type, as
+        // trace_satisfies reads it.
+        #[test]
+        fn test_enforcer_sound(
+            sr in arb_stream_rate(),
+            gaps in prop::collection::vec(prop::sample::select(vec![0, 0, 1, 2, 5]), 1..60),
+        ) {
+            let times: Vec<usize> = gaps
+                .iter()
+                .scan(0, |t, gap| {
+                    *t += gap;
+                    Some(*t)
+                })
+                .collect();
+            let events = admitted(&mut Enforcer::new(&sr), &times);
+            let trace: Vec<Window> = events.iter().map(|t| at(*t)).collect();
+            let result = trace_satisfies(&sr, &trace);
+            prop_assert!(result.is_ok(), "admitted {:?}, but {:?}", events, result);
+        }
+    }
+}
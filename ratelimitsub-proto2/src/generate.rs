@@ -0,0 +1,509 @@
+use crate::enforce::Enforcer;
+use crate::streamrate::StreamRate;
+use crate::streamrate::Window;
+use crate::streamrate::{checked_add, checked_mul};
+use crate::trace::{Timestamp, TimestampOverflow, TraceError, trace_satisfies_within};
+
+// Making up traces that fit a StreamRate, e.g. to load-test whatever sits
+// behind a rate limit, or to see whether stream_sub's answers hold up on real
+// streams.
+//
+// Every mode just decides when events try to arrive, and lets an Enforcer for
+// the rate decide which of them get through, so whatever comes out fits the
+// type (see test_enforcer_sound). A near miss is one of those traces plus one
+// more event, wherever that's enough to break it.
+//
+// A time that doesn't fit in a Timestamp (too late, or too fine-grained) ends
+// the trace early.
+
+/// How a generated trace spaces out its events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceMode {
+    /// Events try to arrive at random, about as fast as the rate allows on
+    /// average, and sometimes several at once.
+    Random,
+    /// As many events as the rate allows, as early as it allows them.
+    Bursty,
+    /// Events on an even grid, as tight as the slowest part of the rate can
+    /// keep up with.
+    Even,
+    /// Bursts at every multiple of every window in the rate.
+    Aligned,
+}
+
+/// Options for [`generate_trace`] and [`near_miss`].
+#[derive(Clone, Debug)]
+pub struct TraceConfig {
+    pub mode: TraceMode,
+    /// Only events before this time. Defaults to four times the rate's
+    /// biggest window (if a [`Timestamp`] can hold that).
+    pub duration: Option<Timestamp>,
+    /// Stop after this many events, even before the end.
+    pub max_events: usize,
+    /// The same seed (and everything else) always makes the same trace.
+    pub seed: u64,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        TraceConfig {
+            mode: TraceMode::Random,
+            duration: None,
+            max_events: 10_000,
+            seed: 0,
+        }
+    }
+}
+
+/// A trace that fits sr, made up according to config. The times are sorted,
+/// start from 0, and are in the rate's units, same as for
+/// [`crate::trace_satisfies`].
+///
+/// Fails if the default duration, or the average spacing between events for a
+/// Random or Even trace, doesn't fit in a [`Timestamp`].
+pub fn generate_trace(
+    sr: &StreamRate,
+    config: &TraceConfig,
+) -> Result<Vec<Timestamp>, TimestampOverflow> {
+    let duration = match config.duration {
+        Some(duration) => duration,
+        None => checked_mul(&max_window(sr), &Window::from_integer(4)).ok_or(TimestampOverflow)?,
+    };
+    let mut generator = Generator {
+        enforcer: Enforcer::new(sr),
+        trace: Vec::new(),
+        duration,
+        max_events: config.max_events,
+    };
+    let mut rng = Rng(config.seed);
+    let zero = Window::from_integer(0);
+    match config.mode {
+        TraceMode::Random => {
+            let Some(spacing) = spacing(sr)? else {
+                return Ok(Vec::new());
+            };
+            let mut now = zero;
+            // NOTE: Rejected events still take up a turn, so a rate that turns
+            // most of them down can't keep us going forever.
+            for _ in 0..attempts(config.max_events) {
+                if !generator.attempt(now) {
+                    break;
+                }
+                // Gaps of 0 to 2 spacings, in eighths, so about one in
+                // sixteen arrives with the one before it.
+                let gap = Window::new(rng.below(17) as usize, 8);
+                let Some(next) =
+                    checked_mul(&spacing, &gap).and_then(|gap| checked_add(&now, &gap))
+                else {
+                    break;
+                };
+                now = next;
+            }
+        }
+        TraceMode::Bursty => {
+            let mut now = zero;
+            while now < duration && generator.trace.len() < config.max_events {
+                match generator.enforcer.try_acquire(now) {
+                    Ok(()) => generator.trace.push(now),
+                    Err(retry) => match retry.0.and_then(|wait| checked_add(&now, &wait)) {
+                        Some(next) => now = next,
+                        None => break,
+                    },
+                }
+            }
+        }
+        TraceMode::Even => {
+            let Some(spacing) = spacing(sr)? else {
+                return Ok(Vec::new());
+            };
+            for k in 0..attempts(config.max_events) {
+                let Some(now) = checked_mul(&spacing, &Window::from_integer(k)) else {
+                    break;
+                };
+                if !generator.attempt(now) {
+                    break;
+                }
+            }
+        }
+        TraceMode::Aligned => {
+            let mut windows = Vec::new();
+            leaf_windows(sr, &mut windows);
+            let mut now = zero;
+            for _ in 0..attempts(config.max_events) {
+                // Burst for as long as the rate lets us.
+                let mut admitted = generator.trace.len();
+                while generator.attempt(now) && generator.trace.len() > admitted {
+                    admitted = generator.trace.len();
+                }
+                if now >= duration || generator.trace.len() >= config.max_events {
+                    break;
+                }
+                // On to the next multiple of any of the windows.
+                let next = windows
+                    .iter()
+                    .filter_map(|w| {
+                        let multiples = checked_mul(&now, &w.recip())?.to_integer();
+                        checked_mul(&Window::from_integer(multiples.checked_add(1)?), w)
+                    })
+                    .min();
+                let Some(next) = next else {
+                    break;
+                };
+                now = next;
+            }
+        }
+    }
+    Ok(generator.trace)
+}
+
+/// A trace that doesn't fit sr, but would without one of its events: a trace
+/// from [`generate_trace`], with one more event at a time where it's enough to
+/// break it. If the mode leaves too much room for that (e.g. a sparse Random
+/// trace, or an empty one), the Bursty trace is used instead, since it's as
+/// full as the rate allows. None if even that didn't work out, and fails
+/// whenever [`generate_trace`] does.
+pub fn near_miss(
+    sr: &StreamRate,
+    config: &TraceConfig,
+) -> Result<Option<Vec<Timestamp>>, TimestampOverflow> {
+    let bursty = TraceConfig {
+        mode: TraceMode::Bursty,
+        ..config.clone()
+    };
+    match one_more(sr, &generate_trace(sr, config)?, config.seed) {
+        Some(missed) => Ok(Some(missed)),
+        None => Ok(one_more(sr, &generate_trace(sr, &bursty)?, config.seed)),
+    }
+}
+
+// The trace with one more event, at a time where that breaks it.
+fn one_more(sr: &StreamRate, trace: &[Timestamp], seed: u64) -> Option<Vec<Timestamp>> {
+    let mut rng = Rng(seed);
+    // The busiest times are the likeliest to break, so try those first: every
+    // distinct time in the trace, biggest bursts first, then a few at random.
+    let mut times: Vec<(usize, Timestamp)> = Vec::new();
+    for t in trace.iter() {
+        match times.last_mut() {
+            Some((count, last)) if last == t => *count += 1,
+            _ => times.push((1, *t)),
+        }
+    }
+    times.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    let mut candidates: Vec<Timestamp> = times.iter().map(|(_, t)| *t).take(32).collect();
+    if let Some(last) = trace.last() {
+        for _ in 0..32 {
+            candidates.extend(checked_mul(last, &Window::new(rng.below(65) as usize, 64)));
+        }
+    }
+    candidates.push(Window::from_integer(0));
+    candidates.into_iter().find_map(|t| {
+        let mut missed = trace.to_vec();
+        let at = missed.partition_point(|e| *e <= t);
+        missed.insert(at, t);
+        matches!(
+            trace_satisfies_within(sr, &missed, usize::MAX),
+            Some(Err(TraceError::Violation(_)))
+        )
+        .then_some(missed)
+    })
+}
+
+struct Generator {
+    enforcer: Enforcer,
+    trace: Vec<Timestamp>,
+    duration: Timestamp,
+    max_events: usize,
+}
+
+impl Generator {
+    // Try an event at now. Returns false once we're past the end.
+    fn attempt(&mut self, now: Timestamp) -> bool {
+        if now >= self.duration || self.trace.len() >= self.max_events {
+            return false;
+        }
+        if self.enforcer.try_acquire(now).is_ok() {
+            self.trace.push(now);
+        }
+        true
+    }
+}
+
+// How many times to try, at most, before giving up on filling the trace.
+fn attempts(max_events: usize) -> usize {
+    max_events.saturating_mul(4)
+}
+
+// Time between events for the slowest part of sr to keep up, if it allows any
+// events at all: a Par goes as fast as all its branches together, but a Sum or
+// Concat only as fast as its slowest side (or phase).
+fn spacing(sr: &StreamRate) -> Result<Option<Window>, TimestampOverflow> {
+    // Events per unit of time, or None if that overflows.
+    fn rate(sr: &StreamRate) -> Option<Window> {
+        match sr {
+            StreamRate::Raw(r) => checked_mul(&Window::from_integer(r.events), &r.window.recip()),
+            StreamRate::Par(srs) => srs.iter().try_fold(Window::from_integer(0), |total, sr| {
+                checked_add(&total, &rate(sr)?)
+            }),
+            StreamRate::Sum(srs) | StreamRate::Concat(srs) => srs
+                .iter()
+                .map(rate)
+                .collect::<Option<Vec<_>>>()?
+                .into_iter()
+                .min(),
+            StreamRate::Star(sr) => rate(sr),
+        }
+    }
+    let rate = rate(sr).ok_or(TimestampOverflow)?;
+    Ok((rate > Window::from_integer(0)).then(|| rate.recip()))
+}
+
+fn leaf_windows(sr: &StreamRate, windows: &mut Vec<Window>) {
+    match sr {
+        StreamRate::Raw(r) => windows.push(r.window),
+        StreamRate::Sum(srs) | StreamRate::Par(srs) | StreamRate::Concat(srs) => {
+            srs.iter().for_each(|sr| leaf_windows(sr, windows))
+        }
+        StreamRate::Star(sr) => leaf_windows(sr, windows),
+    }
+}
+
+fn max_window(sr: &StreamRate) -> Window {
+    let mut windows = Vec::new();
+    leaf_windows(sr, &mut windows);
+    windows
+        .into_iter()
+        .max()
+        .expect("a rate has at least one leaf")
+}
+
+// Just enough randomness for making up traces (splitmix64), so we don't need
+// to pull in a whole random number library.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Something in 0..n.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_side;
+    use crate::strategies::{self, Shape};
+    use crate::streamrate::{Rate, stream_sub};
+    use crate::trace::trace_satisfies;
+    use proptest::prelude::*;
+
+    fn config(mode: TraceMode) -> TraceConfig {
+        TraceConfig {
+            mode,
+            ..TraceConfig::default()
+        }
+    }
+
+    fn times(ts: &[usize]) -> Vec<Timestamp> {
+        ts.iter().map(|t| Window::from_integer(*t)).collect()
+    }
+
+    #[test]
+    fn test_generate_trace() {
+        let sr = parse_side("2/5", 0).unwrap();
+        assert_eq!(
+            generate_trace(&sr, &config(TraceMode::Bursty)).unwrap(),
+            times(&[0, 0, 5, 5, 10, 10, 15, 15])
+        );
+        assert_eq!(
+            generate_trace(&sr, &config(TraceMode::Even)).unwrap(),
+            (0..8).map(|k| Window::new(5 * k, 2)).collect::<Vec<_>>()
+        );
+
+        // Par branches add up, and Aligned bursts on both grids.
+        let sr = parse_side("(|| 1/2 1/3)", 0).unwrap();
+        assert_eq!(
+            generate_trace(&sr, &config(TraceMode::Aligned)).unwrap(),
+            times(&[0, 0, 2, 3, 4, 6, 6, 8, 9, 10])
+        );
+
+        let sr = parse_side("(. 10/5 (|| 45/5 50/100))", 0).unwrap();
+        let random = generate_trace(&sr, &config(TraceMode::Random)).unwrap();
+        assert!(!random.is_empty());
+        assert_eq!(
+            random,
+            generate_trace(&sr, &config(TraceMode::Random)).unwrap()
+        );
+        let other_seed = TraceConfig {
+            seed: 1,
+            ..config(TraceMode::Random)
+        };
+        assert_ne!(random, generate_trace(&sr, &other_seed).unwrap());
+
+        let capped = TraceConfig {
+            max_events: 3,
+            ..config(TraceMode::Bursty)
+        };
+        assert_eq!(generate_trace(&sr, &capped).unwrap().len(), 3);
+        assert_eq!(
+            generate_trace(&parse_side("0/5", 0).unwrap(), &config(TraceMode::Even)).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_generate_trace_overflow() {
+        // Together, the branches allow more events per unit than a Window
+        // can hold, so there's no spacing for Random or Even.
+        let max = usize::MAX;
+        let sr = parse_side(&format!("(|| 1/[1/{}] 1/[1/{}])", max, max - 1), 0).unwrap();
+        assert_eq!(
+            generate_trace(&sr, &config(TraceMode::Random)),
+            Err(TimestampOverflow)
+        );
+        assert_eq!(
+            near_miss(&sr, &config(TraceMode::Even)),
+            Err(TimestampOverflow)
+        );
+        // Bursty doesn't need one, but stops early once the wait for the next
+        // event (from 1/max to 1/(max - 1)) doesn't fit in a Window either.
+        let mut bursty = times(&[0, 0]);
+        bursty.push(Window::new(1, max));
+        assert_eq!(generate_trace(&sr, &config(TraceMode::Bursty)), Ok(bursty));
+
+        // Nor is there a default duration, but with one, the trace just stops
+        // once the next event would be later than a Window can hold.
+        let sr = parse_side(&format!("1/{}", max), 0).unwrap();
+        assert_eq!(
+            generate_trace(&sr, &config(TraceMode::Bursty)),
+            Err(TimestampOverflow)
+        );
+        for mode in [
+            TraceMode::Random,
+            TraceMode::Bursty,
+            TraceMode::Even,
+            TraceMode::Aligned,
+        ] {
+            let config = TraceConfig {
+                duration: Some(Window::from_integer(max)),
+                ..config(mode)
+            };
+            assert_eq!(generate_trace(&sr, &config), Ok(times(&[0])));
+        }
+    }
+
+    #[test]
+    fn test_near_miss() {
+        let sr = parse_side("(|| 2/5 1/1)", 0).unwrap();
+        for mode in [
+            TraceMode::Random,
+            TraceMode::Bursty,
+            TraceMode::Even,
+            TraceMode::Aligned,
+        ] {
+            let missed = near_miss(&sr, &config(mode)).unwrap().unwrap();
+            assert!(trace_satisfies(&sr, &missed).is_err());
+        }
+        // Stars and Concats too, since each repetition (or phase) has to last
+        // before the next one can start. (. 5/1 0/1) has no Random or Even
+        // trace to add to, so those get a Bursty one.
+        for sr in [
+            "(* 2/5)",
+            "(* (|| 1/1 1/3))",
+            "(* (. 1/2 2/3))",
+            "(. 2/5 3/5)",
+            "(. 0/2 1/1)",
+            "(. 5/1 0/1)",
+            "(. 3/1 (* (|| 2/1 1/1)))",
+        ] {
+            let sr = parse_side(sr, 0).unwrap();
+            for mode in [
+                TraceMode::Random,
+                TraceMode::Bursty,
+                TraceMode::Even,
+                TraceMode::Aligned,
+            ] {
+                for seed in 0..3 {
+                    let config = TraceConfig {
+                        seed,
+                        ..config(mode)
+                    };
+                    let missed = near_miss(&sr, &config).unwrap().unwrap();
+                    assert!(trace_satisfies(&sr, &missed).is_err());
+                }
+            }
+        }
+    }
+
+    // NOTE: No Par gets more than two leaves, since trace_satisfies can take
+    // exponential time once a Par has a few leaves that are all close to
+    // full, and the generator is very good at making them that way.
+    fn arb_stream_rate() -> impl Strategy<Value = StreamRate> {
+        let leaf = strategies::arb_rate(0..5, prop::sample::select(vec![1, 2, 3, 5, 10]));
+        strategies::arb_stream_rate(leaf, Shape::default())
+            .prop_filter("too many leaves to check", narrow)
+    }
+
+    fn narrow(sr: &StreamRate) -> bool {
+        match sr {
+            StreamRate::Raw(_) => true,
+            StreamRate::Par(_) => {
+                let mut windows = Vec::new();
+                leaf_windows(sr, &mut windows);
+                windows.len() <= 2
+            }
+            StreamRate::Sum(srs) | StreamRate::Concat(srs) => srs.iter().all(narrow),
+            StreamRate::Star(sr) => narrow(sr),
+        }
+    }
+
+    fn arb_mode() -> impl Strategy<Value = TraceMode> {
+        prop::sample::select(vec![
+            TraceMode::Random,
+            TraceMode::Bursty,
+            TraceMode::Even,
+            TraceMode::Aligned,
+        ])
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+        #[test]
+        fn test_generated_traces_fit(sr in arb_stream_rate(), mode in arb_mode(), seed: u64) {
+            let config = TraceConfig { mode, max_events: 200, seed, ..TraceConfig::default() };
+            let trace = generate_trace(&sr, &config).unwrap();
+            prop_assert!(trace.is_sorted());
+            prop_assert_eq!(trace_satisfies(&sr, &trace), Ok(()));
+            if let Some(missed) = near_miss(&sr, &config).unwrap() {
+                let bursty = TraceConfig { mode: TraceMode::Bursty, ..config };
+                let base = [trace.len(), generate_trace(&sr, &bursty).unwrap().len()];
+                prop_assert!(base.contains(&(missed.len() - 1)));
+                prop_assert!(trace_satisfies(&sr, &missed).is_err());
+            }
+        }
+
+        // Raw against Raw has a closed form in stream_sub, and the bursty
+        // trace is the worst case for it, so the two have to agree.
+        #[test]
+        fn test_bursty_agrees_with_stream_sub(
+            (n1, t1) in (0..10usize, 1..10usize),
+            (n2, t2) in (0..10usize, 1..10usize),
+        ) {
+            let (lhs, rhs) = (StreamRate::Raw(Rate::new(n1, t1)), StreamRate::Raw(Rate::new(n2, t2)));
+            let config = TraceConfig {
+                mode: TraceMode::Bursty,
+                duration: Some(Window::from_integer(2 * t1.max(t2))),
+                ..TraceConfig::default()
+            };
+            let trace = generate_trace(&lhs, &config).unwrap();
+            prop_assert_eq!(stream_sub(&lhs, &rhs), trace_satisfies(&rhs, &trace).is_ok());
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use ratelimitsub_proto2::{
+    Blame, Counterexample, LeafAssignment, ParseError, StreamRate, SubResult, Violation, Window,
+};
+use std::fmt;
+
+// Just enough JSON to print results out for other tools, so we don't need to
+// pull in a whole serialization library for the CLI.
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(usize),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(n) => write!(f, "{}", n),
+            // NOTE: JSON has no NaN or infinity, so those come out as null.
+            Json::Float(x) if !x.is_finite() => write!(f, "null"),
+            Json::Float(x) => write!(f, "{}", x),
+            Json::Str(s) => write_str(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+fn str(s: impl ToString) -> Json {
+    Json::Str(s.to_string())
+}
+
+fn ints(ns: &[usize]) -> Json {
+    Json::Array(ns.iter().map(|n| Json::Int(*n)).collect())
+}
+
+// Whole windows (and times) are plain numbers, anything else is a
+// [numerator, denominator] pair, same as serde.
+fn window(w: &Window) -> Json {
+    if w.is_integer() {
+        Json::Int(w.to_integer())
+    } else {
+        ints(&[*w.numer(), *w.denom()])
+    }
+}
+
+// Trees come out tagged by the operator, e.g. {"par":[{"raw":{...}},...]}.
+pub fn stream_rate(sr: &StreamRate) -> Json {
+    let operands = |tag, srs: &[StreamRate]| {
+        Json::Object(vec![(
+            tag,
+            Json::Array(srs.iter().map(stream_rate).collect()),
+        )])
+    };
+    match sr {
+        StreamRate::Raw(r) => {
+            let mut fields = vec![
+                ("events", Json::Int(r.events)),
+                ("window", window(&r.window)),
+            ];
+            // Like serde, only say so when it's timed.
+            if r.timed {
+                fields.push(("timed", Json::Bool(true)));
+            }
+            Json::Object(vec![("raw", Json::Object(fields))])
+        }
+        StreamRate::Sum(srs) => operands("sum", srs),
+        StreamRate::Par(srs) => operands("par", srs),
+        StreamRate::Concat(srs) => operands("concat", srs),
+        StreamRate::Star(a) => Json::Object(vec![("star", stream_rate(a))]),
+    }
+}
+
+fn leaves(leaves: &[LeafAssignment]) -> Json {
+    Json::Array(
+        leaves
+            .iter()
+            .map(|leaf| {
+                Json::Object(vec![
+                    ("source", str(&leaf.source)),
+                    ("assigned", str(&leaf.assigned)),
+                ])
+            })
+            .collect(),
+    )
+}
+
+pub fn counterexample(cex: &Counterexample) -> Json {
+    Json::Object(vec![
+        ("window", window(&cex.window)),
+        ("lhs_events", Json::Int(cex.lhs_events)),
+        ("rhs_events", Json::Int(cex.rhs_events)),
+        ("lhs_leaves", leaves(&cex.lhs_leaves)),
+        ("rhs_leaves", leaves(&cex.rhs_leaves)),
+        (
+            "events",
+            cex.events.as_ref().map_or(Json::Null, |events| {
+                Json::Array(events.iter().map(window).collect())
+            }),
+        ),
+    ])
+}
+
+// "holds", "refuted" or "unknown".
+pub fn result_name(result: &SubResult) -> &'static str {
+    match result {
+        SubResult::Holds => "holds",
+        SubResult::Refuted(_) => "refuted",
+        SubResult::Unknown(_) => "unknown",
+    }
+}
+
+pub fn blame(blame: &Blame) -> Json {
+    Json::Object(vec![("lhs", ints(&blame.lhs)), ("rhs", ints(&blame.rhs))])
+}
+
+pub fn violation(v: &Violation) -> Json {
+    Json::Object(vec![
+        ("leaf", Json::Int(v.leaf)),
+        ("rate", str(&v.rate)),
+        ("start", window(&v.start)),
+        ("end", window(&v.end)),
+        ("events", Json::Int(v.events)),
+    ])
+}
+
+pub fn parse_error(err: &ParseError) -> Json {
+    Json::Object(vec![
+        ("message", str(err)),
+        ("offset", Json::Int(err.offset())),
+        ("expected", str(err.expected())),
+        ("found", str(err.found())),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratelimitsub_proto2::parse_side;
+
+    #[test]
+    fn test_json() {
+        let sr = parse_side("(|| 10/5 (* 1/2))", 0).unwrap();
+        assert_eq!(
+            stream_rate(&sr).to_string(),
+            r#"{"par":[{"raw":{"events":10,"window":5}},{"star":{"raw":{"events":1,"window":2}}}]}"#
+        );
+        assert_eq!(str("a \"b\"\n").to_string(), r#""a \"b\"\n""#);
+        let floats = [1.5, f64::NAN, f64::INFINITY, f64::NEG_INFINITY];
+        assert_eq!(
+            Json::Array(floats.into_iter().map(Json::Float).collect()).to_string(),
+            "[1.5,null,null,null]"
+        );
+    }
+
+    // The CLI's trees should look just like the library's serde output.
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_matches_serde() {
+        for s in [
+            "(+ (. 10/5 2/1) (* (|| 1/2 3/0.75)))",
+            "(|| 10/5s 100/1m 5/250ms)",
+        ] {
+            let sr = parse_side(s, 0).unwrap();
+            assert_eq!(
+                stream_rate(&sr).to_string(),
+                serde_json::to_string(&sr).unwrap()
+            );
+        }
+    }
+}
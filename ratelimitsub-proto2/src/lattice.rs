@@ -0,0 +1,198 @@
+use crate::streamrate::Rate;
+use crate::streamrate::StreamRate;
+use crate::streamrate::Window;
+use crate::streamrate::stream_sub;
+use std::cmp::max;
+use std::error::Error;
+use std::fmt;
+
+// Joins and meets of StreamRates, w.r.t. stream_sub.
+//
+// NOTE: Under the current subtyping rules, a Sum on the Lhs only needs one of
+// its sides to be a subtype, and a Sum on the Rhs needs both sides to be
+// supertypes. So (+ a b) is already the greatest lower bound of a and b (as
+// long as stream_sub can see that it's below both), and meets are easy.
+// Joins are harder: the least upper bound is the union of the two, which we
+// have no way of writing down unless one side already contains the other. In
+// general the best we can do is a Sum of Raw rates, one for every window size,
+// which is infinite. So we cut that Sum off at the window sizes that show up in
+// the inputs, and report that we did.
+
+// Don't look for bounds with more events than this in a single window.
+const MAX_EVENTS: usize = u32::MAX as usize;
+
+/// Why rate_join or rate_meet couldn't come up with an exact bound.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BoundError {
+    /// The exact bound isn't a finite StreamRate. approx is still a bound, just
+    /// not the tightest one.
+    NoFiniteRepresentation { approx: StreamRate },
+    /// We couldn't show that anything is a bound of both inputs.
+    NoBound,
+}
+
+impl fmt::Display for BoundError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BoundError::NoFiniteRepresentation { approx } => write!(
+                f,
+                "no finite representation of the exact bound, closest is {}",
+                approx
+            ),
+            BoundError::NoBound => write!(f, "couldn't find a bound"),
+        }
+    }
+}
+
+impl Error for BoundError {}
+
+/// The tightest StreamRate that both sr1 and sr2 are subtypes of.
+///
+/// This only returns Ok when one input is a subtype of the other. Otherwise
+/// the best it can do is BoundError::NoFiniteRepresentation, with a Sum of
+/// one Raw rate per window size in the inputs. Finding each of those takes a
+/// search over event counts, with up to about 64 calls to stream_sub per
+/// window size, so this can be slow on inputs with lots of different windows.
+pub fn rate_join(sr1: &StreamRate, sr2: &StreamRate) -> Result<StreamRate, BoundError> {
+    if stream_sub(sr1, sr2) {
+        return Ok(sr2.clone());
+    }
+    if stream_sub(sr2, sr1) {
+        return Ok(sr1.clone());
+    }
+    let mut windows = Vec::new();
+    leaf_windows(sr1, &mut windows);
+    leaf_windows(sr2, &mut windows);
+    windows.sort();
+    windows.dedup();
+    // For each window, the fewest events that both sides fit under.
+    let bounds: Vec<StreamRate> = windows
+        .into_iter()
+        .filter_map(|(window, timed)| {
+            let events = max(
+                min_events_above(sr1, window, timed)?,
+                min_events_above(sr2, window, timed)?,
+            );
+            Some(StreamRate::Raw(Rate {
+                events,
+                window,
+                timed,
+            }))
+        })
+        .collect();
+    let approx = match bounds.len() {
+        0 => return Err(BoundError::NoBound),
+        1 => bounds.into_iter().next().unwrap(),
+        _ => StreamRate::Sum(bounds),
+    };
+    if stream_sub(sr1, &approx) && stream_sub(sr2, &approx) {
+        Err(BoundError::NoFiniteRepresentation { approx })
+    } else {
+        Err(BoundError::NoBound)
+    }
+}
+
+/// The loosest StreamRate that is a subtype of both sr1 and sr2.
+pub fn rate_meet(sr1: &StreamRate, sr2: &StreamRate) -> Result<StreamRate, BoundError> {
+    if stream_sub(sr1, sr2) {
+        return Ok(sr1.clone());
+    }
+    if stream_sub(sr2, sr1) {
+        return Ok(sr2.clone());
+    }
+    let meet = StreamRate::Sum(vec![sr1.clone(), sr2.clone()]);
+    // NOTE: This can fail when stream_sub isn't reflexive on one of the sides.
+    if stream_sub(&meet, sr1) && stream_sub(&meet, sr2) {
+        Ok(meet)
+    } else {
+        Err(BoundError::NoBound)
+    }
+}
+
+// Each leaf's window, and whether it has a unit.
+fn leaf_windows(sr: &StreamRate, windows: &mut Vec<(Window, bool)>) {
+    match sr {
+        StreamRate::Raw(r) => windows.push((r.window, r.timed)),
+        StreamRate::Sum(srs) | StreamRate::Par(srs) | StreamRate::Concat(srs) => {
+            for sr in srs.iter() {
+                leaf_windows(sr, windows);
+            }
+        }
+        StreamRate::Star(sr1) => leaf_windows(sr1, windows),
+    }
+}
+
+// The smallest n such that sr <: n/window, if there is one (up to MAX_EVENTS).
+// This relies on sr <: n/window being monotone in n: double n until it holds,
+// then binary search between the last two tries. That's at most 33 tries to
+// get to MAX_EVENTS, and another 32 for the binary search.
+fn min_events_above(sr: &StreamRate, window: Window, timed: bool) -> Option<usize> {
+    let fits = |events| {
+        stream_sub(
+            sr,
+            &StreamRate::Raw(Rate {
+                events,
+                window,
+                timed,
+            }),
+        )
+    };
+    if fits(0) {
+        return Some(0);
+    }
+    // Invariant: !fits(lo) && fits(hi).
+    let mut lo = 0;
+    let mut hi = 1;
+    while !fits(hi) {
+        if hi == MAX_EVENTS {
+            return None;
+        }
+        lo = hi;
+        hi = hi.saturating_mul(2).min(MAX_EVENTS);
+    }
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        if fits(mid) {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    Some(hi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_side;
+
+    fn sr(s: &str) -> StreamRate {
+        parse_side(s, 0).unwrap()
+    }
+
+    #[test]
+    fn test_comparable() {
+        assert_eq!(rate_join(&sr("10/5"), &sr("20/5")), Ok(sr("20/5")));
+        assert_eq!(rate_meet(&sr("10/5"), &sr("20/5")), Ok(sr("10/5")));
+        assert_eq!(
+            rate_join(&sr("(|| 1/1 2/2)"), &sr("(. 1/1 1/2)")),
+            Ok(sr("(|| 1/1 2/2)"))
+        );
+    }
+
+    #[test]
+    fn test_incomparable() {
+        let (a, b) = (sr("10/5"), sr("3/1"));
+        let meet = rate_meet(&a, &b).unwrap();
+        assert_eq!(meet, sr("(+ 10/5 3/1)"));
+        assert!(stream_sub(&meet, &a) && stream_sub(&meet, &b));
+
+        match rate_join(&a, &b) {
+            Err(BoundError::NoFiniteRepresentation { approx }) => {
+                assert_eq!(approx, sr("(+ 10/1 15/5)"));
+                assert!(stream_sub(&a, &approx) && stream_sub(&b, &approx));
+            }
+            res => panic!("expected an approximate join, got {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Subtyping for rate-limited stream types.
+//!
+//! A [`StreamRate`] describes how fast events are allowed to arrive on a
+//! stream. A raw rate `n/t` ([`Rate`]) allows at most `n` events in any window
+//! of `t` time units, and raw rates combine with
+//!
+//! - `(+ a b)`: [`StreamRate::Sum`], a stream that satisfies both a and b,
+//! - `(|| a b)`: [`StreamRate::Par`], streams satisfying a and b, interleaved,
+//! - `(. a b)`: [`StreamRate::Concat`], a stream satisfying a, followed by one
+//!   satisfying b,
+//! - `(* a)`: [`StreamRate::Star`], zero or more a's, one after the other.
+//!
+//! (See exposition/ at the top of the repo for the full semantics.)
+//!
+//! The main entry point is [`stream_sub`], which decides whether every stream
+//! allowed by the left-hand rate is also allowed by the right-hand one:
+//!
+//! ```
+//! use ratelimitsub_proto2::{parse, stream_sub};
+//!
+//! let (lhs, rhs) = parse("(|| 10/5 12/4) <: 40/5").unwrap();
+//! assert!(stream_sub(&lhs, &rhs));
+//! ```
+//!
+//! # Contract
+//!
+//! - Rates can be built directly or with [`parse`]/[`parse_side`]. The parser
+//!   never panics; malformed input comes back as a [`ParseError`] that points
+//!   at a byte offset into the input.
+//! - Windows must be nonzero, and `Sum`, `Par` and `Concat` need at least one
+//!   operand. The parser checks both, and so does deserializing (with the
+//!   `serde` feature), but rates built directly aren't checked.
+//! - [`stream_sub`] is meant to be conservative: `true` means every stream
+//!   the left-hand side allows is allowed by the right-hand side too, but
+//!   `false` only means we couldn't show it. [`stream_sub_explain`] tells
+//!   those apart from the cases Z3 gave up on ([`SubResult::Unknown`]).
+//! - [`stream_sub_explain`] and [`stream_sub_blame`] agree with
+//!   [`stream_sub`] on whether the relation holds.
+//! - [`Enforcer`], [`trace_satisfies`] and [`generate_trace`] agree on which
+//!   streams fit a rate. src/differential.rs spells out exactly which ones,
+//!   and checks [`stream_sub`] against that.
+//! - Checks can run from several threads at once.
+
+pub mod cache;
+#[cfg(test)]
+mod differential;
+pub mod enforce;
+pub mod generate;
+pub mod lattice;
+pub mod parse;
+#[cfg(test)]
+mod strategies;
+pub mod streamrate;
+pub mod trace;
+
+pub use cache::SubtypeCache;
+pub use enforce::{Enforcer, RetryAfter};
+pub use generate::{TraceConfig, TraceMode, generate_trace, near_miss};
+pub use lattice::{BoundError, rate_join, rate_meet};
+pub use parse::{ParseError, Span, leaf_spans, parse, parse_side, parse_timestamp};
+pub use streamrate::{
+    Blame, Counterexample, LeafAssignment, Rate, SolverConfig, StreamRate, SubReport, SubResult,
+    Window, canonicalize, stream_equiv, stream_sub, stream_sub_blame, stream_sub_blame_with,
+    stream_sub_explain, stream_sub_report, stream_sub_report_with, stream_sub_smt,
+};
+pub use trace::{
+    Timestamp, TimestampOverflow, TraceError, Violation, format_timestamp, trace_satisfies,
+    trace_satisfies_within,
+};
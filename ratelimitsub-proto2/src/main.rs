@@ -1,18 +1,520 @@
+use json::Json;
+use ratelimitsub_proto2::{
+    Blame, Counterexample, ParseError, SolverConfig, SubResult, SubtypeCache, TraceConfig,
+    TraceError, TraceMode,
+};
+use ratelimitsub_proto2::{
+    format_timestamp, generate_trace, leaf_spans, near_miss, parse, parse_side, parse_timestamp,
+    stream_sub_blame_with, trace_satisfies_within,
+};
 use std::env;
-mod parse;
-mod streamrate;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+mod batch;
+mod json;
+mod repl;
+mod tracefile;
+
+enum Format {
+    Text,
+    Json,
+}
+
+struct Options {
+    format: Format,
+    config: SolverConfig,
+    // Where to keep the cache between runs, if anywhere.
+    cache: Option<PathBuf>,
+    // How many states trace gets to look at before it gives up.
+    budget: usize,
+}
+
+// Even without --cache, checks go through a cache, which helps with batch
+// files that repeat themselves. It just doesn't outlive the run.
+const CACHE_CAPACITY: usize = 10_000;
+
+// Checking a trace can take exponential time (see trace_satisfies_within), so
+// by default trace gives up after this many states rather than hang.
+const TRACE_BUDGET: usize = 10_000_000;
 
 // Test string: (|| 10/5 12/4) <: (. (|| 300/50 40/10 50/5) 2/1)
 // Test string:
 // (. (|| 10000/234090980909790 100/30) (|| (. 10/5 35209890/1090809383) (. 109/9898 190987/4545 7676/257890176)))
-// TODO: (. 10/5 10/5 (. 10/5 10/5 10/5) 2/3) <: 100000000000000/5 is false, which
-// seems wrong.
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let (left, right) = parse::parse(&args[1]);
-    if streamrate::stream_sub(&left, &right) {
-        println!("{} is true", args[1])
+    let (args, opts) = take_options(env::args().collect());
+    match args.get(1).map(String::as_str) {
+        Some("repl") => {
+            if let Err(err) = repl::run(&opts.config) {
+                eprintln!("repl error: {}", err);
+                process::exit(1);
+            }
+        }
+        Some("check") => match args.get(2) {
+            Some(path) => {
+                let cache = open_cache(opts.cache.as_deref());
+                let passed = check_batch(path, &opts, &cache);
+                save_cache(opts.cache.as_deref(), &cache);
+                if !passed {
+                    process::exit(1);
+                }
+            }
+            None => usage(&args[0]),
+        },
+        Some("trace") => match (args.get(2), args.get(3)) {
+            (Some(rate), Some(path)) => {
+                if !check_trace(rate, path, &opts) {
+                    process::exit(1);
+                }
+            }
+            _ => usage(&args[0]),
+        },
+        Some("generate") => {
+            let mut args = args;
+            let (config, miss) = take_trace_config(&mut args);
+            match args.get(2) {
+                Some(rate) if args.len() == 3 => generate(rate, &config, miss, &opts),
+                _ => usage(&args[0]),
+            }
+        }
+        Some(judgment) => {
+            let cache = open_cache(opts.cache.as_deref());
+            check(judgment, &opts, &cache);
+            save_cache(opts.cache.as_deref(), &cache);
+        }
+        None => usage(&args[0]),
+    }
+}
+
+fn usage(prog: &str) -> ! {
+    eprintln!("usage: {} [options] '<lhs> <: <rhs>'", prog);
+    eprintln!("       {} [options] check <file, or - for stdin>", prog);
+    eprintln!(
+        "       {} [options] trace '<rate>' <file, or - for stdin>",
+        prog
+    );
+    eprintln!(
+        "       {} [options] generate '<rate>' [--mode random|bursty|even|aligned]",
+        prog
+    );
+    eprintln!("           [--seed <n>] [--duration <time>] [--max-events <n>] [--near-miss]");
+    eprintln!("       {} repl", prog);
+    eprintln!("options:");
+    eprintln!("  --format text|json");
+    eprintln!("  --threads <n>     solve each check's cases on this many threads");
+    eprintln!("  --timeout <ms>    give up on each solver query after this long");
+    eprintln!("  --rlimit <n>      give up on each solver query after this much work");
+    eprintln!("  --cache <file>    remember results in this file between runs");
+    eprintln!("  --budget <n>      give up on a trace after looking at this many states");
+    process::exit(2);
+}
+
+// Pull the options out of the args (wherever they are), and leave the rest.
+fn take_options(mut args: Vec<String>) -> (Vec<String>, Options) {
+    let prog = args[0].clone();
+    let format = match take_flag(&mut args, "--format").as_deref() {
+        None | Some("text") => Format::Text,
+        Some("json") => Format::Json,
+        Some(_) => usage(&prog),
+    };
+    let mut config = SolverConfig::default();
+    if let Some(n) = take_flag(&mut args, "--threads") {
+        config.threads = match n.parse() {
+            Ok(n) if n > 0 => n,
+            _ => usage(&prog),
+        };
+    }
+    if let Some(ms) = take_flag(&mut args, "--timeout") {
+        let ms = ms.parse().unwrap_or_else(|_| usage(&prog));
+        config.timeout = Some(Duration::from_millis(ms));
+    }
+    if let Some(n) = take_flag(&mut args, "--rlimit") {
+        config.rlimit = Some(n.parse().unwrap_or_else(|_| usage(&prog)));
+    }
+    let cache = take_flag(&mut args, "--cache").map(PathBuf::from);
+    let budget = match take_flag(&mut args, "--budget") {
+        Some(n) => n.parse().unwrap_or_else(|_| usage(&prog)),
+        None => TRACE_BUDGET,
+    };
+    let opts = Options {
+        format,
+        config,
+        cache,
+        budget,
+    };
+    (args, opts)
+}
+
+// Pull a flag and its value out of the args, if it's there.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == flag)?;
+    let Some(value) = args.get(i + 1).cloned() else {
+        usage(&args[0])
+    };
+    args.drain(i..i + 2);
+    Some(value)
+}
+
+// Pull generate's own flags out of the args.
+fn take_trace_config(args: &mut Vec<String>) -> (TraceConfig, bool) {
+    let prog = args[0].clone();
+    let mode = match take_flag(args, "--mode").as_deref() {
+        None | Some("random") => TraceMode::Random,
+        Some("bursty") => TraceMode::Bursty,
+        Some("even") => TraceMode::Even,
+        Some("aligned") => TraceMode::Aligned,
+        Some(_) => usage(&prog),
+    };
+    let mut config = TraceConfig {
+        mode,
+        ..TraceConfig::default()
+    };
+    if let Some(seed) = take_flag(args, "--seed") {
+        config.seed = seed.parse().unwrap_or_else(|_| usage(&prog));
+    }
+    if let Some(duration) = take_flag(args, "--duration") {
+        config.duration = Some(parse_timestamp(&duration).unwrap_or_else(|| usage(&prog)));
+    }
+    if let Some(n) = take_flag(args, "--max-events") {
+        config.max_events = n.parse().unwrap_or_else(|_| usage(&prog));
+    }
+    let miss = match args.iter().position(|arg| arg == "--near-miss") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    (config, miss)
+}
+
+fn open_cache(path: Option<&Path>) -> SubtypeCache {
+    let Some(path) = path else {
+        return SubtypeCache::new(CACHE_CAPACITY);
+    };
+    match SubtypeCache::load(path, CACHE_CAPACITY) {
+        Ok(cache) => cache,
+        // Nothing there yet is fine, it's just the first run.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => SubtypeCache::new(CACHE_CAPACITY),
+        Err(err) => {
+            eprintln!("couldn't read cache {}: {}", path.display(), err);
+            process::exit(2);
+        }
+    }
+}
+
+// It's only a cache, so failing to save it isn't worth failing the run over.
+fn save_cache(path: Option<&Path>, cache: &SubtypeCache) {
+    if let Some(path) = path
+        && let Err(err) = cache.save(path)
+    {
+        eprintln!("couldn't save cache {}: {}", path.display(), err);
+    }
+}
+
+// Check every judgment in a file. Returns whether all of them came out as
+// expected.
+fn check_batch(path: &str, opts: &Options, cache: &SubtypeCache) -> bool {
+    let input = read_input(path);
+    let results = batch::run_batch(&input, &opts.config, cache);
+    match opts.format {
+        Format::Text => batch::print_table(&results),
+        Format::Json => println!("{}", batch::to_json(&results)),
+    }
+    results.iter().all(|res| res.passed())
+}
+
+// Read a whole file, or stdin for -.
+fn read_input(path: &str) -> String {
+    let mut input = String::new();
+    let read = if path == "-" {
+        io::stdin().read_to_string(&mut input).map(|_| ())
     } else {
-        println!("{} is false", args[1])
+        fs::read_to_string(path).map(|s| input = s)
+    };
+    if let Err(err) = read {
+        eprintln!("couldn't read {}: {}", path, err);
+        process::exit(2);
     }
+    input
+}
+
+// Check a trace file against a rate. Returns whether the trace conforms, which
+// it doesn't if we gave up.
+fn check_trace(rate: &str, path: &str, opts: &Options) -> bool {
+    let sr = match parse_side(rate, 0) {
+        Ok(sr) => sr,
+        Err(err) => {
+            match opts.format {
+                Format::Text => print_parse_error(rate, &err),
+                Format::Json => println!(
+                    "{}",
+                    Json::Object(vec![
+                        ("input", Json::Str(rate.to_string())),
+                        ("error", json::parse_error(&err)),
+                    ])
+                ),
+            }
+            process::exit(1);
+        }
+    };
+    let trace = match tracefile::read_trace(&read_input(path)) {
+        Ok(trace) => trace,
+        Err(err) => {
+            eprintln!("couldn't read {}: {}", path, err);
+            process::exit(2);
+        }
+    };
+    let result = trace_satisfies_within(&sr, &trace, opts.budget);
+    let unknown = match &result {
+        Some(Err(TraceError::Overflow(overflow))) => overflow.to_string(),
+        _ => format!("gave up after {} states", opts.budget),
+    };
+    match opts.format {
+        Format::Text => match &result {
+            Some(Ok(())) => println!("{} events satisfy {}", trace.len(), rate),
+            Some(Err(TraceError::Violation(violation))) => {
+                println!("{} events violate {}: {}", trace.len(), rate, violation);
+                let blame = Blame {
+                    lhs: vec![violation.leaf],
+                    rhs: Vec::new(),
+                };
+                print_blame(rate, &blame);
+            }
+            None | Some(Err(TraceError::Overflow(_))) => println!(
+                "unknown whether {} events satisfy {}: {}",
+                trace.len(),
+                rate,
+                unknown
+            ),
+        },
+        Format::Json => println!(
+            "{}",
+            Json::Object(vec![
+                ("input", Json::Str(rate.to_string())),
+                ("rate", json::stream_rate(&sr)),
+                ("events", Json::Int(trace.len())),
+                (
+                    "satisfies",
+                    match &result {
+                        Some(Ok(())) => Json::Bool(true),
+                        Some(Err(TraceError::Violation(_))) => Json::Bool(false),
+                        None | Some(Err(TraceError::Overflow(_))) => Json::Null,
+                    }
+                ),
+                (
+                    "violation",
+                    match &result {
+                        Some(Err(TraceError::Violation(violation))) => json::violation(violation),
+                        _ => Json::Null,
+                    }
+                ),
+                (
+                    "unknown_reason",
+                    match &result {
+                        None | Some(Err(TraceError::Overflow(_))) => Json::Str(unknown.clone()),
+                        Some(_) => Json::Null,
+                    }
+                ),
+            ])
+        ),
+    }
+    result.is_some_and(|r| r.is_ok())
+}
+
+// Print a made-up trace for a rate, one event per line, in a form that trace
+// can read back.
+fn generate(rate: &str, config: &TraceConfig, miss: bool, opts: &Options) {
+    let sr = match parse_side(rate, 0) {
+        Ok(sr) => sr,
+        Err(err) => {
+            match opts.format {
+                Format::Text => print_parse_error(rate, &err),
+                Format::Json => println!(
+                    "{}",
+                    Json::Object(vec![
+                        ("input", Json::Str(rate.to_string())),
+                        ("error", json::parse_error(&err)),
+                    ])
+                ),
+            }
+            process::exit(1);
+        }
+    };
+    let trace = if miss {
+        near_miss(&sr, config)
+    } else {
+        generate_trace(&sr, config).map(Some)
+    };
+    let trace = match trace {
+        Ok(Some(trace)) => trace,
+        Ok(None) => {
+            eprintln!("no trace is one event away from violating {}", rate);
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("can't make a trace for {}: {}", rate, err);
+            process::exit(1);
+        }
+    };
+    match opts.format {
+        // NOTE: Traces can be long, and often go straight into head or the
+        // like, so stop quietly once nobody's reading.
+        Format::Text => {
+            let mut out = io::stdout().lock();
+            for t in &trace {
+                if writeln!(out, "{}", format_timestamp(t)).is_err() {
+                    break;
+                }
+            }
+        }
+        Format::Json => println!(
+            "{}",
+            Json::Object(vec![
+                ("input", Json::Str(rate.to_string())),
+                ("rate", json::stream_rate(&sr)),
+                (
+                    "events",
+                    Json::Array(
+                        trace
+                            .iter()
+                            .map(|t| Json::Str(format_timestamp(t)))
+                            .collect()
+                    )
+                ),
+            ])
+        ),
+    }
+}
+
+fn check(judgment: &str, opts: &Options, cache: &SubtypeCache) {
+    let (left, right) = match parse(judgment) {
+        Ok(sides) => sides,
+        Err(err) => {
+            match opts.format {
+                Format::Text => print_parse_error(judgment, &err),
+                Format::Json => println!(
+                    "{}",
+                    Json::Object(vec![
+                        ("input", Json::Str(judgment.to_string())),
+                        ("error", json::parse_error(&err)),
+                    ])
+                ),
+            }
+            process::exit(1);
+        }
+    };
+    let start = Instant::now();
+    let report = cache.report(&left, &right, &opts.config);
+    let elapsed = start.elapsed();
+    let blame = report
+        .result
+        .counterexample()
+        .and_then(|_| stream_sub_blame_with(&left, &right, &opts.config));
+    match opts.format {
+        Format::Text => match &report.result {
+            SubResult::Holds => println!("{} is true", judgment),
+            SubResult::Refuted(cex) => {
+                println!("{} is false", judgment);
+                print_counterexample(cex);
+                if let Some(blame) = &blame {
+                    print_blame(judgment, blame);
+                }
+            }
+            SubResult::Unknown(reason) => {
+                println!("{} is unknown: the solver gave up ({})", judgment, reason);
+            }
+        },
+        Format::Json => println!(
+            "{}",
+            Json::Object(vec![
+                ("input", Json::Str(judgment.to_string())),
+                ("lhs", json::stream_rate(&left)),
+                ("rhs", json::stream_rate(&right)),
+                ("lhs_normal", Json::Str(report.lhs_normal.clone())),
+                ("rhs_normal", Json::Str(report.rhs_normal.clone())),
+                ("holds", Json::Bool(report.holds())),
+                (
+                    "result",
+                    Json::Str(json::result_name(&report.result).to_string())
+                ),
+                (
+                    "unknown_reason",
+                    match &report.result {
+                        SubResult::Unknown(reason) => Json::Str(reason.clone()),
+                        SubResult::Holds | SubResult::Refuted(_) => Json::Null,
+                    },
+                ),
+                ("smt_cases", Json::Int(report.smt_cases)),
+                ("elapsed_ms", Json::Float(elapsed.as_secs_f64() * 1000.0)),
+                (
+                    "counterexample",
+                    report
+                        .result
+                        .counterexample()
+                        .map_or(Json::Null, json::counterexample),
+                ),
+                ("blame", blame.as_ref().map_or(Json::Null, json::blame)),
+            ])
+        ),
+    }
+}
+
+fn print_counterexample(cex: &Counterexample) {
+    println!(
+        "counterexample: window {}, lhs has {} events, rhs allows {}",
+        cex.window, cex.lhs_events, cex.rhs_events
+    );
+    for (side, leaves) in [("lhs", &cex.lhs_leaves), ("rhs", &cex.rhs_leaves)] {
+        for leaf in leaves.iter() {
+            println!("  {} {} -> {}", side, leaf.source, leaf.assigned);
+        }
+    }
+    match &cex.events {
+        Some(events) => {
+            let events: Vec<String> = events.iter().map(|e| e.to_string()).collect();
+            println!("  events at: {}", events.join(" "));
+        }
+        None => println!("  (no trace of events found that shows it)"),
+    }
+}
+
+// Print the judgment with the blamed raw rates underlined.
+fn print_blame(input: &str, blame: &Blame) {
+    let (left_spans, right_spans) = leaf_spans(input);
+    let blamed: Vec<_> = blame
+        .lhs
+        .iter()
+        .filter_map(|pos| left_spans.get(*pos))
+        .chain(blame.rhs.iter().filter_map(|pos| right_spans.get(*pos)))
+        .collect();
+    // NOTE: The spans are byte offsets, but the underline needs one column per
+    // char.
+    let underline: String = input
+        .char_indices()
+        .map(|(i, _)| {
+            if blamed
+                .iter()
+                .any(|(start, end)| (*start..*end).contains(&i))
+            {
+                '^'
+            } else {
+                ' '
+            }
+        })
+        .collect();
+    println!("blame:");
+    println!("  {}", input);
+    println!("  {}", underline.trim_end());
+}
+
+// Print the error with a caret under the column where things went wrong.
+fn print_parse_error(input: &str, err: &ParseError) {
+    let column = input[..err.offset()].chars().count();
+    eprintln!("{}", err);
+    eprintln!("  {}", input);
+    eprintln!("  {}^", " ".repeat(column));
 }
@@ -1,8 +1,15 @@
 use crate::streamrate::Rate;
 use crate::streamrate::StreamRate;
+use crate::streamrate::TIME_UNITS;
+use crate::streamrate::Window;
+use crate::streamrate::checked_mul;
+use crate::trace::Timestamp;
+use std::error::Error;
+use std::fmt;
 use std::str;
 
 // (. 10/5 (| 45/5 50/100 6000/1000))
+// (* (. 10/5 20/100))
 // This is more like a Scheme/Lisp s-expr parser.
 // I should learn how to write a real parser at some point, hopefully soon.
 // This is very hacky, but it's OK for now.
@@ -13,23 +20,110 @@ enum ExprOp {
     Sum,
     Par,
     Concat,
+    Star,
+}
+
+/// Everything that can go wrong while parsing. Every error carries the byte
+/// offset (into the full string that was passed to parse) where we noticed
+/// something was wrong, what we expected there, and what we found instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    // Found a character we didn't expect.
+    UnexpectedChar {
+        offset: usize,
+        expected: &'static str,
+        found: char,
+    },
+    // Ran out of input while we were still expecting something.
+    UnexpectedEnd {
+        offset: usize,
+        expected: &'static str,
+    },
+    // Something that should have been a raw rate, but isn't of the form n/t.
+    BadRawRate {
+        offset: usize,
+        expected: &'static str,
+        found: String,
+    },
+    // An operator applied to the wrong number of subexpressions.
+    BadArity {
+        offset: usize,
+        expected: &'static str,
+        found: usize,
+    },
+    // A raw rate with a time unit mixed in with ones without, or the other
+    // way around. We can't tell how long a unitless tick is supposed to be.
+    MixedUnits {
+        offset: usize,
+        expected: &'static str,
+        found: String,
+    },
+}
+
+impl ParseError {
+    /// Byte offset into the full input.
+    pub fn offset(&self) -> usize {
+        match self {
+            ParseError::UnexpectedChar { offset, .. }
+            | ParseError::UnexpectedEnd { offset, .. }
+            | ParseError::BadRawRate { offset, .. }
+            | ParseError::BadArity { offset, .. }
+            | ParseError::MixedUnits { offset, .. } => *offset,
+        }
+    }
+
+    pub fn expected(&self) -> &'static str {
+        match self {
+            ParseError::UnexpectedChar { expected, .. }
+            | ParseError::UnexpectedEnd { expected, .. }
+            | ParseError::BadRawRate { expected, .. }
+            | ParseError::BadArity { expected, .. }
+            | ParseError::MixedUnits { expected, .. } => expected,
+        }
+    }
+
+    pub fn found(&self) -> String {
+        match self {
+            ParseError::UnexpectedChar { found, .. } => format!("{:?}", found),
+            ParseError::UnexpectedEnd { .. } => "end of input".to_string(),
+            ParseError::BadRawRate { found, .. } => format!("{:?}", found),
+            ParseError::BadArity { found, .. } => format!("{} subexpressions", found),
+            ParseError::MixedUnits { found, .. } => format!("{:?}", found),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "parsing error at byte {}: expected {}, found {}",
+            self.offset(),
+            self.expected(),
+            self.found()
+        )
+    }
+}
+
+impl Error for ParseError {}
+
+// An unparsed subexpression, along with its offset in the full input.
+type Chunk<'a> = (usize, &'a str);
+
+// Trim whitespace off of s, and shift s's offset (base) along with the start.
+fn trim_at(s: &str, base: usize) -> (&str, usize) {
+    let s_start = s.trim_start();
+    (s_start.trim_end(), base + (s.len() - s_start.len()))
 }
 
 fn get_next_parenthesized_chunk<'a>(
     s: &'a str,
     s_iter: &mut str::CharIndices,
     start_idx: usize,
-) -> &'a str {
-    let error_prefix = "parsing error:";
-    match s.get(start_idx..start_idx + 1) {
-        // TODO: OK, well to start: the start index is not actually a (, despite
-        // us directly making sure that this is the case at the call site...
-        None => panic!("wtf!"),
-        Some(c) => match c {
-            "(" => (),
-            _ => panic!("first character of parenthesized chunk should be ("),
-        },
-    }
+    base: usize,
+) -> Result<&'a str, ParseError> {
+    // NOTE: The caller has to make sure that s[start_idx] is a (, and that
+    // s_iter is s.char_indices() advanced just past it.
     // We only call this function when we've discovered an open parenthesis.
     let mut open_parens = 1;
     loop {
@@ -40,7 +134,7 @@ fn get_next_parenthesized_chunk<'a>(
                         open_parens -= 1;
                         if open_parens == 0 {
                             // We want the i+1, since we want the trailing ).
-                            break &s[start_idx..i + 2];
+                            break Ok(&s[start_idx..i + 1]);
                         } else {
                             continue;
                         }
@@ -53,34 +147,33 @@ fn get_next_parenthesized_chunk<'a>(
                 }
             }
             None => {
-                if open_parens == 0 {
-                    break &s[start_idx..];
-                } else {
-                    panic!("{} unclosed parentheses", error_prefix)
-                }
+                break Err(ParseError::UnexpectedEnd {
+                    offset: base + s.len(),
+                    expected: ")",
+                });
             }
         }
     }
 }
 
-fn chunk_one_level(s: &str) -> (ExprOp, Vec<&str>) {
-    // TODO: Handle case where it's just 1 raw rate with no operators.
-    let error_prefix = "parsing error:";
-    let mut chunked: Vec<&str> = Vec::new();
-    let s_trim = s.trim();
+// Splits s into its operator and its (unparsed) subexpressions. base is the
+// offset of s in the full input.
+fn chunk_one_level(s: &str, base: usize) -> Result<(ExprOp, Vec<Chunk<'_>>), ParseError> {
+    let mut chunked: Vec<Chunk> = Vec::new();
+    let (s_trim, base) = trim_at(s, base);
     let mut s_trim_iter = s_trim.char_indices();
     let mut outer_open_parens = 0;
     let mut active_range = false;
     let mut active_start = 0;
     let mut active_end = 0;
     loop {
+        // NOTE: This sort of ends up being the same code that gets raw
+        // rate type strings later in this function, but formatted
+        // slightly differently, i.e. we directly pattern match on the
+        // tuple later on, whereas here we pattern match on the char part
+        // exclusively. I guess I could unify this later with some nicer
+        // abstractions, but...it's fine for now.
         match s_trim_iter.next() {
-            // NOTE: This sort of ends up being the same code that gets raw
-            // rate type strings later in this function, but formatted
-            // slightly differently, i.e. we directly pattern match on the
-            // tuple later on, whereas here we pattern match on the char part
-            // exclusively. I guess I could unify this later with some nicer
-            // abstractions, but...it's fine for now.
             Some(c_tuple) => {
                 let (i, c) = c_tuple;
                 match c {
@@ -88,31 +181,28 @@ fn chunk_one_level(s: &str) -> (ExprOp, Vec<&str>) {
                         outer_open_parens += 1;
                         break;
                     }
-                    // Must be a single raw rate, otherwise panic.
-                    '0'..='9' | '/' => {
-                        if active_range {
-                            active_end = i
-                        } else {
+                    // Must be a single raw rate, otherwise error out.
+                    '0'..='9' | '/' | '.' | '[' | ']' | 'a'..='z' => {
+                        if !active_range {
                             active_range = true;
                             active_start = i
                         }
                     }
                     _ => {
-                        panic!(
-                            "{} expression must either begin with ( or be a \
-                                single raw rate",
-                            error_prefix
-                        )
+                        return Err(ParseError::UnexpectedChar {
+                            offset: base + i,
+                            expected: "( or a single raw rate",
+                            found: c,
+                        });
                     }
                 }
             }
             None => {
                 if active_range {
-                    chunked.push(s);
-                    return (ExprOp::None, chunked);
-                } else {
-                    return (ExprOp::None, chunked); // Just return empty Vec
+                    chunked.push((base + active_start, s_trim));
                 }
+                // Otherwise, just return an empty Vec.
+                return Ok((ExprOp::None, chunked));
             }
         }
     }
@@ -122,21 +212,39 @@ fn chunk_one_level(s: &str) -> (ExprOp, Vec<&str>) {
             Some(c_tuple) => match c_tuple {
                 (_, ' ') | (_, '\t') => continue,
                 (_, '.') => break ExprOp::Concat,
-                (_, '|') => match s_trim_iter.next() {
+                (i, '|') => match s_trim_iter.next() {
                     Some(c_tuple) => match c_tuple {
                         (_, '|') => break ExprOp::Par,
-                        (_, _) => panic!("{} | should be ||", error_prefix),
+                        (j, c) => {
+                            return Err(ParseError::UnexpectedChar {
+                                offset: base + j,
+                                expected: "|| (| should be ||)",
+                                found: c,
+                            });
+                        }
                     },
-                    None => panic!("{} | should be ||", error_prefix),
+                    None => {
+                        return Err(ParseError::UnexpectedEnd {
+                            offset: base + i + 1,
+                            expected: "|| (| should be ||)",
+                        });
+                    }
                 },
                 (_, '+') => break ExprOp::Sum,
-                (_, _) => panic!(
-                    "{} open parenthesis must be followed by operator",
-                    error_prefix
-                ),
+                (_, '*') => break ExprOp::Star,
+                (i, c) => {
+                    return Err(ParseError::UnexpectedChar {
+                        offset: base + i,
+                        expected: "an operator (., ||, + or *) after (",
+                        found: c,
+                    });
+                }
             },
             None => {
-                panic!("{} no operator found for expression", error_prefix)
+                return Err(ParseError::UnexpectedEnd {
+                    offset: base + s_trim.len(),
+                    expected: "an operator (., ||, + or *) after (",
+                });
             }
         }
     };
@@ -145,42 +253,64 @@ fn chunk_one_level(s: &str) -> (ExprOp, Vec<&str>) {
         match s_trim_iter.next() {
             Some(c_tuple) => {
                 match c_tuple {
-                    (i, '0'..='9') | (i, '/') => {
+                    (i, '0'..='9') | (i, '/') | (i, '.') | (i, '[') | (i, ']') | (i, 'a'..='z') => {
                         if active_range {
                             active_end = i
                         } else {
                             active_range = true;
-                            active_start = i
+                            active_start = i;
+                            active_end = i
                         }
                     }
                     (_, ' ') | (_, '\t') => {
                         if active_range {
                             active_range = false;
-                            chunked.push(&s_trim[active_start..(active_end + 1)])
+                            chunked.push((
+                                base + active_start,
+                                &s_trim[active_start..(active_end + 1)],
+                            ))
                         }
                     }
                     (i, '(') => {
                         if active_range {
                             active_range = false;
-                            chunked.push(&s_trim[active_start..(active_end + 1)])
+                            chunked.push((
+                                base + active_start,
+                                &s_trim[active_start..(active_end + 1)],
+                            ))
                         };
-                        let pchunk = get_next_parenthesized_chunk(s, &mut s_trim_iter, i);
-                        chunked.push(pchunk)
+                        let pchunk =
+                            get_next_parenthesized_chunk(s_trim, &mut s_trim_iter, i, base)?;
+                        chunked.push((base + i, pchunk))
                     }
                     (_, ')') => {
                         outer_open_parens -= 1;
                         if active_range {
                             active_range = false;
-                            chunked.push(&s_trim[active_start..(active_end + 1)])
+                            chunked.push((
+                                base + active_start,
+                                &s_trim[active_start..(active_end + 1)],
+                            ))
                         };
                         if outer_open_parens == 0 {
-                            // Just stop parsing when our initial open parenthesis
-                            // gets closed (anything afterwards will just be ignored).
+                            // Our initial open parenthesis is closed, so
+                            // there shouldn't be anything left but whitespace.
+                            if let Some((j, c)) = s_trim_iter.find(|(_, c)| !c.is_whitespace()) {
+                                return Err(ParseError::UnexpectedChar {
+                                    offset: base + j,
+                                    expected: "nothing after the closing )",
+                                    found: c,
+                                });
+                            }
                             break;
                         }
                     }
                     (i, c) => {
-                        panic!("{} did not expect {} at character {}", error_prefix, c, i)
+                        return Err(ParseError::UnexpectedChar {
+                            offset: base + i,
+                            expected: "a raw rate, ( or )",
+                            found: c,
+                        });
                     }
                 }
             }
@@ -188,107 +318,596 @@ fn chunk_one_level(s: &str) -> (ExprOp, Vec<&str>) {
                 if outer_open_parens == 0 {
                     break;
                 } else {
-                    panic!("{} expression not closed with )", error_prefix)
+                    return Err(ParseError::UnexpectedEnd {
+                        offset: base + s_trim.len(),
+                        expected: ")",
+                    });
                 }
             }
         }
     }
-    return (op, chunked);
+    Ok((op, chunked))
+}
+
+fn parse_count(part: Option<&str>, chunk: &str, base: usize) -> Result<usize, ParseError> {
+    let bad_raw_rate = || ParseError::BadRawRate {
+        offset: base,
+        expected: "a raw rate of the form n/t",
+        found: chunk.to_string(),
+    };
+    match part {
+        None => Err(bad_raw_rate()),
+        Some(e) => e.parse::<usize>().map_err(|_| bad_raw_rate()),
+    }
+}
+
+// Windows can be whole numbers, decimals, e.g. 1.5 or 0.25, or fractions in
+// brackets, e.g. [1/3], followed by an optional time unit, e.g. 250ms. Windows
+// with a unit come back in seconds, along with true (i.e. timed).
+fn parse_window(
+    part: Option<&str>,
+    chunk: &str,
+    base: usize,
+) -> Result<(Window, bool), ParseError> {
+    let Some(unit_start) = part.and_then(|w| w.find(|c: char| c.is_ascii_alphabetic())) else {
+        return parse_number(part, chunk, base).map(|w| (w, false));
+    };
+    let (number, unit) = part.unwrap().split_at(unit_start);
+    let seconds = match TIME_UNITS.iter().find(|(name, _)| *name == unit) {
+        Some((_, seconds)) => seconds,
+        None => {
+            return Err(ParseError::BadRawRate {
+                offset: base,
+                expected: "a time unit (d, h, m, s, ms, us or ns)",
+                found: chunk.to_string(),
+            });
+        }
+    };
+    let w = parse_number(Some(number), chunk, base)?;
+    match checked_mul(&w, seconds) {
+        Some(w) => Ok((w, true)),
+        None => Err(ParseError::BadRawRate {
+            offset: base,
+            expected: "a window that's in range",
+            found: chunk.to_string(),
+        }),
+    }
+}
+
+fn parse_number(part: Option<&str>, chunk: &str, base: usize) -> Result<Window, ParseError> {
+    match part.and_then(|w| w.strip_prefix('[')?.strip_suffix(']')) {
+        Some(fraction) => parse_fraction(fraction, chunk, base),
+        None => parse_decimal(part, chunk, base),
+    }
+}
+
+// The inside of a bracketed window, e.g. 1/3. That's how windows without a
+// finite decimal expansion print.
+fn parse_fraction(fraction: &str, chunk: &str, base: usize) -> Result<Window, ParseError> {
+    let mut parts = fraction.split('/');
+    let numer = parse_count(parts.next(), chunk, base)?;
+    let denom = parse_count(parts.next(), chunk, base)?;
+    if parts.next().is_some() || denom == 0 {
+        return Err(ParseError::BadRawRate {
+            offset: base,
+            expected: "a window of the form [a/b], with b nonzero",
+            found: chunk.to_string(),
+        });
+    }
+    Ok(Window::new(numer, denom))
+}
+
+fn parse_decimal(part: Option<&str>, chunk: &str, base: usize) -> Result<Window, ParseError> {
+    let bad_raw_rate = || ParseError::BadRawRate {
+        offset: base,
+        expected: "a raw rate of the form n/t",
+        found: chunk.to_string(),
+    };
+    let Some((whole, frac)) = part.and_then(|w| w.split_once('.')) else {
+        return parse_count(part, chunk, base).map(Window::from_integer);
+    };
+    let whole = parse_count(Some(whole), chunk, base)?;
+    if frac.is_empty() || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(bad_raw_rate());
+    }
+    // NOTE: Too many digits after the point and 10^digits overflows, so we
+    // just refuse those.
+    let unit = u32::try_from(frac.len())
+        .ok()
+        .and_then(|digits| 10usize.checked_pow(digits))
+        .ok_or_else(bad_raw_rate)?;
+    let frac = parse_count(Some(frac), chunk, base)?;
+    whole
+        .checked_mul(unit)
+        .and_then(|w| w.checked_add(frac))
+        .map(|numer| Window::new(numer, unit))
+        .ok_or_else(bad_raw_rate)
 }
 
-fn parse_chunk(chunk: &str) -> StreamRate {
+fn parse_chunk(chunk: &str, base: usize) -> Result<StreamRate, ParseError> {
     match chunk.get(0..1) {
-        Some("(") => parse_side(chunk),
+        Some("(") => parse_side_unchecked(chunk, base),
         Some(_) => {
-            let mut rate_parts = chunk.split('/');
-            let ev_count: usize = match rate_parts.next() {
-                None => panic!("raw rate must have form n/t"),
-                Some(e) => match e.parse::<usize>() {
-                    Err(err) => panic!("raw rate event count is ill formed: {}", err),
-                    Ok(r) => r,
-                },
-            };
-            let win_size: usize = match rate_parts.next() {
-                None => panic!("raw rate must have form n/t"),
-                Some(e) => match e.parse::<usize>() {
-                    Err(err) => panic!("raw rate window size is  ill formed: {}", err),
-                    Ok(r) => r,
-                },
-            };
-            StreamRate::Raw(Rate {
+            // NOTE: Only the first / splits the count from the window, since a
+            // window in brackets has one of its own.
+            let mut rate_parts = chunk.splitn(2, '/');
+            let ev_count = parse_count(rate_parts.next(), chunk, base)?;
+            let (win_size, timed) = parse_window(rate_parts.next(), chunk, base)?;
+            if win_size == Window::from_integer(0) {
+                return Err(ParseError::BadRawRate {
+                    offset: base,
+                    expected: "a nonzero window",
+                    found: chunk.to_string(),
+                });
+            }
+            Ok(StreamRate::Raw(Rate {
                 events: ev_count,
                 window: win_size,
-            })
+                timed,
+            }))
         }
-        None => panic!("passed empty chunk to parse_chunk"),
+        None => Err(ParseError::UnexpectedEnd {
+            offset: base,
+            expected: "a stream rate expression",
+        }),
     }
 }
 
-fn generate_streamrate_rec(eo: &ExprOp, v: Vec<&str>) -> Option<StreamRate> {
-    match v.len() {
-        0 => None,
-        1 => {
-            // This unwrap is guaranteed to be safe.
-            Some(parse_chunk(v.get(0).unwrap().trim()))
-        }
-        _ => {
-            let hd_parsed = parse_chunk(v.get(0).unwrap().trim());
-            // This unwrap should also be guaranteed to be safe, although it's a
-            // bit harder to reason about and prove. Basically, the case analysis
-            // here makes it so.
-            let tl_parsed = generate_streamrate_rec(eo, v.get(1..).unwrap().to_vec()).unwrap();
-            match eo {
-                ExprOp::None => None,
-                ExprOp::Sum => Some(StreamRate::Sum(Box::new(hd_parsed), Box::new(tl_parsed))),
-                ExprOp::Concat => {
-                    Some(StreamRate::Concat(Box::new(hd_parsed), Box::new(tl_parsed)))
-                }
-                ExprOp::Par => Some(StreamRate::Par(Box::new(hd_parsed), Box::new(tl_parsed))),
-            }
-        }
+fn generate_streamrate_rec(eo: &ExprOp, v: &[Chunk]) -> Result<Option<StreamRate>, ParseError> {
+    let parsed = v
+        .iter()
+        .map(|(base, chunk)| {
+            let (chunk, base) = trim_at(chunk, *base);
+            parse_chunk(chunk, base)
+        })
+        .collect::<Result<Vec<StreamRate>, ParseError>>()?;
+    // NOTE: A single subexpression still gets its operator, even though it's
+    // the same type without, so that printing and parsing round-trips.
+    if parsed.is_empty() {
+        return Ok(None);
     }
+    Ok(match eo {
+        // Star only takes a single subexpression, which
+        // generate_streamrate checks before we ever get here.
+        ExprOp::None | ExprOp::Star => None,
+        ExprOp::Sum => Some(StreamRate::Sum(parsed)),
+        ExprOp::Concat => Some(StreamRate::Concat(parsed)),
+        ExprOp::Par => Some(StreamRate::Par(parsed)),
+    })
 }
-fn generate_streamrate(eo: &ExprOp, v: Vec<&str>) -> StreamRate {
-    let error_prefix = "parsing error:";
-    if v.len() == 0 {
-        panic!("{} no subexpressions after operator", error_prefix)
+
+// base is the offset of the whole expression, for errors about the operator's
+// subexpressions as a group.
+fn generate_streamrate(eo: &ExprOp, v: Vec<Chunk>, base: usize) -> Result<StreamRate, ParseError> {
+    if let ExprOp::Star = eo {
+        if v.len() != 1 {
+            return Err(ParseError::BadArity {
+                offset: base,
+                expected: "exactly one subexpression after *",
+                found: v.len(),
+            });
+        }
+        let (chunk, chunk_base) = trim_at(v[0].1, v[0].0);
+        return Ok(StreamRate::Star(Box::new(parse_chunk(chunk, chunk_base)?)));
     };
-    match generate_streamrate_rec(&eo, v) {
-        None => panic!("{} no subexpressions after operator", error_prefix),
-        Some(sr) => sr,
+    match generate_streamrate_rec(eo, &v)? {
+        None => Err(ParseError::BadArity {
+            offset: base,
+            expected: "at least one subexpression after operator",
+            found: 0,
+        }),
+        Some(sr) => Ok(sr),
+    }
+}
+
+/// Parses one side of a subtyping judgment, e.g. (. 10/5 (|| 45/5 50/100)).
+/// base is the offset of s in whatever larger string it came from, and only
+/// matters for the offsets reported in errors.
+pub fn parse_side(s: &str, base: usize) -> Result<StreamRate, ParseError> {
+    let sr = parse_side_unchecked(s, base)?;
+    check_units(&sr, s, base, None)?;
+    Ok(sr)
+}
+
+/// Parses a timestamp for [`crate::trace_satisfies`], written the same way as
+/// a window: a whole number or a decimal, e.g. 12 or 0.25, with an optional
+/// time unit, e.g. 250ms, which puts it in seconds. Fractions like 1/3 are
+/// fine too, since that's how [`crate::format_timestamp`] writes times that
+/// aren't decimals.
+pub fn parse_timestamp(s: &str) -> Option<Timestamp> {
+    if let Some((numer, denom)) = s.split_once('/') {
+        let numer = parse_count(Some(numer), s, 0).ok()?;
+        let denom = parse_count(Some(denom), s, 0).ok()?;
+        return (denom != 0).then(|| Timestamp::new(numer, denom));
     }
+    parse_window(Some(s), s, 0).ok().map(|(t, _)| t)
 }
 
-fn parse_side(s: &str) -> StreamRate {
-    let error_prefix = "parsing error:";
-    match chunk_one_level(s) {
+fn parse_side_unchecked(s: &str, base: usize) -> Result<StreamRate, ParseError> {
+    let (s_trim, base) = trim_at(s, base);
+    match chunk_one_level(s_trim, base)? {
         (ExprOp::None, v) => {
             match v.len() {
-                0 => panic!("{} stream rate expression is empty", error_prefix),
+                0 => Err(ParseError::UnexpectedEnd {
+                    offset: base,
+                    expected: "a stream rate expression",
+                }),
                 // If just a single raw rate, directly generate StreamRate
-                1 => parse_chunk(v[0]),
-                _ => panic!(
-                    "{} only single raw rate allowed if no operator",
-                    error_prefix
-                ),
+                1 => parse_chunk(v[0].1, v[0].0),
+                // NOTE: chunk_one_level should never actually give us more
+                // than one chunk without an operator.
+                n => Err(ParseError::BadArity {
+                    offset: base,
+                    expected: "a single raw rate if there is no operator",
+                    found: n,
+                }),
             }
         }
-        (eo, v) => generate_streamrate(&eo, v),
+        (eo, v) => generate_streamrate(&eo, v, base),
     }
 }
 
-pub fn parse(full_sub_str: &String) -> (StreamRate, StreamRate) {
-    let mut split_sides = full_sub_str.split("<:");
-    let left = match split_sides.next() {
-        None => panic!("wtf!"),
-        Some(r) => parse_side(&r.trim()),
-    };
-    let right = match split_sides.next() {
-        None => panic!("wtf!"),
-        Some(r) => parse_side(&r.trim()),
+/// Parses a subtyping judgment of the form left <: right.
+pub fn parse(full_sub_str: &str) -> Result<(StreamRate, StreamRate), ParseError> {
+    let split_idx = match full_sub_str.find("<:") {
+        None => {
+            return Err(ParseError::UnexpectedEnd {
+                offset: full_sub_str.len(),
+                expected: "<:",
+            });
+        }
+        Some(i) => i,
     };
+    let left = parse_side(&full_sub_str[..split_idx], 0)?;
+    let right = parse_side(&full_sub_str[split_idx + 2..], split_idx + 2)?;
+    // Each side is consistent on its own, so it's enough to hold the right
+    // side to whatever the left side does.
+    let left_timed = leaves_timed(&left).first().copied();
+    check_units(
+        &right,
+        &full_sub_str[split_idx + 2..],
+        split_idx + 2,
+        left_timed,
+    )?;
     // TODO: Remove or comment out after testing.
     // dbg!(left.clone());
     // dbg!(right.clone());
+    Ok((left, right))
+}
+
+// Whether each Raw leaf of sr has a unit, left to right.
+fn leaves_timed(sr: &StreamRate) -> Vec<bool> {
+    match sr {
+        StreamRate::Raw(r) => vec![r.timed],
+        StreamRate::Sum(srs) | StreamRate::Par(srs) | StreamRate::Concat(srs) => {
+            srs.iter().flat_map(leaves_timed).collect()
+        }
+        StreamRate::Star(sr) => leaves_timed(sr),
+    }
+}
+
+// Either every raw rate in sr (which was parsed from s, at offset base) has a
+// unit, or none of them do. If timed is given, they all have to match it,
+// otherwise they have to match the first one.
+fn check_units(
+    sr: &StreamRate,
+    s: &str,
+    base: usize,
+    timed: Option<bool>,
+) -> Result<(), ParseError> {
+    let leaves = leaves_timed(sr);
+    let Some(timed) = timed.or(leaves.first().copied()) else {
+        return Ok(());
+    };
+    let Some(i) = leaves.iter().position(|t| *t != timed) else {
+        return Ok(());
+    };
+    let (start, end) = leaf_spans(s).0[i];
+    Err(ParseError::MixedUnits {
+        offset: base + start,
+        expected: if timed {
+            "a time unit, since the other rates have one"
+        } else {
+            "no time unit, since the other rates don't have one"
+        },
+        found: s[start..end].to_string(),
+    })
+}
+
+/// A byte range (start, end) in the input.
+pub type Span = (usize, usize);
+
+/// Byte ranges of each raw rate in a subtyping judgment, for the left and
+/// right sides. Raw rates are just the runs of digits, /, ., brackets and
+/// units (that start with a digit), so reading them off left to right gives
+/// the same order that the leaves of the parsed StreamRate are numbered in.
+pub fn leaf_spans(full_sub_str: &str) -> (Vec<Span>, Vec<Span>) {
+    let split_idx = full_sub_str.find("<:").unwrap_or(full_sub_str.len());
+    let mut left = Vec::new();
+    let mut right = Vec::new();
+    let mut active_start: Option<usize> = None;
+    // Tack a space onto the end so that a trailing raw rate still gets closed.
+    for (i, c) in full_sub_str
+        .char_indices()
+        .chain([(full_sub_str.len(), ' ')])
+    {
+        match (c, active_start) {
+            ('0'..='9' | '/', None) => active_start = Some(i),
+            // A . can't start a raw rate (it's Concat there), but it can be
+            // the decimal point in a window, brackets can go around it, and
+            // letters can be its unit.
+            ('0'..='9' | '/' | '.' | '[' | ']' | 'a'..='z', Some(_)) => continue,
+            (_, Some(start)) => {
+                if start < split_idx {
+                    left.push((start, i))
+                } else {
+                    right.push((start, i))
+                }
+                active_start = None
+            }
+            (_, None) => continue,
+        }
+    }
     (left, right)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies::{self, Shape};
+    use crate::trace::format_timestamp;
+    use proptest::prelude::*;
+
+    fn arb_stream_rate() -> impl Strategy<Value = StreamRate> {
+        // Denominators that print as decimals, so printing and parsing
+        // round-trips.
+        let leaf = (
+            0..1000usize,
+            1..1000usize,
+            prop::sample::select(vec![1usize, 2, 4, 5, 8, 100]),
+        )
+            .prop_map(|(events, numer, denom)| Rate {
+                events,
+                window: Window::new(numer, denom),
+                timed: false,
+            });
+        let shape = Shape {
+            depth: 4,
+            size: 32,
+            width: 3,
+        };
+        strategies::arb_stream_rate(leaf, shape)
+    }
+
+    #[test]
+    fn test_parse() {
+        let raw = |events, window| StreamRate::Raw(Rate::new(events, window));
+        assert_eq!(
+            parse("(. 10/5 (|| 45/5 50/100)) <: (* 2/1)"),
+            Ok((
+                StreamRate::Concat(vec![
+                    raw(10, 5),
+                    StreamRate::Par(vec![raw(45, 5), raw(50, 100)])
+                ]),
+                StreamRate::Star(Box::new(raw(2, 1)))
+            ))
+        );
+        assert_eq!(
+            parse_side("3/1.5", 0),
+            Ok(StreamRate::Raw(Rate {
+                events: 3,
+                window: Window::new(3, 2),
+                timed: false,
+            }))
+        );
+        // Units all come out in seconds.
+        let timed = |events, seconds| StreamRate::Raw(Rate::timed(events, seconds));
+        assert_eq!(
+            parse("(|| 10/5s 100/1m 5/250ms) <: (+ 1/1h 2/1.5us 3/2d)"),
+            Ok((
+                StreamRate::Par(vec![
+                    timed(10, Window::from_integer(5)),
+                    timed(100, Window::from_integer(60)),
+                    timed(5, Window::new(1, 4)),
+                ]),
+                StreamRate::Sum(vec![
+                    timed(1, Window::from_integer(3600)),
+                    timed(2, Window::new(3, 2000000)),
+                    timed(3, Window::from_integer(172800)),
+                ])
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("12"), Some(Window::from_integer(12)));
+        assert_eq!(parse_timestamp("0.25"), Some(Window::new(1, 4)));
+        assert_eq!(parse_timestamp("250ms"), Some(Window::new(1, 4)));
+        assert_eq!(parse_timestamp("2m"), Some(Window::from_integer(120)));
+        assert_eq!(parse_timestamp("4/6"), Some(Window::new(2, 3)));
+        for bad in ["", "-1", "1.", "time", "3 s", "1/0", "1/", "0.5/2"] {
+            assert_eq!(parse_timestamp(bad), None);
+        }
+        for t in [
+            Window::new(1, 3),
+            Window::new(5, 2),
+            Window::from_integer(7),
+        ] {
+            assert_eq!(parse_timestamp(&format_timestamp(&t)), Some(t));
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse("(| 5/10 7/5) <: 1/1"),
+            Err(ParseError::UnexpectedChar {
+                offset: 2,
+                expected: "|| (| should be ||)",
+                found: ' ',
+            })
+        );
+        assert_eq!(
+            parse("1/1 <: (|| 5/10 (. 7/5 1/1)"),
+            Err(ParseError::UnexpectedEnd {
+                offset: 27,
+                expected: ")",
+            })
+        );
+        assert_eq!(
+            parse("1/1 <: (|| 5/10 1/2/3)"),
+            Err(ParseError::BadRawRate {
+                offset: 16,
+                expected: "a raw rate of the form n/t",
+                found: "1/2/3".to_string(),
+            })
+        );
+        for zero in ["1/0", "1/0s", "1/0.0", "1/0.000ms", "1/[0/3]"] {
+            assert_eq!(
+                parse_side(zero, 0),
+                Err(ParseError::BadRawRate {
+                    offset: 0,
+                    expected: "a nonzero window",
+                    found: zero.to_string(),
+                })
+            );
+        }
+        assert_eq!(parse("1/1 <: 1/0").unwrap_err().offset(), 7);
+        for bad in [
+            "1/1.",
+            "1/.5",
+            "1/1.5.5",
+            "1/1.12345678901234567890",
+            "1/[1/3",
+            "1/[1.5/3]",
+            "1/[1]",
+        ] {
+            assert!(matches!(
+                parse_side(bad, 0),
+                Err(ParseError::BadRawRate { .. })
+            ));
+        }
+        for bad in ["1/[1/0]", "1/[1/2/3]"] {
+            assert_eq!(
+                parse_side(bad, 0),
+                Err(ParseError::BadRawRate {
+                    offset: 0,
+                    expected: "a window of the form [a/b], with b nonzero",
+                    found: bad.to_string(),
+                })
+            );
+        }
+        assert_eq!(
+            parse("1/1s <: 1/1y"),
+            Err(ParseError::BadRawRate {
+                offset: 8,
+                expected: "a time unit (d, h, m, s, ms, us or ns)",
+                found: "1/1y".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("(|| 10/5s 3/4) <: 1/1s"),
+            Err(ParseError::MixedUnits {
+                offset: 10,
+                expected: "a time unit, since the other rates have one",
+                found: "3/4".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("(. 10/5 3/4) <: (|| 1/1 60/1m)"),
+            Err(ParseError::MixedUnits {
+                offset: 24,
+                expected: "no time unit, since the other rates don't have one",
+                found: "60/1m".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_side("1/99999999999999999d", 0),
+            Err(ParseError::BadRawRate {
+                offset: 0,
+                expected: "a window that's in range",
+                found: "1/99999999999999999d".to_string(),
+            })
+        );
+        // Big numbers are fine, as long as the window is in range.
+        assert_eq!(
+            parse_side("1/99999999999999999ns", 0).unwrap().to_string(),
+            "1/99999999999999999ns"
+        );
+        // Nothing but whitespace can come after the closing ).
+        for (bad, offset, found) in [
+            ("(|| 1/1 2/2)) <: 3/1", 12, ')'),
+            ("(|| 1/1 2/2) 5/5 <: 3/1", 13, '5'),
+            ("(|| 1/1 2/2)x <: 3/1", 12, 'x'),
+            ("1/1 <: (|| 1/1 2/2) junk", 20, 'j'),
+        ] {
+            assert_eq!(
+                parse(bad),
+                Err(ParseError::UnexpectedChar {
+                    offset,
+                    expected: "nothing after the closing )",
+                    found,
+                })
+            );
+        }
+        assert!(parse("(|| 1/1 2/2)  <: (. 1/1 2/2) ").is_ok());
+        assert_eq!(parse("(* 1/1 2/2)").unwrap_err().expected(), "<:");
+        assert_eq!(parse("(* 1/1 2/2) <: 1/1").unwrap_err().offset(), 0);
+    }
+
+    #[test]
+    fn test_print_parse_examples() {
+        for s in [
+            "10/5",
+            "(. 10/5 (|| 45/5 50/100))",
+            "(+ 1/1 2/2 3/3)",
+            "(. (. 1/1 2/2) 3/3)",
+            "(* (|| 1/2 (* 3/4)))",
+            "(|| 3/1.5 1000/0.25 7/10.125)",
+            "(. 10/5s 100/1m 5/250ms 1/1h 1/2d 7/3us 9/1ns)",
+            "1/1500ms",
+            "(+ 1/[1/3] (* 2/[10/3]))",
+            "1/[1/3]s",
+            "(. (+ 1/1) (|| 2/2))",
+            "1/18446744073709551615",
+            "1/1844674407370955161.5",
+            "1/[18446744073709551615/4]",
+        ] {
+            assert_eq!(parse_side(s, 0).unwrap().to_string(), s);
+        }
+    }
+
+    // Any window at all, with or without a time unit (but the same for every
+    // leaf, so that the units don't get mixed), and single-operand nodes too.
+    fn arb_any_stream_rate() -> impl Strategy<Value = StreamRate> {
+        let shape = Shape {
+            depth: 3,
+            size: 12,
+            width: 3,
+        };
+        any::<bool>().prop_flat_map(move |timed| {
+            let leaf = (any::<usize>(), 1..=usize::MAX, 1..=usize::MAX).prop_map(
+                move |(events, numer, denom)| Rate {
+                    events,
+                    window: Window::new(numer, denom),
+                    timed,
+                },
+            );
+            strategies::arb_single_stream_rate(leaf, shape)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_print_parse_roundtrip(lhs in arb_stream_rate(), rhs in arb_stream_rate()) {
+            let printed = format!("{} <: {}", lhs, rhs);
+            prop_assert_eq!(parse(&printed), Ok((lhs, rhs)));
+        }
+
+        #[test]
+        fn test_print_parse_any_window(sr in arb_any_stream_rate()) {
+            prop_assert_eq!(parse_side(&sr.to_string(), 0), Ok(sr));
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use ratelimitsub_proto2::{
+    SolverConfig, StreamRate, SubResult, canonicalize, parse_side, stream_sub_blame_with,
+    stream_sub_report_with, stream_sub_smt,
+};
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+
+const HELP: &str = "\
+let NAME = A     bind NAME to the rate A (names can be used anywhere a rate can)
+:sub A B         check A <: B
+:explain A B     check A <: B, and explain why it fails if it does
+:norm A          print the canonical form of A
+:smt A B         print the SMT queries for A <: B
+:help            print this
+:quit            quit (so does Ctrl-D)
+Two-rate commands also take A <: B.";
+
+// Every check goes through config, so the limits on the command line hold here
+// too.
+pub fn run(config: &SolverConfig) -> Result<(), ReadlineError> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // NOTE: This fails the first time around, when there's no history yet.
+        let _ = editor.load_history(path);
+    }
+    let mut bindings: HashMap<String, StreamRate> = HashMap::new();
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+        if line == ":quit" || line == ":q" {
+            break;
+        }
+        if let Err(msg) = eval(&mut bindings, line, config) {
+            eprintln!("{}", msg);
+        }
+    }
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".ratelimitsub_history"))
+}
+
+fn eval(
+    bindings: &mut HashMap<String, StreamRate>,
+    line: &str,
+    config: &SolverConfig,
+) -> Result<(), String> {
+    if let Some(rest) = line.strip_prefix("let ") {
+        let (name, expr) = rest
+            .split_once('=')
+            .ok_or("expected let NAME = RATE".to_string())?;
+        let name = name.trim();
+        if !is_name(name) {
+            return Err(format!("{:?} isn't a valid name", name));
+        }
+        let sr = eval_rate(bindings, expr)?;
+        println!("{} = {}", name, sr);
+        bindings.insert(name.to_string(), sr);
+        return Ok(());
+    }
+    let (cmd, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    match cmd {
+        ":help" => println!("{}", HELP),
+        ":norm" => {
+            let sr = eval_rate(bindings, rest)?;
+            println!("{}", canonicalize(&sr));
+        }
+        ":sub" => {
+            let (sr1, sr2) = eval_rate_pair(bindings, rest)?;
+            match stream_sub_report_with(&sr1, &sr2, config).result {
+                SubResult::Unknown(reason) => println!("unknown ({})", reason),
+                result => println!("{}", result.holds()),
+            }
+        }
+        ":explain" => {
+            let (sr1, sr2) = eval_rate_pair(bindings, rest)?;
+            // Print the judgment out again so the blame lines up with it.
+            let judgment = format!("{} <: {}", sr1, sr2);
+            match stream_sub_report_with(&sr1, &sr2, config).result {
+                SubResult::Holds => println!("{} is true", judgment),
+                SubResult::Refuted(cex) => {
+                    println!("{} is false", judgment);
+                    crate::print_counterexample(&cex);
+                    if let Some(blame) = stream_sub_blame_with(&sr1, &sr2, config) {
+                        crate::print_blame(&judgment, &blame);
+                    }
+                }
+                SubResult::Unknown(reason) => println!("{} is unknown ({})", judgment, reason),
+            }
+        }
+        ":smt" => {
+            let (sr1, sr2) = eval_rate_pair(bindings, rest)?;
+            let queries = stream_sub_smt(&sr1, &sr2);
+            if queries.is_empty() {
+                println!("; no queries, everything is decided without the solver");
+            }
+            for query in queries {
+                println!("{}", query);
+            }
+        }
+        _ => return Err(format!("unknown command {:?}, see :help", cmd)),
+    }
+    Ok(())
+}
+
+fn eval_rate(bindings: &HashMap<String, StreamRate>, expr: &str) -> Result<StreamRate, String> {
+    let expanded = expand(bindings, expr)?;
+    parse_side(&expanded, 0).map_err(|err| {
+        crate::print_parse_error(&expanded, &err);
+        "couldn't parse rate".to_string()
+    })
+}
+
+fn eval_rate_pair(
+    bindings: &HashMap<String, StreamRate>,
+    args: &str,
+) -> Result<(StreamRate, StreamRate), String> {
+    match split_args(args).as_slice() {
+        [a, b] | [a, "<:", b] => Ok((eval_rate(bindings, a)?, eval_rate(bindings, b)?)),
+        _ => Err("expected two rates".to_string()),
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// Replace every bound name in expr with its rate, printed back out. Tokens are
+// runs of anything that isn't whitespace or a paren, and only the ones that
+// start like a name count as names (so 10/5 is left alone).
+fn expand(bindings: &HashMap<String, StreamRate>, expr: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut token = String::new();
+    for c in expr.chars().chain(std::iter::once(' ')) {
+        if c.is_whitespace() || c == '(' || c == ')' {
+            if token.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+                match bindings.get(&token) {
+                    Some(sr) => expanded.push_str(&sr.to_string()),
+                    None => return Err(format!("{} isn't bound to anything", token)),
+                }
+            } else {
+                expanded.push_str(&token);
+            }
+            token.clear();
+            expanded.push(c);
+        } else {
+            token.push(c);
+        }
+    }
+    expanded.pop();
+    Ok(expanded.trim().to_string())
+}
+
+// Split on whitespace that isn't inside any parens.
+fn split_args(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = None;
+    for (i, c) in args.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        if c.is_whitespace() && depth == 0 {
+            if let Some(s) = start.take() {
+                parts.push(&args[s..i]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        parts.push(&args[s..]);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let mut bindings = HashMap::new();
+        bindings.insert("a".to_string(), parse_side("(|| 1/2 3/4)", 0).unwrap());
+        assert_eq!(
+            expand(&bindings, "(. a 10/5 (* a))"),
+            Ok("(. (|| 1/2 3/4) 10/5 (* (|| 1/2 3/4)))".to_string())
+        );
+        assert!(expand(&bindings, "(. b 10/5)").is_err());
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(
+            split_args("  (|| 1/2 3/4)   a <: (. b  c) "),
+            vec!["(|| 1/2 3/4)", "a", "<:", "(. b  c)"]
+        );
+    }
+}
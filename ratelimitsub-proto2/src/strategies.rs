@@ -0,0 +1,109 @@
+use crate::streamrate::{Rate, StreamRate, leaf_count};
+use proptest::prelude::*;
+use std::ops::Range;
+
+// Proptest strategies for well-formed rates, for the test modules to share.
+// Every node they build has operands (Sum, Par and Concat at least two, unless
+// asked for fewer), and every window is nonzero, so everything they generate is
+// something the parser could have given back.
+
+// How big a generated StreamRate gets: at most depth levels of operators
+// above the leaves, aiming for about size nodes in all, with at most width
+// operands to a Sum, Par or Concat. See prop_recursive for the details.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Shape {
+    pub(crate) depth: u32,
+    pub(crate) size: u32,
+    pub(crate) width: usize,
+}
+
+impl Default for Shape {
+    fn default() -> Shape {
+        Shape {
+            depth: 2,
+            size: 6,
+            width: 3,
+        }
+    }
+}
+
+pub(crate) fn arb_rate(
+    events: Range<usize>,
+    windows: impl Strategy<Value = usize>,
+) -> impl Strategy<Value = Rate> {
+    (events, windows).prop_map(|(n, t)| Rate::new(n, t))
+}
+
+// Rates made of the given leaves, in the given shape. Leaves come out as
+// single Raws too, so the shape is only an upper bound.
+pub(crate) fn arb_stream_rate(
+    leaf: impl Strategy<Value = Rate> + 'static,
+    shape: Shape,
+) -> impl Strategy<Value = StreamRate> {
+    assert!(shape.width >= 2, "a Sum, Par or Concat needs two operands");
+    arb_stream_rate_from(leaf, shape, 2)
+}
+
+// The same, but with Sums, Pars and Concats of as few as one operand, which
+// the parser reads, but which are the same type as their operand.
+pub(crate) fn arb_single_stream_rate(
+    leaf: impl Strategy<Value = Rate> + 'static,
+    shape: Shape,
+) -> impl Strategy<Value = StreamRate> {
+    arb_stream_rate_from(leaf, shape, 1)
+}
+
+fn arb_stream_rate_from(
+    leaf: impl Strategy<Value = Rate> + 'static,
+    shape: Shape,
+    fewest: usize,
+) -> impl Strategy<Value = StreamRate> {
+    let width = fewest..=shape.width;
+    leaf.prop_map(StreamRate::Raw).prop_recursive(
+        shape.depth,
+        shape.size,
+        shape.width as u32,
+        move |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), width.clone()).prop_map(StreamRate::Sum),
+                prop::collection::vec(inner.clone(), width.clone()).prop_map(StreamRate::Par),
+                prop::collection::vec(inner.clone(), width.clone()).prop_map(StreamRate::Concat),
+                inner.prop_map(|sr| StreamRate::Star(Box::new(sr))),
+            ]
+        },
+    )
+}
+
+// The same rate with every leaf at least as loose: some more events, and a
+// window no bigger (but still nonzero). Every operator is monotone in its
+// leaves, so anything sr allows, this allows too.
+pub(crate) fn loosen(sr: StreamRate) -> impl Strategy<Value = StreamRate> {
+    let leaves = leaf_count(&sr);
+    prop::collection::vec((0..3usize, 0..3usize), leaves).prop_map(move |slack| {
+        let mut slack = slack.into_iter();
+        loosen_leaves(&sr, &mut slack)
+    })
+}
+
+fn loosen_leaves(sr: &StreamRate, slack: &mut impl Iterator<Item = (usize, usize)>) -> StreamRate {
+    match sr {
+        StreamRate::Raw(r) => {
+            let (more, less) = slack.next().unwrap();
+            let window = r.window.to_integer();
+            StreamRate::Raw(Rate::new(
+                r.events + more,
+                window.saturating_sub(less).max(1),
+            ))
+        }
+        StreamRate::Sum(srs) => {
+            StreamRate::Sum(srs.iter().map(|sr| loosen_leaves(sr, slack)).collect())
+        }
+        StreamRate::Par(srs) => {
+            StreamRate::Par(srs.iter().map(|sr| loosen_leaves(sr, slack)).collect())
+        }
+        StreamRate::Concat(srs) => {
+            StreamRate::Concat(srs.iter().map(|sr| loosen_leaves(sr, slack)).collect())
+        }
+        StreamRate::Star(sr) => StreamRate::Star(Box::new(loosen_leaves(sr, slack))),
+    }
+}
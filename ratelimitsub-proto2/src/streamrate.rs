@@ -1,45 +1,341 @@
+use crate::trace::{TraceError, trace_satisfies_within};
+use num_rational::Ratio;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use z3::Context;
+use z3::Params;
+use z3::PrepareSynchronized;
 use z3::SatResult;
 use z3::Solver;
+use z3::Translate;
 use z3::ast::Bool;
 use z3::ast::Int;
 // use std::dbg;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// A window size (or a point in time), in time units. Windows can be any
+/// positive rational, e.g. 3/2 for a limit like 3 requests per 1.5 s.
+pub type Window = Ratio<usize>;
+
+/// The time units the parser knows about, and how many seconds each one is.
+/// Biggest first, which is the order Display tries them in.
+pub const TIME_UNITS: [(&str, Window); 7] = [
+    ("d", Ratio::new_raw(86400, 1)),
+    ("h", Ratio::new_raw(3600, 1)),
+    ("m", Ratio::new_raw(60, 1)),
+    ("s", Ratio::new_raw(1, 1)),
+    ("ms", Ratio::new_raw(1, 1000)),
+    ("us", Ratio::new_raw(1, 1000000)),
+    ("ns", Ratio::new_raw(1, 1000000000)),
+];
+
+/// A raw rate n/t: at most n events in any window of t time units.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Rate {
-    // NOTE: We may be able to allow real-valued windows here without any issue,
-    // but first get something working with integer-valued windows.
+    // NOTE: Events stay whole numbers. Something like 1.5 events per window
+    // doesn't mean much for a sliding window (you can't send half an event),
+    // and 3 per 2 isn't the same limit, so there's nothing sensible to turn
+    // it into.
     pub events: usize,
-    pub window: usize,
+    // With serde, whole windows are plain integers, and anything else is a
+    // [numerator, denominator] pair.
+    #[cfg_attr(feature = "serde", serde(with = "window_serde"))]
+    pub window: Window,
+    // Whether the window is in seconds (i.e. the rate was written with a time
+    // unit, like 10/5s or 100/1m), or in unitless ticks. Either way, stream_sub
+    // just compares the numbers, so it's up to whoever builds the rates not to
+    // mix the two; the parser refuses to.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "std::ops::Not::not")
+    )]
+    pub timed: bool,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+impl Rate {
+    /// A rate with a whole-number window in unitless ticks, which is the usual
+    /// case.
+    pub fn new(events: usize, window: usize) -> Rate {
+        Rate {
+            events,
+            window: Window::from_integer(window),
+            timed: false,
+        }
+    }
+
+    /// A rate with a window in seconds.
+    pub fn timed(events: usize, seconds: Window) -> Rate {
+        Rate {
+            events,
+            window: seconds,
+            timed: true,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod window_serde {
+    use super::Window;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Whole(usize),
+        Ratio(usize, usize),
+    }
+
+    pub fn serialize<S: Serializer>(window: &Window, serializer: S) -> Result<S::Ok, S::Error> {
+        if window.is_integer() {
+            Repr::Whole(window.to_integer())
+        } else {
+            Repr::Ratio(*window.numer(), *window.denom())
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Window, D::Error> {
+        match Repr::deserialize(deserializer)? {
+            Repr::Whole(0) | Repr::Ratio(0, _) => {
+                Err(serde::de::Error::custom("window must be nonzero"))
+            }
+            Repr::Whole(w) => Ok(Window::from_integer(w)),
+            Repr::Ratio(_, 0) => Err(serde::de::Error::custom(
+                "window denominator must be nonzero",
+            )),
+            Repr::Ratio(n, d) => Ok(Window::new(n, d)),
+        }
+    }
+}
+
+// Sum, Par and Concat need at least one operand, same as in the schema.
+#[cfg(feature = "serde")]
+fn nonempty<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<StreamRate>, D::Error> {
+    let srs: Vec<StreamRate> = serde::Deserialize::deserialize(deserializer)?;
+    if srs.is_empty() {
+        Err(serde::de::Error::custom(
+            "operators need at least one operand",
+        ))
+    } else {
+        Ok(srs)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 enum BARate {
     // NOTE: I guess I never use this at the moment. I thought we might have
     // needed to pass symbolic rates back up recursively somehow as a BARate,
     // but I guess I just store all the symbolic rates in a Vec that actually
     // gets passed back up.
     // Sym(SymRate),
-    Raw(Rate),
-    Par(Box<BARate>, Box<BARate>),
+    // The usize is the leaf's position in the StreamRate it came from, i.e.
+    // its index when the leaves are read left to right. Rewrites may copy a
+    // leaf, but the copies keep pointing back at the same source leaf.
+    Raw(Rate, usize),
+    Par(Vec<BARate>),
     // NOTE: We should always immediately collapse Concats on the Lhs of a
     // potential subtyping relation when both elements are Raw. This case is
     // really only for the scenario where one (or both!) sides of the Concat
     // are ParSum (since our current set of rules can't immediately reduce this
     // form of rate type until abstraction/SMT solving time).
-    LConcat(Box<BARate>, Box<BARate>),
-    Or(Box<BARate>, Box<BARate>),
-    And(Box<BARate>, Box<BARate>),
+    LConcat(Vec<BARate>),
+    // NOTE: Like LConcat, this only shows up on the Lhs. The Window is how
+    // long a repetition lasts at least, i.e. the min_length of the body it
+    // started out with, which rewrites of the body mustn't change.
+    LStar(Box<BARate>, Window),
+    // A Star on the Rhs, with the same Window as LStar. rate_symbolize can't
+    // do anything with it, so it's decided from its body (see star_sub_with).
+    // Under a Par, it becomes its body, since anything that satisfies the body
+    // (one repetition) also satisfies the Star.
+    RStar(Box<BARate>, Window),
+    // A Concat on the Rhs. Like RStar, it's decided from its phases (see
+    // concat_sub_with), and under a Par, it becomes the And of its phases,
+    // which is tighter.
+    RConcat(Vec<BARate>),
+    Or(Vec<BARate>),
+    And(Vec<BARate>),
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// A rate type for a whole stream, built up from raw rates. Sum, Par and Concat
+/// take any (non-zero) number of operands, and one operand is the same as just
+/// that operand on its own.
+// NOTE: The Ord is only there so canonicalize has something to sort by.
+// With serde, each node is tagged with its operator, e.g.
+// {"par": [{"raw": {"events": 10, "window": 5}}, ...]}. See
+// schema/stream-rate.schema.json.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum StreamRate {
     Raw(Rate),
-    Sum(Box<StreamRate>, Box<StreamRate>),
-    Par(Box<StreamRate>, Box<StreamRate>),
-    Concat(Box<StreamRate>, Box<StreamRate>),
+    /// A stream that satisfies every one of them (written (+ a b ...)).
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "nonempty"))]
+    Sum(Vec<StreamRate>),
+    /// All of them at once, interleaved (written (|| a b ...)).
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "nonempty"))]
+    Par(Vec<StreamRate>),
+    /// Each of them, one after the other (written (. a b ...)).
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "nonempty"))]
+    Concat(Vec<StreamRate>),
+    /// Zero or more repetitions, one after the other (written (* a)).
+    Star(Box<StreamRate>),
 }
 
-#[derive(Clone, Debug)]
+// Printing. StreamRates print in the same s-expression syntax that the parser
+// reads, so parse(x.to_string()) gives back x.
+
+// Windows print as decimals, e.g. 3/1.5, which is what the parser reads.
+// Windows without a finite decimal expansion (like 1/3) print as an exact
+// fraction in brackets, e.g. 1/[1/3], which the parser reads too. Timed
+// windows print in the biggest unit that makes them a whole number (so 60/60s
+// comes out as 60/1m), or in seconds if there isn't one.
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/", self.events)?;
+        if !self.timed {
+            return write_window(f, &self.window);
+        }
+        // NOTE: A tiny window in days (or a huge one in ns) can overflow, but
+        // then that's not the unit to write it in anyway.
+        let whole_unit = TIME_UNITS.iter().find_map(|(unit, seconds)| {
+            checked_mul(&self.window, &seconds.recip())
+                .filter(|w| w.is_integer())
+                .map(|w| (unit, w))
+        });
+        match whole_unit {
+            Some((unit, w)) => write!(f, "{}{}", w, unit),
+            None => {
+                write_window(f, &self.window)?;
+                write!(f, "s")
+            }
+        }
+    }
+}
+
+// a * b, or None if it overflows. Cancels across first, the same way Ratio's *
+// does, so it only fails if the result really doesn't fit.
+pub(crate) fn checked_mul(a: &Window, b: &Window) -> Option<Window> {
+    let x = Window::new(*a.numer(), *b.denom());
+    let y = Window::new(*b.numer(), *a.denom());
+    Some(Window::new(
+        x.numer().checked_mul(*y.numer())?,
+        x.denom().checked_mul(*y.denom())?,
+    ))
+}
+
+// a + b, or None if it overflows. Over the lcm of the denominators, the same
+// way Ratio's + does.
+pub(crate) fn checked_add(a: &Window, b: &Window) -> Option<Window> {
+    let denom = lcm(*a.denom(), *b.denom())?;
+    let numer = a
+        .numer()
+        .checked_mul(denom / a.denom())?
+        .checked_add(b.numer().checked_mul(denom / b.denom())?)?;
+    Some(Window::new(numer, denom))
+}
+
+// a - b, or None if it overflows (or b is bigger).
+pub(crate) fn checked_sub(a: &Window, b: &Window) -> Option<Window> {
+    let denom = lcm(*a.denom(), *b.denom())?;
+    let numer = a
+        .numer()
+        .checked_mul(denom / a.denom())?
+        .checked_sub(b.numer().checked_mul(denom / b.denom())?)?;
+    Some(Window::new(numer, denom))
+}
+
+fn write_window(f: &mut fmt::Formatter, w: &Window) -> fmt::Result {
+    match decimal(w) {
+        Some(decimal) => write!(f, "{}", decimal),
+        None => write!(f, "[{}]", w),
+    }
+}
+
+// w written out exactly as a decimal, if it can be.
+pub(crate) fn decimal(w: &Window) -> Option<String> {
+    match decimal_digits(w)? {
+        0 => Some(w.numer().to_string()),
+        digits => {
+            let unit = 10u128.pow(digits);
+            let scaled = *w.numer() as u128 * unit / *w.denom() as u128;
+            let frac = format!("{:0width$}", scaled % unit, width = digits as usize);
+            Some(format!("{}.{}", scaled / unit, frac.trim_end_matches('0')))
+        }
+    }
+}
+
+// How many digits after the decimal point it takes to write w exactly, if it
+// can be written that way at all (i.e. its denominator only has 2s and 5s).
+fn decimal_digits(w: &Window) -> Option<u32> {
+    let mut denom = *w.denom();
+    let (mut twos, mut fives) = (0, 0);
+    while denom.is_multiple_of(2) {
+        denom /= 2;
+        twos += 1;
+    }
+    while denom.is_multiple_of(5) {
+        denom /= 5;
+        fives += 1;
+    }
+    // NOTE: The parser reads a decimal as a whole number of 10^-digits, so if
+    // that doesn't fit in a usize (e.g. past 19 digits), w gets the fraction
+    // instead.
+    let digits = max(twos, fives) as u32;
+    let fits = 10usize
+        .checked_pow(digits)
+        .and_then(|unit| w.numer().checked_mul(unit / w.denom()))
+        .is_some();
+    if denom == 1 && fits {
+        Some(digits)
+    } else {
+        None
+    }
+}
+
+fn write_op<T: fmt::Display>(f: &mut fmt::Formatter, op: &str, operands: &[T]) -> fmt::Result {
+    write!(f, "({}", op)?;
+    for operand in operands {
+        write!(f, " {}", operand)?;
+    }
+    write!(f, ")")
+}
+
+impl fmt::Display for StreamRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamRate::Raw(r) => write!(f, "{}", r),
+            StreamRate::Sum(srs) => write_op(f, "+", srs),
+            StreamRate::Par(srs) => write_op(f, "||", srs),
+            StreamRate::Concat(srs) => write_op(f, ".", srs),
+            StreamRate::Star(sr) => write!(f, "(* {})", sr),
+        }
+    }
+}
+
+// BARates print like StreamRates, except that Sums have already been split
+// into Or/And, which print as (or ...) and (and ...). These are only for
+// debugging and don't parse back. Leaf positions aren't printed either.
+impl fmt::Display for BARate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BARate::Raw(r, _) => write!(f, "{}", r),
+            BARate::Par(bars) => write_op(f, "||", bars),
+            BARate::LConcat(bars) | BARate::RConcat(bars) => write_op(f, ".", bars),
+            BARate::LStar(bar, _) | BARate::RStar(bar, _) => write!(f, "(* {})", bar),
+            BARate::Or(bars) => write_op(f, "or", bars),
+            BARate::And(bars) => write_op(f, "and", bars),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 enum SubRel {
     Lhs,
     Rhs,
@@ -60,6 +356,145 @@ struct SymRate {
     seen_concrete_windows: Vec<usize>,
     seen_symbolic_windows: Vec<Int>,
     related_constraints: Vec<Bool>,
+    // The Raw leaves that this symbolic rate was built from, so we can read
+    // their values back out of a model when we need a counterexample.
+    leaves: Vec<SymLeaf>,
+}
+
+// A Raw leaf along with the symbolic variables that rate_symbolize created for
+// it. The symbolic variables are the leaf's rate "viewed" at whatever window
+// size the solver picked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SymLeaf {
+    rate: Rate,
+    pos: usize,
+    rel: SubRel,
+    events: Int,
+    window: Int,
+    // The constraints tying the symbolic variables to the leaf's rate. These
+    // are kept here (and not in related_constraints) so that they can be
+    // tracked per leaf when we go looking for an unsat core.
+    constraints: Vec<Bool>,
+}
+
+// One case split produced by rate_sub_symbolize. We keep the final comparison
+// between the two sides separate from everything else, since the constraints
+// minus the comparison are exactly what we need to go looking for a witness.
+// The per-leaf constraints live in the leaves.
+struct SubCase {
+    constraints: Vec<Bool>,
+    lhs_events: Int,
+    rhs_events: Int,
+    window: Int,
+    leaves: Vec<SymLeaf>,
+}
+
+impl SubCase {
+    // Assert everything for this case except the comparison, leaves included,
+    // i.e. everything it takes to check the case on its own.
+    fn assert_constraints(&self, solver: &Solver) {
+        for c in self.constraints.iter() {
+            solver.assert(c);
+        }
+        for leaf in self.leaves.iter() {
+            for c in leaf.constraints.iter() {
+                solver.assert(c);
+            }
+        }
+    }
+}
+
+// Everything rate_sub_symbolize produces for a pair of BARates: the cases, and
+// every leaf that shows up in any of them, once each.
+struct SubCases {
+    leaves: Vec<SymLeaf>,
+    cases: Vec<SubCase>,
+}
+
+// NOTE: Translating has to cover every Z3 AST in these, so that a translated
+// SubCases doesn't keep anything from the context it came from.
+unsafe impl Translate for SymLeaf {
+    fn translate(&self, dest: &Context) -> Self {
+        SymLeaf {
+            rate: self.rate.clone(),
+            pos: self.pos,
+            rel: self.rel.clone(),
+            events: self.events.translate(dest),
+            window: self.window.translate(dest),
+            constraints: self.constraints.translate(dest),
+        }
+    }
+}
+
+unsafe impl Translate for SubCase {
+    fn translate(&self, dest: &Context) -> Self {
+        SubCase {
+            constraints: self.constraints.translate(dest),
+            lhs_events: self.lhs_events.translate(dest),
+            rhs_events: self.rhs_events.translate(dest),
+            window: self.window.translate(dest),
+            leaves: self.leaves.translate(dest),
+        }
+    }
+}
+
+unsafe impl Translate for SubCases {
+    fn translate(&self, dest: &Context) -> Self {
+        SubCases {
+            leaves: self.leaves.translate(dest),
+            cases: self.cases.translate(dest),
+        }
+    }
+}
+
+impl SubCases {
+    // NOTE: A leaf's constraints only mention its own two variables, and
+    // they're satisfiable for any window, so asserting every leaf up front
+    // doesn't change whether any one case is satisfiable. That way the leaf
+    // constraints (which have all the nonlinear stuff) go in once per solver,
+    // and each case only pushes its own constraints on top.
+    fn assert_leaves(&self, solver: &Solver) {
+        for leaf in self.leaves.iter() {
+            for c in leaf.constraints.iter() {
+                solver.assert(c);
+            }
+        }
+    }
+}
+
+/// The leaves to blame for a failed subtyping check, given as positions of Raw
+/// leaves (counting from 0, left to right) in each side's StreamRate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Blame {
+    pub lhs: Vec<usize>,
+    pub rhs: Vec<usize>,
+}
+
+/// A concrete witness for a failed subtyping check. We pick a window size at
+/// which the left-hand side allows more events than the right-hand side does,
+/// and, where we can, an arrival trace that shows it. Windows and timestamps
+/// are in the same units as the rates (i.e. seconds, for timed rates).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Counterexample {
+    pub window: Window,
+    // Each leaf's rate as seen at the chosen window size, paired with the
+    // leaf's original rate.
+    pub lhs_leaves: Vec<LeafAssignment>,
+    pub rhs_leaves: Vec<LeafAssignment>,
+    pub lhs_events: usize,
+    pub rhs_events: usize,
+    // Event arrival timestamps, in non-decreasing order. Several events can
+    // arrive at the same timestamp. Only there if trace_satisfies agrees that
+    // the left-hand side allows them and the right-hand side doesn't, since
+    // the trace we build from the leaves doesn't always (e.g. it ignores when
+    // a Concat's later phases start).
+    pub events: Option<Vec<Window>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeafAssignment {
+    pub source: Rate,
+    pub assigned: Rate,
 }
 
 // Two helper functions to take max, min of two usizes
@@ -70,20 +505,36 @@ fn min(a: usize, b: usize) -> usize {
     if a < b { a } else { b }
 }
 
-fn rate_symbolize(rate: &BARate, rel: &SubRel) -> Vec<SymRate> {
+// The solver only ever sees whole-number windows: before anything gets
+// symbolized, both sides are scaled up by the lcm of all the windows'
+// denominators (see scale_ba). That keeps everything in Int, which is exact
+// and a good deal faster for Z3 than Real, and integer windows are just scaled
+// by 1.
+fn ticks(w: &Window) -> usize {
+    assert!(
+        w.is_integer(),
+        "windows should be scaled to integers before symbolizing"
+    );
+    w.to_integer()
+}
+
+// Which version of the encoding below (and of the closed forms that have to
+// agree with it) answers are from. Bump it whenever a change can flip an
+// answer, so a SubtypeCache saved before doesn't get read back.
+pub(crate) const ENCODING: u32 = 2;
+
+fn rate_symbolize(rate: &BARate, rel: &SubRel, memo: &SymMemo) -> Vec<SymRate> {
     match rate {
         // BARate::Sym(s) => vec![s.clone()],
-        BARate::Raw(r) => {
+        BARate::Raw(r, pos) => {
             // Initialize new constraints Vec.
             let mut constraints: Vec<Bool> = Vec::new();
             let sym_raw_n = Int::fresh_const("n");
             let sym_raw_t = Int::fresh_const("t");
             constraints.push(sym_raw_n.ge(0));
             constraints.push(sym_raw_t.gt(0));
-            let Rate {
-                events: usize_n,
-                window: usize_t,
-            } = r;
+            let usize_n = &r.events;
+            let usize_t = &ticks(&r.window);
             let n = Int::from_u64(*usize_n as u64);
             let t = Int::from_u64(*usize_t as u64);
             match rel {
@@ -91,27 +542,35 @@ fn rate_symbolize(rate: &BARate, rel: &SubRel) -> Vec<SymRate> {
                 // rules and automatically compile them to these SMT assertions.
                 // I'm pretty worried that my hand-compilation here is going to
                 // be subtly wrong.
+                // On the Lhs, a window bigger than the leaf's can fit a burst
+                // of n at every multiple of t inside it, i.e. n * ceil(T / t).
                 SubRel::Lhs => {
                     constraints.push((sym_raw_t.le(&t)).implies(sym_raw_n.eq(&n)));
                     constraints.push((sym_raw_t.gt(&t)).implies(((&sym_raw_t % &t).eq(0)).ite(
                         &sym_raw_n.eq(&n * (&sym_raw_t / &t)),
-                        &sym_raw_n.eq(&n * (&sym_raw_t / &t) + 1),
+                        &sym_raw_n.eq(&n * (&sym_raw_t / &t) + &n),
                     )));
                 }
+                // On the Rhs, a window smaller than the leaf's only gets what
+                // it can have while ceil(t / T) of them (which cover a whole
+                // window of t) still add up to at most n, i.e. that rounded
+                // down.
                 SubRel::Rhs => {
                     constraints.push((sym_raw_t.ge(&t)).implies(sym_raw_n.eq(&n)));
                     constraints.push((sym_raw_t.lt(&t)).implies(((&t % &sym_raw_t).eq(0)).ite(
-                        &(((&n % (&t / &sym_raw_t)).eq(0)).ite(
-                            &sym_raw_n.eq(&n / (&t / &sym_raw_t)),
-                            &sym_raw_n.eq((&n / (&t / &sym_raw_t)) + 1),
-                        )),
-                        &(((&n % ((&t / &sym_raw_t) + 1)).eq(0)).ite(
-                            &sym_raw_n.eq(&n / ((&t / &sym_raw_t) + 1)),
-                            &sym_raw_n.eq((&n / ((&t / &sym_raw_t) + 1)) + 1),
-                        )),
+                        &sym_raw_n.eq(&n / (&t / &sym_raw_t)),
+                        &sym_raw_n.eq(&n / ((&t / &sym_raw_t) + 1)),
                     )))
                 }
             };
+            let leaf = SymLeaf {
+                rate: r.clone(),
+                pos: *pos,
+                rel: rel.clone(),
+                events: sym_raw_n.clone(),
+                window: sym_raw_t.clone(),
+                constraints,
+            };
             vec![SymRate {
                 events: sym_raw_n,
                 window: sym_raw_t,
@@ -119,210 +578,244 @@ fn rate_symbolize(rate: &BARate, rel: &SubRel) -> Vec<SymRate> {
                 min_window: *usize_t,
                 seen_concrete_windows: vec![*usize_t],
                 seen_symbolic_windows: Vec::new(),
-                related_constraints: constraints,
+                related_constraints: Vec::new(),
+                leaves: vec![leaf],
             }]
         }
-        BARate::Par(left, right) => {
-            let left_sym = rate_symbolize(left, rel);
-            let right_sym = rate_symbolize(right, rel);
+        BARate::Par(children) => {
+            let child_syms: Vec<Vec<SymRate>> =
+                children.iter().map(|c| memo.symbolize(c, rel)).collect();
             let mut return_sym: Vec<SymRate> = Vec::new();
-            for lsym in left_sym.iter() {
-                for rsym in right_sym.iter() {
-
-                    let SymRate {
-                        events: l_sym_n,
-                        window: l_sym_t,
-                        max_window: l_max_window,
-                        min_window: l_min_window,
-                        seen_concrete_windows: l_seen_concrete_windows,
-                        seen_symbolic_windows: l_seen_symbolic_windows,
-                        related_constraints: l_related_constraints,
-                    } = lsym;
-                    let SymRate {
-                        events: r_sym_n,
-                        window: r_sym_t,
-                        max_window: r_max_window,
-                        min_window: r_min_window,
-                        seen_concrete_windows: r_seen_concrete_windows,
-                        seen_symbolic_windows: r_seen_symbolic_windows,
-                        related_constraints: r_related_constraints,
-                    } = rsym;
-                    // Combine related constraints, consuming those of the sides.
-                    let mut combined_constraints = Vec::new();
-                    combined_constraints.extend_from_slice(&l_related_constraints[..]);
-                    combined_constraints.extend_from_slice(&r_related_constraints[..]);
-                    let sym_par_n = Int::fresh_const("n");
-                    let sym_par_t = Int::fresh_const("t");
-                    combined_constraints.push(sym_par_n.ge(0));
-                    combined_constraints.push(sym_par_t.gt(0));
-                    // If symbolic windows on left and right hand sides are
-                    // equal, then we can immediately just sum events.
-                    // NOTE: They must be equal! This simplifies this below.
-                    combined_constraints.push(l_sym_t.eq(r_sym_t));
-                    combined_constraints.push(sym_par_t.eq(r_sym_t));
-                    combined_constraints.push(sym_par_n.eq(l_sym_n + r_sym_n));
-                    // More imperative stuff that I don't like, but I don't know
-                    // how to use RCs yet so it's OK.
-                    let mut all_seen_concrete_windows = Vec::new();
-                    all_seen_concrete_windows.extend_from_slice(&l_seen_concrete_windows[..]);
-                    all_seen_concrete_windows.extend_from_slice(&r_seen_concrete_windows[..]);
-                    let mut all_seen_symbolic_windows = Vec::new();
-                    all_seen_symbolic_windows.extend_from_slice(&l_seen_symbolic_windows[..]);
-                    all_seen_symbolic_windows.extend_from_slice(&r_seen_symbolic_windows[..]);
-                    let par_rate_sym = SymRate {
-                        events: sym_par_n,
-                        window: sym_par_t,
-                        max_window: max(*l_max_window, *r_max_window),
-                        min_window: min(*l_min_window, *r_min_window),
-                        seen_concrete_windows: all_seen_concrete_windows,
-                        seen_symbolic_windows: all_seen_symbolic_windows,
-                        related_constraints: combined_constraints,
-                    };
-                    return_sym.push(par_rate_sym);
+            for parts in sym_product(&child_syms) {
+                let mut par_rate_sym = sym_combine(&parts);
+                // NOTE: The windows of all the parts must be equal! Then we
+                // can just sum events.
+                for part in parts.iter() {
+                    par_rate_sym
+                        .related_constraints
+                        .push(par_rate_sym.window.eq(&part.window));
                 }
+                let all_events: Vec<Int> = parts.iter().map(|p| p.events.clone()).collect();
+                par_rate_sym
+                    .related_constraints
+                    .push(par_rate_sym.events.eq(Int::add(&all_events)));
+                return_sym.push(par_rate_sym);
             }
             return_sym
         }
-        BARate::LConcat(left, right) => {
+        BARate::LConcat(children) => {
             // NOTE: rel here should only be SubRel::Lhs. Again, it would be
             // nice to prove this in the code itself, but I'll just write this
             // note here now to call this out.
-            let left_sym = rate_symbolize(left, rel);
-            let right_sym = rate_symbolize(right, rel);
-            // OK, I've succumbed here to writing very imperative code. There
-            // might be a way to recover a more functional style, although it
-            // might not be super nice in Rust.
-            // NOTE: In this case, we actually need to return 3 possible
-            // symbolic rates (all of which should end up satisfying the
-            // subtyping relation), so we start by constructing a Vec of
-            // symbolic rates.
+            let child_syms: Vec<Vec<SymRate>> =
+                children.iter().map(|c| memo.symbolize(c, rel)).collect();
+            // NOTE: A window can only ever overlap a contiguous run of the
+            // phases, so we return one symbolic rate per run (times the cases
+            // of each phase in it), all of which should end up satisfying the
+            // subtyping relation. For two phases, this is the left rate, the
+            // crossover rate and the right rate.
             let mut return_sym: Vec<SymRate> = Vec::new();
-            for lsym in left_sym.iter() {
-                for rsym in right_sym.iter() {
-                    let SymRate {
-                        events: l_sym_n,
-                        window: l_sym_t,
-                        max_window: l_max_window,
-                        min_window: l_min_window,
-                        seen_concrete_windows: l_seen_concrete_windows,
-                        seen_symbolic_windows: l_seen_symbolic_windows,
-                        related_constraints: l_related_constraints,
-                    } = lsym;
-                    let SymRate {
-                        events: r_sym_n,
-                        window: r_sym_t,
-                        max_window: r_max_window,
-                        min_window: r_min_window,
-                        seen_concrete_windows: r_seen_concrete_windows,
-                        seen_symbolic_windows: r_seen_symbolic_windows,
-                        related_constraints: r_related_constraints,
-                    } = rsym;
-                    // Combine related constraints, consuming left and right.
-                    // NOTE: We have 3 different cases here --- we can either
-                    // choose the left symbolic rate, the crossover symbolic
-                    // rate, or the right symbolic rate.
-                    // CASE 1: We take the left symbolic rate as representative.
-                    // TODO: Perhaps there is a way to make Vecs like
-                    // l_related_constraints borrowed and mutable (with a
-                    // lifetime specifier), which would make this more efficient.
-                    let mut takeleft_constraints = Vec::new();
-                    takeleft_constraints.extend_from_slice(&l_related_constraints[..]);
-                    let left_sym_n = Int::fresh_const("n");
-                    let left_sym_t = Int::fresh_const("t");
-                    takeleft_constraints.push(left_sym_n.ge(0));
-                    takeleft_constraints.push(left_sym_t.gt(0));
-                    // New symbolic rate equal to left symbolic rate.
-                    takeleft_constraints.push(left_sym_n.eq(l_sym_n));
-                    takeleft_constraints.push(left_sym_t.eq(l_sym_t));
-                    let left_rate_sym = SymRate {
-                        events: left_sym_n,
-                        window: left_sym_t,
-                        max_window: *l_max_window,
-                        min_window: *l_min_window,
-                        seen_concrete_windows: l_seen_concrete_windows.clone(),
-                        seen_symbolic_windows: l_seen_symbolic_windows.clone(),
-                        related_constraints: takeleft_constraints,
-                    };
-                    return_sym.push(left_rate_sym);
-                    // CASE 2: We take the crossover rate as representative.
-                    let mut takecross_constraints = Vec::new();
-                    takecross_constraints.extend_from_slice(&l_related_constraints[..]);
-                    takecross_constraints.extend_from_slice(&r_related_constraints[..]);
-                    let cross_sym_n = Int::fresh_const("n");
-                    let cross_sym_t = Int::fresh_const("t");
-                    takecross_constraints.push(cross_sym_n.ge(0));
-                    takecross_constraints.push(cross_sym_t.gt(0));
-                    // Add constraints for crossover period
-                    takecross_constraints.push(cross_sym_n.eq(l_sym_n + r_sym_n));
-                    takecross_constraints.push(cross_sym_t.eq(l_sym_t + r_sym_t));
-                    // Combine seen windows from both sides
-                    let mut all_seen_concrete_windows = Vec::new();
-                    all_seen_concrete_windows.extend_from_slice(&l_seen_concrete_windows[..]);
-                    all_seen_concrete_windows.extend_from_slice(&r_seen_concrete_windows[..]);
-                    let mut all_seen_symbolic_windows = Vec::new();
-                    all_seen_symbolic_windows.extend_from_slice(&l_seen_symbolic_windows[..]);
-                    all_seen_symbolic_windows.extend_from_slice(&r_seen_symbolic_windows[..]);
-                    all_seen_symbolic_windows.push(cross_sym_t.clone());
-                    let cross_rate_sym = SymRate {
-                        events: cross_sym_n,
-                        window: cross_sym_t,
-                        max_window: max(*l_max_window, *r_max_window),
-                        min_window: min(*l_min_window, *r_min_window),
-                        seen_concrete_windows: all_seen_concrete_windows,
-                        // Add the symbolic window generated by the crossover
-                        // region to the set of seen symbolic windows for use
-                        // in the overall subtyping constraint as a possible
-                        // common window size. It's actually unclear to me if
-                        // this is necessary, or if we can get away with just
-                        // tracking concrete window sizes, but I'll probably
-                        // need to prove this. I do suspect right now that it
-                        // is necessary, so I'm including it here.
-                        seen_symbolic_windows: all_seen_symbolic_windows,
-                        related_constraints: takecross_constraints,
-                    };
-                    return_sym.push(cross_rate_sym);
-                    // CASE 3: We take the right symbolic rate as representative.
-                    let mut takeright_constraints = Vec::new();
-                    takeright_constraints.extend_from_slice(&r_related_constraints[..]);
-                    let right_sym_n = Int::fresh_const("n");
-                    let right_sym_t = Int::fresh_const("t");
-                    takeright_constraints.push(right_sym_n.ge(0));
-                    takeright_constraints.push(right_sym_t.gt(0));
-                    // New symbolic rate equal to right symbolic rate.
-                    takeright_constraints.push(right_sym_n.eq(r_sym_n));
-                    takeright_constraints.push(right_sym_t.eq(r_sym_t));
-                    let right_rate_sym = SymRate {
-                        events: right_sym_n,
-                        window: right_sym_t,
-                        max_window: *r_max_window,
-                        min_window: *r_min_window,
-                        seen_concrete_windows: r_seen_concrete_windows.clone(),
-                        seen_symbolic_windows: r_seen_symbolic_windows.clone(),
-                        related_constraints: takeright_constraints,
-                    };
-                    return_sym.push(right_rate_sym);
+            for first in 0..child_syms.len() {
+                for last in first..child_syms.len() {
+                    for parts in sym_product(&child_syms[first..=last]) {
+                        let mut run_rate_sym = sym_combine(&parts);
+                        if let [part] = parts[..] {
+                            // Just the one phase: new symbolic rate equal to
+                            // its symbolic rate.
+                            run_rate_sym
+                                .related_constraints
+                                .push(run_rate_sym.events.eq(&part.events));
+                            run_rate_sym
+                                .related_constraints
+                                .push(run_rate_sym.window.eq(&part.window));
+                        } else {
+                            // A crossover period: the window meets each
+                            // phase in the run in a stretch no longer than
+                            // itself, so each phase gets no more than it
+                            // could in the whole window, and events add up
+                            // over the run, as for a Par.
+                            for part in parts.iter() {
+                                run_rate_sym
+                                    .related_constraints
+                                    .push(run_rate_sym.window.eq(&part.window));
+                            }
+                            let all_events: Vec<Int> =
+                                parts.iter().map(|p| p.events.clone()).collect();
+                            run_rate_sym
+                                .related_constraints
+                                .push(run_rate_sym.events.eq(Int::add(&all_events)));
+                            // Any window is as good as another to compare
+                            // at, so let this one be any size, not just one
+                            // of the leaves'.
+                            let cross_window = run_rate_sym.window.clone();
+                            run_rate_sym.seen_symbolic_windows.push(cross_window);
+                        }
+                        return_sym.push(run_rate_sym);
+                    }
                 }
             }
             return_sym
         }
+        BARate::LStar(inner, min_length) => {
+            // NOTE: rel here should only be SubRel::Lhs, same as LConcat.
+            // A repetition only ends once it has lasted min_length and had
+            // its last event, and the next one starts right then (see
+            // trace_satisfies). So repetitions start at least min_length
+            // apart, and each one's events fall between its own start and the
+            // next one's. A window of size t can have at most
+            // ceil(t / min_length) starts inside it, so it touches at most
+            // ceil(t / min_length) + 1 repetitions: those, plus the one that
+            // was already going when it opened. Each of those contributes at
+            // most what the body allows in a window of size t, which is just
+            // the body's symbolic rate at window t. This gives us a single
+            // case, instead of having to split on how many repetitions the
+            // window spans (which is unbounded).
+            // NOTE: That's the min_length of the whole body, which can be less
+            // than any window in this case's leaves, e.g. once an Or in the
+            // body has been split up (see reduce_ba).
+            let inner_sym = memo.symbolize(inner, rel);
+            let mut return_sym: Vec<SymRate> = Vec::new();
+            for isym in inner_sym.iter() {
+                let SymRate {
+                    events: i_sym_n,
+                    window: i_sym_t,
+                    max_window: i_max_window,
+                    min_window: i_min_window,
+                    seen_concrete_windows: i_seen_concrete_windows,
+                    seen_symbolic_windows: i_seen_symbolic_windows,
+                    related_constraints: i_related_constraints,
+                    leaves: i_leaves,
+                } = isym;
+                let mut star_constraints = Vec::new();
+                star_constraints.extend_from_slice(&i_related_constraints[..]);
+                let star_sym_n = Int::fresh_const("n");
+                let star_sym_t = Int::fresh_const("t");
+                star_constraints.push(star_sym_n.ge(0));
+                star_constraints.push(star_sym_t.gt(0));
+                star_constraints.push(star_sym_t.eq(i_sym_t));
+                let min_t = Int::from_u64(ticks(min_length) as u64);
+                let touched = ((&star_sym_t + &min_t - 1) / &min_t) + 1;
+                star_constraints.push(star_sym_n.eq(i_sym_n * touched));
+                let star_rate_sym = SymRate {
+                    events: star_sym_n,
+                    window: star_sym_t,
+                    max_window: *i_max_window,
+                    min_window: *i_min_window,
+                    seen_concrete_windows: i_seen_concrete_windows.clone(),
+                    seen_symbolic_windows: i_seen_symbolic_windows.clone(),
+                    related_constraints: star_constraints,
+                    leaves: i_leaves.clone(),
+                };
+                return_sym.push(star_rate_sym);
+            }
+            return_sym
+        }
         _ => {
             // This is where I wish we could prove this statement in the code
             // instead of just throwing an exception. Maybe if I designed the
             // types a bit better, it would help...but I think this is where
             // dependent types would be very nice.
-            panic!("Unexpected type constructor: And, Or should not appear here.")
+            panic!("Unexpected type constructor: And, Or, RStar, RConcat should not appear here.")
+        }
+    }
+}
+
+// Every way of picking one symbolic rate (i.e. one case) for each child.
+fn sym_product(child_syms: &[Vec<SymRate>]) -> Vec<Vec<&SymRate>> {
+    let mut combos: Vec<Vec<&SymRate>> = vec![Vec::new()];
+    for syms in child_syms.iter() {
+        combos = combos
+            .iter()
+            .flat_map(|combo| {
+                syms.iter().map(move |sym| {
+                    let mut combo = combo.clone();
+                    combo.push(sym);
+                    combo
+                })
+            })
+            .collect();
+    }
+    combos
+}
+
+// A fresh symbolic rate for a node made out of parts, carrying along the
+// metadata and constraints of all the parts. The caller adds the constraints
+// that tie the new symbolic rate to the parts.
+fn sym_combine(parts: &[&SymRate]) -> SymRate {
+    let events = Int::fresh_const("n");
+    let window = Int::fresh_const("t");
+    let mut combined = SymRate {
+        events: events.clone(),
+        window: window.clone(),
+        max_window: parts.iter().fold(0, |acc, p| max(acc, p.max_window)),
+        min_window: parts
+            .iter()
+            .fold(usize::MAX, |acc, p| min(acc, p.min_window)),
+        seen_concrete_windows: Vec::new(),
+        seen_symbolic_windows: Vec::new(),
+        related_constraints: Vec::new(),
+        leaves: Vec::new(),
+    };
+    for part in parts.iter() {
+        combined
+            .seen_concrete_windows
+            .extend_from_slice(&part.seen_concrete_windows[..]);
+        combined
+            .seen_symbolic_windows
+            .extend_from_slice(&part.seen_symbolic_windows[..]);
+        combined
+            .related_constraints
+            .extend_from_slice(&part.related_constraints[..]);
+        combined.leaves.extend_from_slice(&part.leaves[..]);
+    }
+    combined.related_constraints.push(events.ge(0));
+    combined.related_constraints.push(window.gt(0));
+    combined
+}
+
+// rate_symbolize results for every subtree, so that one that gets compared
+// against several others (e.g. the Lhs against every operand of an And on the
+// Rhs), or that shows up more than once (e.g. from distributing), only gets
+// symbolized once. Sharing variables between copies of a subtree is fine:
+// everything in one case is at the case's window, and a leaf's events are
+// fixed by its window, so the copies would all agree anyway. NOTE: Since Z3
+// ASTs belong to the thread's context, a memo can't leave the thread it was
+// made on.
+#[derive(Default)]
+struct SymMemo(RefCell<HashMap<(BARate, SubRel), Vec<SymRate>>>);
+
+impl SymMemo {
+    fn symbolize(&self, rate: &BARate, rel: &SubRel) -> Vec<SymRate> {
+        let key = (rate.clone(), rel.clone());
+        if let Some(syms) = self.0.borrow().get(&key) {
+            return syms.clone();
         }
+        let syms = rate_symbolize(rate, rel, self);
+        self.0.borrow_mut().insert(key, syms.clone());
+        syms
     }
 }
 
-fn rate_sub_symbolize(rate1: &BARate, rate2: &BARate) -> Vec<Vec<Bool>> {
+fn rate_sub_symbolize(rate1: &BARate, rate2: &BARate, memo: &SymMemo) -> SubCases {
     // TODO: We probably just want to call rate_symbolize here on each side
     // and then do the stuff that involves the actual subtyping comparison
     // between both sides, i.e. coalescing all the seen windows, min and max
     // windows, and then adding the global constraints involving everything
     // in the subtyping relation to the solver.
-    let left_rate_sym = rate_symbolize(rate1, &SubRel::Lhs);
-    let right_rate_sym = rate_symbolize(rate2, &SubRel::Rhs);
+    let left_rate_sym = memo.symbolize(rate1, &SubRel::Lhs);
+    let right_rate_sym = memo.symbolize(rate2, &SubRel::Rhs);
+    // Most leaves show up in lots of cases, but it's the same variables
+    // every time.
+    let mut leaves: Vec<SymLeaf> = Vec::new();
+    for leaf in left_rate_sym
+        .iter()
+        .chain(right_rate_sym.iter())
+        .flat_map(|sym| sym.leaves.iter())
+    {
+        if !leaves.iter().any(|l| l.events == leaf.events) {
+            leaves.push(leaf.clone());
+        }
+    }
     let mut return_constraints = Vec::new();
     // Coalesce and add final constraints for each case
     for lsym in left_rate_sym.iter() {
@@ -335,6 +828,7 @@ fn rate_sub_symbolize(rate1: &BARate, rate2: &BARate) -> Vec<Vec<Bool>> {
                 seen_concrete_windows: l_seen_concrete_windows,
                 seen_symbolic_windows: l_seen_symbolic_windows,
                 related_constraints: l_related_constraints,
+                leaves: l_leaves,
             } = lsym;
             let SymRate {
                 events: r_sym_n,
@@ -344,6 +838,7 @@ fn rate_sub_symbolize(rate1: &BARate, rate2: &BARate) -> Vec<Vec<Bool>> {
                 seen_concrete_windows: r_seen_concrete_windows,
                 seen_symbolic_windows: r_seen_symbolic_windows,
                 related_constraints: r_related_constraints,
+                leaves: r_leaves,
             } = rsym;
             let mut all_constraints = Vec::new();
             all_constraints.extend_from_slice(&l_related_constraints[..]);
@@ -359,7 +854,8 @@ fn rate_sub_symbolize(rate1: &BARate, rate2: &BARate) -> Vec<Vec<Bool>> {
             let _overall_min_window = min(*l_min_window, *r_min_window);
             // all_constraints.push(l_sym_t.le(Int::from_u64(overall_max_window as u64)));
             // all_constraints.push(l_sym_t.ge(Int::from_u64(overall_min_window as u64)));
-            all_constraints.push(l_sym_n.le(r_sym_n));
+            // NOTE: The actual comparison, i.e. l_sym_n <= r_sym_n, gets added
+            // by the caller; see SubCase.
             // Add constraints for possible window sizes --- must be one of
             // seen concrete windows or seen symbolic windows.
             let mut all_seen_concrete_windows = Vec::new();
@@ -392,592 +888,2391 @@ fn rate_sub_symbolize(rate1: &BARate, rate2: &BARate) -> Vec<Vec<Bool>> {
             all_window_constraints.append(&mut concrete_window_constraints);
             all_window_constraints.append(&mut symbolic_window_constraints);
             all_constraints.push(Bool::or(&all_window_constraints[..]));
-            return_constraints.push(all_constraints);
+            let mut all_leaves = Vec::new();
+            all_leaves.extend_from_slice(&l_leaves[..]);
+            all_leaves.extend_from_slice(&r_leaves[..]);
+            return_constraints.push(SubCase {
+                constraints: all_constraints,
+                lhs_events: l_sym_n.clone(),
+                rhs_events: r_sym_n.clone(),
+                window: l_sym_t.clone(),
+                leaves: all_leaves,
+            });
+        }
+    }
+    SubCases {
+        leaves,
+        cases: return_constraints,
+    }
+}
+
+// Everything a single stream_sub check carries around while it decides the
+// pairs of normalized subterms.
+struct CheckState<'a> {
+    config: &'a SolverConfig,
+    // How many cases we hand to the solver, for reporting.
+    smt_cases: Cell<usize>,
+    memo: SymMemo,
+}
+
+impl<'a> CheckState<'a> {
+    fn new(config: &'a SolverConfig) -> Self {
+        CheckState {
+            config,
+            smt_cases: Cell::new(0),
+            memo: SymMemo::default(),
+        }
+    }
+}
+
+// Construct SMT constraints and solve. Every case has to come back Sat for the
+// subtyping relation to hold. Otherwise we give a counterexample for a case
+// that's Unsat, or if there aren't any, Z3's reason for giving up on one.
+fn rate_sub_solve(rate1: &BARate, rate2: &BARate, state: &CheckState) -> SubResult {
+    let (config, smt_cases) = (state.config, &state.smt_cases);
+    let symbolized = rate_sub_symbolize(rate1, rate2, &state.memo);
+    let threads = config.threads.min(symbolized.cases.len()).max(1);
+    // NOTE: The workers take turns pulling the next case off a shared counter,
+    // and stop as soon as any case is refuted or comes back Unknown, since
+    // either way the pair doesn't hold. That's the same however many threads
+    // there are. It does mean an Unknown can stop us before we get to a case
+    // that would have been refuted, but going on would spend the limits again
+    // on every case that's left.
+    let next = AtomicUsize::new(0);
+    let solved = AtomicUsize::new(0);
+    let cancelled = Mutex::new(false);
+    let wake = Condvar::new();
+    let failures = Mutex::new(Vec::new());
+    let work = |symbolized: &SubCases| {
+        let solver = config.solver();
+        symbolized.assert_leaves(&solver);
+        while !*cancelled.lock().unwrap() {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let Some(case) = symbolized.cases.get(i) else {
+                break;
+            };
+            solved.fetch_add(1, Ordering::Relaxed);
+            let res = solve_case(&solver, case, config);
+            if res.holds() {
+                continue;
+            }
+            let mut stop = cancelled.lock().unwrap();
+            // An Unknown after we stopped is most likely just the interrupt
+            // (see watched), and whatever stopped us is already in failures.
+            if *stop && matches!(res, SubResult::Unknown(_)) {
+                continue;
+            }
+            *stop = true;
+            wake.notify_all();
+            failures.lock().unwrap().push((i, res));
         }
     };
-    return_constraints
+    // NOTE: Z3 can only be interrupted from another thread, so every spawned
+    // worker has a watcher that waits for it to finish or for the others to
+    // stop, and interrupts whatever check the worker is in the middle of in
+    // the latter case. The calling thread works on the cases too, but it just
+    // finishes its check: an interrupt can stick to a context after the check
+    // it was meant for, and the calling thread's context outlives this call.
+    let watched = |symbolized: &SubCases| {
+        let context = Context::thread_local();
+        let handle = context.handle();
+        let finished = AtomicBool::new(false);
+        thread::scope(|watch| {
+            watch.spawn(|| {
+                let mut stop = cancelled.lock().unwrap();
+                while !*stop && !finished.load(Ordering::Relaxed) {
+                    stop = wake.wait(stop).unwrap();
+                }
+                if *stop {
+                    handle.interrupt();
+                }
+            });
+            work(symbolized);
+            let _stop = cancelled.lock().unwrap();
+            finished.store(true, Ordering::Relaxed);
+            wake.notify_all();
+        });
+    };
+    if threads == 1 {
+        work(&symbolized);
+    } else {
+        // NOTE: Z3 ASTs belong to the context they were made in, and contexts
+        // are thread-local, so every other worker gets its own translation of
+        // the cases, by way of a context of their own that can move between
+        // threads. Case i is the same on every worker.
+        let shared = symbolized.synchronized();
+        thread::scope(|scope| {
+            for _ in 1..threads {
+                scope.spawn(|| watched(&shared.recover()));
+            }
+            work(&symbolized);
+        });
+    }
+    smt_cases.set(smt_cases.get() + solved.into_inner());
+    // Several workers can fail at once. Go through them in case order, which
+    // is at least stable when only one case fails.
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by_key(|(i, _)| *i);
+    all_hold(failures.into_iter().map(|(_, res)| res))
 }
 
-// Construct SMT constraints and solve.
-fn rate_sub_solve(rate1: &BARate, rate2: &BARate) -> bool {
-    let solver = Solver::new();
-    let constraints = rate_sub_symbolize(rate1, rate2);
-    // Rust is an imperative language lol
-    // I'll just do this sequentially. It is clearly parallelizable though.
-    for cs in constraints.iter() {
-        solver.reset();
-        for c in cs.iter() {
-            solver.assert(c);
-        };
-        // let asserts = solver.get_assertions();
-        // dbg!(asserts);
-        match solver.check() {
-            SatResult::Sat => {
-                // NOTE: Produce a model in this case for debugging.
-                // let model = solver.get_model().unwrap();
-                // println!("printing model!");
-                // dbg!(model);
-                // continue
-            }
-            // TODO: Would also probably be nice to produce some kind of unsat core
-            // for debugging purposes (i.e. for the user, which rates were the
-            // offending ones).
-            SatResult::Unsat | SatResult::Unknown => return false,
+// Check a single case, on a solver that already has the leaves asserted (see
+// SubCases::assert_leaves). Everything else for the case goes in a scope of its
+// own, so the solver is back where it started afterwards.
+fn solve_case(solver: &Solver, case: &SubCase, config: &SolverConfig) -> SubResult {
+    solver.push();
+    for c in case.constraints.iter() {
+        solver.assert(c);
+    }
+    solver.assert(case.lhs_events.le(&case.rhs_events));
+    // let asserts = solver.get_assertions();
+    // dbg!(asserts);
+    let res = match solver.check() {
+        SatResult::Sat => SubResult::Holds,
+        // NOTE: See rate_sub_blame for the unsat core, i.e. which rates
+        // were the offending ones.
+        SatResult::Unsat => {
+            // The witness flips the comparison, so it needs a scope of its
+            // own.
+            solver.pop(1);
+            return match case_witness(solver, case, config) {
+                Ok(cex) => SubResult::Refuted(cex),
+                Err(reason) => SubResult::Unknown(reason),
+            };
         }
+        SatResult::Unknown => SubResult::Unknown(config.unknown_reason(solver)),
+    };
+    solver.pop(1);
+    res
+}
+
+// Look for a witness for a failed case: keep every constraint for the case, but
+// flip the comparison, and read the window and leaf rates out of the model.
+// Like solve_case, this goes in a scope of its own on top of the leaves.
+fn case_witness(
+    solver: &Solver,
+    case: &SubCase,
+    config: &SolverConfig,
+) -> Result<Counterexample, String> {
+    solver.push();
+    for c in case.constraints.iter() {
+        solver.assert(c);
+    }
+    solver.assert(case.lhs_events.gt(&case.rhs_events));
+    // NOTE: This should only fail if Z3 gives up on us (e.g. it runs out of
+    // time on the flipped query), in which case we don't have anything
+    // concrete to give back, so the case is Unknown after all.
+    let model = match solver.check() {
+        SatResult::Sat => solver.get_model().ok_or_else(|| "no model".to_string()),
+        SatResult::Unsat => Err("no witness".to_string()),
+        SatResult::Unknown => Err(config.unknown_reason(solver)),
+    };
+    solver.pop(1);
+    let model = model.map_err(|reason| format!("{} for a refuted case", reason))?;
+    // NOTE: A value the model can't give us (or that doesn't fit in a usize)
+    // is as good as no model at all: making one up would give a leaf a window
+    // of 0, or a witness that isn't one.
+    let eval = |i: &Int| -> Result<usize, String> {
+        model
+            .eval(i, true)
+            .and_then(|v| v.as_u64())
+            .and_then(|v| usize::try_from(v).ok())
+            .ok_or_else(|| format!("no value for {} for a refuted case", i))
     };
-    // Return true if all possibilities are SAT
-    true
+    let mut lhs_leaves = Vec::new();
+    let mut rhs_leaves = Vec::new();
+    for leaf in case.leaves.iter() {
+        let assignment = LeafAssignment {
+            source: leaf.rate.clone(),
+            assigned: Rate::new(eval(&leaf.events)?, eval(&leaf.window)?),
+        };
+        match leaf.rel {
+            SubRel::Lhs => lhs_leaves.push(assignment),
+            SubRel::Rhs => rhs_leaves.push(assignment),
+        }
+    }
+    let events = lhs_burst_trace(&lhs_leaves);
+    Ok(Counterexample {
+        window: Window::from_integer(eval(&case.window)?),
+        lhs_leaves,
+        rhs_leaves,
+        lhs_events: eval(&case.lhs_events)?,
+        rhs_events: eval(&case.rhs_events)?,
+        events,
+    })
+}
+
+// Build an arrival trace where every left-hand leaf sends its assigned number
+// of events as early as its own rate lets it, i.e. in bursts of (at most)
+// source.events at the start of each of its source windows.
+// NOTE: For leaves under an LConcat, this ignores the offset at which the
+// later phase actually starts, so everything just starts at 0. None if it'd be
+// too long to check anyway (see checked_witness).
+fn lhs_burst_trace(lhs_leaves: &[LeafAssignment]) -> Option<Vec<Window>> {
+    let total = lhs_leaves.iter().try_fold(0usize, |total, leaf| {
+        total.checked_add(leaf.assigned.events)
+    })?;
+    if total > WITNESS_BUDGET {
+        return None;
+    }
+    let mut events = Vec::new();
+    for leaf in lhs_leaves.iter() {
+        let mut remaining = leaf.assigned.events;
+        let mut burst_start = Window::from_integer(0);
+        while remaining > 0 {
+            // A leaf with zero events per window can't send anything, no matter
+            // what the model says.
+            if leaf.source.events == 0 {
+                break;
+            }
+            let burst = min(remaining, leaf.source.events);
+            events.extend(std::iter::repeat_n(burst_start, burst));
+            remaining -= burst;
+            burst_start += leaf.source.window;
+        }
+    }
+    events.sort();
+    Some(events)
+}
+
+// Closed-form witness for the Raw-Raw case. If w2 <= w1, all e1 events can
+// arrive at once and land inside a single w2 window. Otherwise, bursts of e1
+// every w1 ticks put e1 * ceil(w2 / w1) events inside a w2 window.
+fn raw_witness(r1: &Rate, r2: &Rate) -> Counterexample {
+    let Rate {
+        events: e1,
+        window: w1,
+        ..
+    } = r1;
+    let Rate {
+        events: e2,
+        window: w2,
+        ..
+    } = r2;
+    let bursts = if w2 <= w1 { 1 } else { windows_touched(w2, w1) };
+    let lhs_events = e1.saturating_mul(bursts);
+    let events = (lhs_events <= WITNESS_BUDGET).then(|| {
+        let mut events = Vec::new();
+        for b in 0..bursts {
+            events.extend(std::iter::repeat_n(w1 * b, *e1));
+        }
+        events
+    });
+    Counterexample {
+        window: *w2,
+        lhs_leaves: vec![LeafAssignment {
+            source: r1.clone(),
+            assigned: Rate {
+                events: lhs_events,
+                window: *w2,
+                timed: r1.timed,
+            },
+        }],
+        rhs_leaves: vec![LeafAssignment {
+            source: r2.clone(),
+            assigned: r2.clone(),
+        }],
+        lhs_events,
+        rhs_events: *e2,
+        events,
+    }
+}
+
+// Raw-Raw subtyping can be decided in closed form, without the solver.
+// test_raw_sub_agrees checks that the solver would say the same.
+fn raw_sub(r1: &Rate, r2: &Rate) -> bool {
+    let Rate {
+        events: e1,
+        window: w1,
+        ..
+    } = r1;
+    let Rate {
+        events: e2,
+        window: w2,
+        ..
+    } = r2;
+    if w2 <= w1 {
+        e1 <= e2
+    } else {
+        let bound = e2 / windows_touched(w2, w1);
+        *e1 <= bound
+    }
+}
+
+// ceil(w2 / w1), i.e. how many back-to-back windows of size w1 it takes to
+// cover a window of size w2.
+fn windows_touched(w2: &Window, w1: &Window) -> usize {
+    (w2 / w1).ceil().to_integer()
 }
 
-fn rate_sub(rate1: &BARate, rate2: &BARate) -> bool {
+fn rate_sub_explain(rate1: &BARate, rate2: &BARate, state: &CheckState) -> SubResult {
     match (rate1, rate2) {
-        (
-            BARate::Raw(Rate {
-                events: e1,
-                window: w1,
-            }),
-            BARate::Raw(Rate {
-                events: e2,
-                window: w2,
-            }),
-        ) => {
-            if w2 <= w1 {
-                e1 <= e2
+        (BARate::Raw(r1, _), BARate::Raw(r2, _)) => {
+            if raw_sub(r1, r2) {
+                SubResult::Holds
             } else {
-                let bound = e2 / w2.div_ceil(*w1);
-                *e1 <= bound
+                SubResult::Refuted(raw_witness(r1, r2))
             }
         }
-        (r1, r2) => {
-            rate_sub_solve(r1, r2)
+        (r1, r2) => match (par_leaves(r1), par_leaves(r2)) {
+            (Some(lhs), Some(rhs)) => {
+                par_sub_decide(&lhs, &rhs).map_or(SubResult::Holds, SubResult::Refuted)
+            }
+            _ => rate_sub_solve(r1, r2, state),
         },
     }
 }
 
-fn ba_rate_sub(ba_rate1: &BARate, ba_rate2: &BARate) -> bool {
-    match (ba_rate1, ba_rate2) {
-        (r, BARate::Or(bar1, bar2)) => ba_rate_sub(r, bar1) || ba_rate_sub(r, bar2),
-        (BARate::Or(bar1, bar2), r) => ba_rate_sub(bar1, r) || ba_rate_sub(bar2, r),
-        (r, BARate::And(bar1, bar2)) => ba_rate_sub(r, bar1) && ba_rate_sub(r, bar2),
-        (BARate::And(bar1, bar2), r) => ba_rate_sub(bar1, r) && ba_rate_sub(bar2, r),
-        (r1, r2) => rate_sub(r1, r2),
+// The Raw leaves of bar, if it's just Raws under (possibly nested) Pars.
+fn par_leaves(bar: &BARate) -> Option<Vec<&Rate>> {
+    match bar {
+        BARate::Raw(r, _) => Some(vec![r]),
+        BARate::Par(bars) => {
+            let mut leaves = Vec::new();
+            for bar in bars.iter() {
+                leaves.extend(par_leaves(bar)?);
+            }
+            Some(leaves)
+        }
+        _ => None,
     }
 }
 
-fn convert_to_ba(sr: &StreamRate, rel: &SubRel) -> BARate {
-    match sr {
-        StreamRate::Raw(r) => BARate::Raw(r.clone()),
-        // TODO: I actually think this should be And for both...
-        StreamRate::Sum(box_sr1, box_sr2) => match rel {
-            SubRel::Lhs => BARate::Or(
-                Box::new(convert_to_ba(box_sr1, rel)),
-                Box::new(convert_to_ba(box_sr2, rel)),
-            ),
-            SubRel::Rhs => BARate::And(
-                Box::new(convert_to_ba(box_sr1, rel)),
-                Box::new(convert_to_ba(box_sr2, rel)),
-            ),
-        },
-        StreamRate::Par(box_sr1, box_sr2) => BARate::Par(
-            Box::new(convert_to_ba(box_sr1, rel)),
-            Box::new(convert_to_ba(box_sr2, rel)),
-        ),
-        StreamRate::Concat(box_sr1, box_sr2) => match rel {
-            SubRel::Lhs => BARate::LConcat(
-                Box::new(convert_to_ba(box_sr1, rel)),
-                Box::new(convert_to_ba(box_sr2, rel)),
-            ),
-            SubRel::Rhs => BARate::And(
-                Box::new(convert_to_ba(box_sr1, rel)),
-                Box::new(convert_to_ba(box_sr2, rel)),
-            ),
-        },
+// What a Raw leaf allows in a window of t ticks, exactly as rate_symbolize
+// encodes it (rounding and all). A Par of Raw leaves has every leaf at the
+// same window and just adds up their events, so this is all we need to decide
+// Par-of-Raw cases without the solver.
+// NOTE: If the encoding in rate_symbolize changes, this has to change along
+// with it. test_par_sub_decide checks that the two agree.
+fn leaf_events_at(r: &Rate, rel: &SubRel, t: usize) -> u128 {
+    let e = r.events as u128;
+    let w = ticks(&r.window) as u128;
+    let t = t as u128;
+    match rel {
+        SubRel::Lhs if t <= w => e,
+        SubRel::Lhs => e * t.div_ceil(w),
+        SubRel::Rhs if t >= w => e,
+        SubRel::Rhs => e / w.div_ceil(t),
     }
 }
 
-// NOTE: The second return value is a bool indicating whether the reduction step
-// actually changed the BARate. This is used in fixpoint computation, i.e. we
-// stop when the BARate rewrites stop changing.
-fn reduce_ba(bar: BARate) -> (BARate, bool) {
-    match bar {
-        // BARate::Sym(_) => (bar, false),
-        BARate::Raw(_) => (bar, false),
-        BARate::Par(bar1, bar2) => {
-            match (*bar1, *bar2) {
-                // (S1 OR S2) || S3 <=> (S1 || S3) OR (S2 || S3)
-                (BARate::Or(left, right), b) | (b, BARate::Or(left, right)) => (
-                    BARate::Or(
-                        Box::new(BARate::Par(left, Box::new(b.clone()))),
-                        Box::new(BARate::Par(right, Box::new(b.clone()))),
-                    ),
-                    true,
-                ),
-                // (S1 AND S2) || S3 <=> (S1 || S3) AND (S2 || S3)
-                (BARate::And(left, right), b) | (b, BARate::And(left, right)) => (
-                    BARate::And(
-                        Box::new(BARate::Par(left, Box::new(b.clone()))),
-                        Box::new(BARate::Par(right, Box::new(b.clone()))),
-                    ),
-                    true,
-                ),
-                (b1, b2) => {
-                    let (reduced_b1, has_change1) = reduce_ba(b1);
-                    let (reduced_b2, has_change2) = reduce_ba(b2);
-                    match (has_change1, has_change2) {
-                        (false, false) => (
-                            BARate::Par(Box::new(reduced_b1), Box::new(reduced_b2)),
-                            false,
-                        ),
-                        (_, _) => (
-                            BARate::Par(Box::new(reduced_b1), Box::new(reduced_b2)),
-                            true,
-                        ),
-                    }
+// Decides lhs <: rhs for two Pars of Raw leaves (either of which can also be
+// a single Raw), without the solver. The solver can only pick a window from the
+// leaves' windows here (there's no LConcat, so no symbolic windows), so we just
+// try them all: the relation holds if it holds at any one of them. On failure,
+// the counterexample is at the smallest window.
+fn par_sub_decide(lhs: &[&Rate], rhs: &[&Rate]) -> Option<Counterexample> {
+    let mut windows: Vec<usize> = lhs
+        .iter()
+        .chain(rhs.iter())
+        .map(|r| ticks(&r.window))
+        .collect();
+    windows.sort();
+    windows.dedup();
+    let side_events = |leaves: &[&Rate], rel: &SubRel, t: usize| -> u128 {
+        leaves.iter().map(|r| leaf_events_at(r, rel, t)).sum()
+    };
+    if windows
+        .iter()
+        .any(|t| side_events(lhs, &SubRel::Lhs, *t) <= side_events(rhs, &SubRel::Rhs, *t))
+    {
+        return None;
+    }
+    let t = windows[0];
+    let to_usize = |n: u128| usize::try_from(n).unwrap_or(usize::MAX);
+    let assign = |leaves: &[&Rate], rel: &SubRel| -> Vec<LeafAssignment> {
+        leaves
+            .iter()
+            .map(|r| LeafAssignment {
+                source: (*r).clone(),
+                assigned: Rate::new(to_usize(leaf_events_at(r, rel, t)), t),
+            })
+            .collect()
+    };
+    let lhs_leaves = assign(lhs, &SubRel::Lhs);
+    let events = lhs_burst_trace(&lhs_leaves);
+    Some(Counterexample {
+        window: Window::from_integer(t),
+        lhs_leaves,
+        rhs_leaves: assign(rhs, &SubRel::Rhs),
+        lhs_events: to_usize(side_events(lhs, &SubRel::Lhs, t)),
+        rhs_events: to_usize(side_events(rhs, &SubRel::Rhs, t)),
+        events,
+    })
+}
+
+fn rate_sub_blame(rate1: &BARate, rate2: &BARate, config: &SolverConfig) -> Option<Blame> {
+    match (rate1, rate2) {
+        (BARate::Raw(r1, pos1), BARate::Raw(r2, pos2)) => {
+            if raw_sub(r1, r2) {
+                None
+            } else {
+                // Nothing else is involved, so both leaves are to blame.
+                Some(Blame {
+                    lhs: vec![*pos1],
+                    rhs: vec![*pos2],
+                })
+            }
+        }
+        (r1, r2) => rate_sub_solve_blame(r1, r2, config),
+    }
+}
+
+// Like rate_sub_solve, but for the first case that fails, we track the
+// constraints of each source leaf with an assumption literal and ask Z3 for an
+// unsat core over those literals. The core then gets shrunk down to a minimal
+// one, since Z3 doesn't promise us that.
+fn rate_sub_solve_blame(rate1: &BARate, rate2: &BARate, config: &SolverConfig) -> Option<Blame> {
+    let solver = config.solver();
+    // NOTE: No sharing the leaves here, since the whole point is to track
+    // them separately for each case.
+    let cases = rate_sub_symbolize(rate1, rate2, &SymMemo::default()).cases;
+    for case in cases.iter() {
+        solver.reset();
+        for c in case.constraints.iter() {
+            solver.assert(c);
+        }
+        solver.assert(case.lhs_events.le(&case.rhs_events));
+        // One tracking literal per source leaf. Copies of the same leaf (from
+        // distributing over Or/And) share a literal.
+        let mut trackers: Vec<(SubRel, usize, Bool)> = Vec::new();
+        for leaf in case.leaves.iter() {
+            let existing = trackers
+                .iter()
+                .find(|(rel, pos, _)| *rel == leaf.rel && *pos == leaf.pos)
+                .map(|(_, _, p)| p.clone());
+            let tracker = match existing {
+                Some(p) => p,
+                None => {
+                    let p = Bool::fresh_const("leaf");
+                    trackers.push((leaf.rel.clone(), leaf.pos, p.clone()));
+                    p
                 }
+            };
+            for c in leaf.constraints.iter() {
+                solver.assert(tracker.implies(c));
             }
         }
-        BARate::LConcat(bar1, bar2) => {
-            match (*bar1, *bar2) {
-                // (S1 OR S2) . S3 <=> (S1 . S3) OR (S2 . S3)
-                (BARate::Or(left, right), b) | (b, BARate::Or(left, right)) => (
-                    BARate::Or(
-                        Box::new(BARate::Par(left, Box::new(b.clone()))),
-                        Box::new(BARate::Par(right, Box::new(b.clone()))),
-                    ),
-                    true,
-                ),
-                // (S1 AND S2) . S3 <=> (S1 . S3) AND (S2 . S3)
-                (BARate::And(left, right), b) | (b, BARate::And(left, right)) => (
-                    BARate::And(
-                        Box::new(BARate::Par(left, Box::new(b.clone()))),
-                        Box::new(BARate::Par(right, Box::new(b.clone()))),
-                    ),
-                    true,
-                ),
-                (b1, b2) => {
-                    let (reduced_b1, has_change1) = reduce_ba(b1);
-                    let (reduced_b2, has_change2) = reduce_ba(b2);
-                    match (has_change1, has_change2) {
-                        (false, false) => (
-                            BARate::LConcat(Box::new(reduced_b1), Box::new(reduced_b2)),
-                            false,
-                        ),
-                        (_, _) => (
-                            BARate::LConcat(Box::new(reduced_b1), Box::new(reduced_b2)),
-                            true,
-                        ),
+        let assumptions: Vec<Bool> = trackers.iter().map(|(_, _, p)| p.clone()).collect();
+        let core = match solver.check_assumptions(&assumptions[..]) {
+            SatResult::Sat => continue,
+            SatResult::Unsat => {
+                let mut core = solver.get_unsat_core();
+                // Deletion-based minimization: drop each literal in turn and
+                // keep it dropped if what's left is still unsat.
+                let mut i = 0;
+                while i < core.len() {
+                    let mut without = core.clone();
+                    without.remove(i);
+                    match solver.check_assumptions(&without[..]) {
+                        SatResult::Unsat => core = without,
+                        SatResult::Sat | SatResult::Unknown => i += 1,
                     }
                 }
+                core
             }
-        }
-        BARate::Or(bar1, bar2) => {
-            let (reduced_b1, has_change1) = reduce_ba(*bar1);
-            let (reduced_b2, has_change2) = reduce_ba(*bar2);
-            match (has_change1, has_change2) {
-                (false, false) => (
-                    BARate::Or(Box::new(reduced_b1), Box::new(reduced_b2)),
-                    false,
-                ),
-                (_, _) => (BARate::Or(Box::new(reduced_b1), Box::new(reduced_b2)), true),
+            // NOTE: If Z3 gives up, the case might well hold, so there's
+            // nothing to blame it for. Move on to the next one.
+            SatResult::Unknown => continue,
+        };
+        let mut blame = Blame {
+            lhs: Vec::new(),
+            rhs: Vec::new(),
+        };
+        for (rel, pos, p) in trackers.iter() {
+            if core.contains(p) {
+                match rel {
+                    SubRel::Lhs => blame.lhs.push(*pos),
+                    SubRel::Rhs => blame.rhs.push(*pos),
+                }
             }
         }
-        BARate::And(bar1, bar2) => {
-            let (reduced_b1, has_change1) = reduce_ba(*bar1);
-            let (reduced_b2, has_change2) = reduce_ba(*bar2);
-            match (has_change1, has_change2) {
-                (false, false) => (
-                    BARate::And(Box::new(reduced_b1), Box::new(reduced_b2)),
-                    false,
-                ),
-                (_, _) => (
-                    BARate::And(Box::new(reduced_b1), Box::new(reduced_b2)),
-                    true,
-                ),
+        blame.lhs.sort();
+        blame.rhs.sort();
+        return Some(blame);
+    }
+    None
+}
+
+// Decides ba_rate1 <: ba_rate2 with the given leaf-level check, from the
+// BARates convert_to_ba gives (scaled, but not reduced). An And on the Rhs
+// needs every operand to hold, and an Or on the Lhs just one, in that order,
+// so each Rhs operand can pick its own Lhs one. Then a Star, Concat or Par gets
+// to go operand by operand against the same operator on the other side, which
+// the normal forms can't do, since they lose track of which Lhs leaf goes with
+// which Rhs leaf: e.g. (|| 1/1 1/2) <: (|| 1/1 1/2) only gets compared window
+// by window there, and fails. Whatever's left is decided on its normal forms.
+fn ba_rate_sub_with<W>(
+    ba_rate1: &BARate,
+    ba_rate2: &BARate,
+    check: &dyn Fn(&BARate, &BARate) -> SubResult<W>,
+) -> SubResult<W> {
+    match (ba_rate1, ba_rate2) {
+        (r, BARate::And(bars)) => all_hold(bars.iter().map(|bar| ba_rate_sub_with(r, bar, check))),
+        (BARate::Or(bars), r) => any_holds(bars.iter().map(|bar| ba_rate_sub_with(bar, r, check))),
+        (r, BARate::RStar(body, min_length)) => star_sub_with(r, body, min_length, check),
+        (r, BARate::RConcat(bars)) => concat_sub_with(r, bars, check),
+        (BARate::Par(bars1), BARate::Par(bars2)) => {
+            // NOTE: The normal forms go first, since if it comes to that, a
+            // counterexample for a pair of operands isn't one for the Pars.
+            match normal_form_sub_with(ba_rate1, ba_rate2, check) {
+                SubResult::Holds => SubResult::Holds,
+                _ if pairs_up(bars1, bars2, check) => SubResult::Holds,
+                failed => failed,
             }
         }
+        (r1, r2) => normal_form_sub_with(r1, r2, check),
     }
 }
 
-// NOTE/TODO: Should prove termination, just for sanity. Should also prove
-// existence and structure of normal form.
-fn reduce_ba_fixpoint(bar: BARate) -> BARate {
-    let (reduced, has_change) = reduce_ba(bar);
-    if has_change {
-        reduce_ba_fixpoint(reduced)
-    } else {
+// Walks the Or/And structure of the normal forms of two BARates, deciding each
+// pair underneath with check. Stars and Concats on the Rhs keep their shape in
+// the normal forms, and go back to ba_rate_sub_with.
+fn normal_form_sub_with<W>(
+    ba_rate1: &BARate,
+    ba_rate2: &BARate,
+    check: &dyn Fn(&BARate, &BARate) -> SubResult<W>,
+) -> SubResult<W> {
+    let norm1 = reduce_ba_fixpoint(ba_rate1.clone());
+    let norm2 = reduce_ba_fixpoint(ba_rate2.clone());
+    normal_sub_with(&norm1, &norm2, check)
+}
+
+fn normal_sub_with<W>(
+    norm1: &BARate,
+    norm2: &BARate,
+    check: &dyn Fn(&BARate, &BARate) -> SubResult<W>,
+) -> SubResult<W> {
+    match (norm1, norm2) {
+        (r, BARate::And(bars)) => all_hold(bars.iter().map(|bar| normal_sub_with(r, bar, check))),
+        (BARate::Or(bars), r) => any_holds(bars.iter().map(|bar| normal_sub_with(bar, r, check))),
+        (r, BARate::Or(bars)) => any_holds(bars.iter().map(|bar| normal_sub_with(r, bar, check))),
+        (BARate::And(bars), r) => all_hold(bars.iter().map(|bar| normal_sub_with(bar, r, check))),
+        (_, BARate::RStar(..) | BARate::RConcat(_)) => ba_rate_sub_with(norm1, norm2, check),
+        (r1, r2) => check(r1, r2),
+    }
+}
+
+// r <: (* body) holds if r <: body, since one repetition is a Star too. Or, if
+// r is a Star as well, if its body is under the Rhs body and its repetitions
+// can't be any shorter: then the Rhs can split a stream into repetitions
+// wherever the Lhs did, and each repetition fits. Anything else fails the way
+// r <: body does, since the counts for the Star are the same as for its body.
+fn star_sub_with<W>(
+    r: &BARate,
+    body: &BARate,
+    min_length: &Window,
+    check: &dyn Fn(&BARate, &BARate) -> SubResult<W>,
+) -> SubResult<W> {
+    let whole = std::iter::once_with(|| ba_rate_sub_with(r, body, check));
+    any_holds(
+        whole.chain(
+            repetitions(r, min_length)
+                .into_iter()
+                .map(|r_body| ba_rate_sub_with(r_body, body, check)),
+        ),
+    )
+}
+
+// How long a stream of the rate lasts at least: its shortest leaf window.
+pub(crate) fn min_length(sr: &StreamRate) -> Window {
+    match sr {
+        StreamRate::Raw(r) => r.window,
+        StreamRate::Sum(srs) | StreamRate::Par(srs) | StreamRate::Concat(srs) => srs
+            .iter()
+            .map(min_length)
+            .min()
+            .expect("operators have at least one operand"),
+        StreamRate::Star(body) => min_length(body),
+    }
+}
+
+// The body of r, if r is a Star whose repetitions last at least min_length.
+fn repetitions<'a>(r: &'a BARate, min_length: &Window) -> Option<&'a BARate> {
+    match r {
+        BARate::LStar(r_body, r_min_length) if r_min_length >= min_length => Some(r_body),
+        _ => None,
+    }
+}
+
+// r <: (. bars) holds if r is under every phase. Or, if r is a Concat with as
+// many phases, if each is under the Rhs one, and none but the last can end any
+// sooner, the same as for Stars (see star_sub_with).
+fn concat_sub_with<W>(
+    r: &BARate,
+    bars: &[BARate],
+    check: &dyn Fn(&BARate, &BARate) -> SubResult<W>,
+) -> SubResult<W> {
+    let every =
+        std::iter::once_with(|| all_hold(bars.iter().map(|bar| ba_rate_sub_with(r, bar, check))));
+    any_holds(every.chain(phases(r, bars).into_iter().map(|phases| {
+        all_hold(
+            phases
+                .into_iter()
+                .map(|(r_bar, bar)| ba_rate_sub_with(r_bar, bar, check)),
+        )
+    })))
+}
+
+// r's phases paired up with bars, if r is a Concat whose phases line up with
+// them (see concat_sub_with).
+fn phases<'a>(r: &'a BARate, bars: &'a [BARate]) -> Option<Vec<(&'a BARate, &'a BARate)>> {
+    let BARate::LConcat(r_bars) = r else {
+        return None;
+    };
+    let last = bars.len().checked_sub(1)?;
+    let lines_up = r_bars.len() == bars.len()
+        && r_bars[..last]
+            .iter()
+            .zip(&bars[..last])
+            .all(|(r_bar, bar)| ba_min_length(r_bar) >= ba_min_length(bar));
+    lines_up.then(|| r_bars.iter().zip(bars).collect())
+}
+
+// How long a phase (or repetition) of bar lasts at least, like min_length.
+// NOTE: That's only the min_length of what bar came from before it's reduced,
+// since splitting an Or can leave only the longer windows. LStars and RStars
+// keep theirs.
+fn ba_min_length(bar: &BARate) -> Window {
+    match bar {
+        BARate::Raw(r, _) => r.window,
+        BARate::Par(bars)
+        | BARate::LConcat(bars)
+        | BARate::RConcat(bars)
+        | BARate::Or(bars)
+        | BARate::And(bars) => bars
+            .iter()
+            .map(ba_min_length)
+            .min()
+            .expect("operators have at least one operand"),
+        BARate::LStar(_, min_length) | BARate::RStar(_, min_length) => *min_length,
+    }
+}
+
+// Whether every one of bars1 can get a partner of its own in bars2 that it's
+// under, by augmenting paths. A Par is under another one if so, since the Rhs
+// can then share a stream out the same way the Lhs did.
+fn pairs_up<W>(
+    bars1: &[BARate],
+    bars2: &[BARate],
+    check: &dyn Fn(&BARate, &BARate) -> SubResult<W>,
+) -> bool {
+    if bars1.len() != bars2.len() {
+        return false;
+    }
+    let under: Vec<Vec<bool>> = bars1
+        .iter()
+        .map(|bar1| {
+            bars2
+                .iter()
+                .map(|bar2| ba_rate_sub_with(bar1, bar2, check).holds())
+                .collect()
+        })
+        .collect();
+    let mut partner: Vec<Option<usize>> = vec![None; bars2.len()];
+    fn augment(
+        i: usize,
+        under: &[Vec<bool>],
+        seen: &mut [bool],
+        partner: &mut [Option<usize>],
+    ) -> bool {
+        for j in 0..partner.len() {
+            if under[i][j] && !seen[j] {
+                seen[j] = true;
+                if partner[j].is_none_or(|k| augment(k, under, seen, partner)) {
+                    partner[j] = Some(i);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+    (0..bars1.len()).all(|i| augment(i, &under, &mut vec![false; bars2.len()], &mut partner))
+}
+
+// Holds if any result holds. Otherwise it's Unknown if any result is (since
+// that one might have held), and refuted by the first refutation if not. Stops
+// as soon as something holds.
+fn any_holds<W>(results: impl Iterator<Item = SubResult<W>>) -> SubResult<W> {
+    let mut failed = None;
+    for res in results {
+        match res {
+            SubResult::Holds => return SubResult::Holds,
+            SubResult::Unknown(reason) => {
+                if !matches!(failed, Some(SubResult::Unknown(_))) {
+                    failed = Some(SubResult::Unknown(reason));
+                }
+            }
+            SubResult::Refuted(witness) => {
+                failed.get_or_insert(SubResult::Refuted(witness));
+            }
+        }
+    }
+    failed.unwrap_or(SubResult::Holds)
+}
+
+// Refuted by the first refutation, if there is one. Otherwise it's Unknown if
+// any result is, and holds if not. Stops as soon as something is refuted.
+fn all_hold<W>(results: impl Iterator<Item = SubResult<W>>) -> SubResult<W> {
+    let mut unknown = None;
+    for res in results {
+        match res {
+            SubResult::Holds => {}
+            SubResult::Refuted(witness) => return SubResult::Refuted(witness),
+            SubResult::Unknown(reason) => {
+                unknown.get_or_insert(reason);
+            }
+        }
+    }
+    unknown.map_or(SubResult::Holds, SubResult::Unknown)
+}
+
+// The lcm of the denominators of all the windows in bar, i.e. the smallest
+// scale that makes every window a whole number. None if that overflows.
+fn window_scale(bar: &BARate) -> Option<usize> {
+    match bar {
+        BARate::Raw(r, _) => Some(*r.window.denom()),
+        BARate::Par(bars)
+        | BARate::LConcat(bars)
+        | BARate::RConcat(bars)
+        | BARate::Or(bars)
+        | BARate::And(bars) => bars
+            .iter()
+            .try_fold(1, |scale, bar| lcm(scale, window_scale(bar)?)),
+        BARate::LStar(bar, min_length) | BARate::RStar(bar, min_length) => {
+            lcm(window_scale(bar)?, *min_length.denom())
+        }
+    }
+}
+
+fn lcm(a: usize, b: usize) -> Option<usize> {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    (a / x).checked_mul(b)
+}
+
+// Multiplies every window in bar by scale, which has to be a multiple of every
+// window's denominator. Events stay the same, so this doesn't change which
+// streams bar allows, just the unit we measure time in. None if a window gets
+// too big.
+fn scale_ba(bar: &BARate, scale: usize) -> Option<BARate> {
+    let scale_all = |bars: &[BARate]| -> Option<Vec<BARate>> {
+        bars.iter().map(|bar| scale_ba(bar, scale)).collect()
+    };
+    let scale_window = |w: &Window| {
+        Some(Window::from_integer(
+            w.numer().checked_mul(scale / w.denom())?,
+        ))
+    };
+    Some(match bar {
+        BARate::Raw(r, pos) => BARate::Raw(
+            Rate {
+                window: scale_window(&r.window)?,
+                ..r.clone()
+            },
+            *pos,
+        ),
+        BARate::Par(bars) => BARate::Par(scale_all(bars)?),
+        BARate::LConcat(bars) => BARate::LConcat(scale_all(bars)?),
+        BARate::RConcat(bars) => BARate::RConcat(scale_all(bars)?),
+        BARate::LStar(bar, min_length) => {
+            BARate::LStar(Box::new(scale_ba(bar, scale)?), scale_window(min_length)?)
+        }
+        BARate::RStar(bar, min_length) => {
+            BARate::RStar(Box::new(scale_ba(bar, scale)?), scale_window(min_length)?)
+        }
+        BARate::Or(bars) => BARate::Or(scale_all(bars)?),
+        BARate::And(bars) => BARate::And(scale_all(bars)?),
+    })
+}
+
+// Undoes scale_ba on a counterexample, so that it's back in the caller's time
+// units.
+fn unscale_counterexample(cex: Counterexample, scale: usize) -> Counterexample {
+    let unscale_leaves = |leaves: Vec<LeafAssignment>| -> Vec<LeafAssignment> {
+        leaves
+            .into_iter()
+            .map(|leaf| LeafAssignment {
+                source: Rate {
+                    window: leaf.source.window / scale,
+                    ..leaf.source.clone()
+                },
+                // NOTE: The solver's assignments don't know about units, so
+                // they take the source's.
+                assigned: Rate {
+                    events: leaf.assigned.events,
+                    window: leaf.assigned.window / scale,
+                    timed: leaf.source.timed,
+                },
+            })
+            .collect()
+    };
+    Counterexample {
+        window: cex.window / scale,
+        lhs_leaves: unscale_leaves(cex.lhs_leaves),
+        rhs_leaves: unscale_leaves(cex.rhs_leaves),
+        lhs_events: cex.lhs_events,
+        rhs_events: cex.rhs_events,
+        events: cex
+            .events
+            .map(|events| events.into_iter().map(|e| e / scale).collect()),
+    }
+}
+
+// Both sides, converted and then scaled to whole-number windows, along with the
+// scale. They aren't reduced yet, since ba_rate_sub_with needs to see the
+// operators they came from. None if the windows are too far apart for that,
+// i.e. the scale or the longest window doesn't fit in a usize (e.g. 1/1d
+// against 1/0.000001ns).
+fn scaled_forms(sr1: &StreamRate, sr2: &StreamRate) -> Option<(BARate, BARate, usize)> {
+    let ba_lhs = convert_to_ba(sr1, &SubRel::Lhs);
+    let ba_rhs = convert_to_ba(sr2, &SubRel::Rhs);
+    let scale = lcm(window_scale(&ba_lhs)?, window_scale(&ba_rhs)?)?;
+    Some((scale_ba(&ba_lhs, scale)?, scale_ba(&ba_rhs, scale)?, scale))
+}
+
+const UNSCALABLE: &str = "the windows are too far apart to scale to whole numbers";
+
+// How many Raw leaves sr has, i.e. how many positions convert_to_ba hands out.
+pub(crate) fn leaf_count(sr: &StreamRate) -> usize {
+    match sr {
+        StreamRate::Raw(_) => 1,
+        StreamRate::Sum(srs) | StreamRate::Par(srs) | StreamRate::Concat(srs) => {
+            srs.iter().map(leaf_count).sum()
+        }
+        StreamRate::Star(sr) => leaf_count(sr),
+    }
+}
+
+fn convert_to_ba(sr: &StreamRate, rel: &SubRel) -> BARate {
+    let mut next_pos = 0;
+    convert_to_ba_rec(sr, rel, &mut next_pos)
+}
+
+// next_pos counts the Raw leaves we've seen so far, left to right.
+fn convert_to_ba_rec(sr: &StreamRate, rel: &SubRel, next_pos: &mut usize) -> BARate {
+    let mut convert_all = |srs: &[StreamRate]| -> Vec<BARate> {
+        srs.iter()
+            .map(|sr| convert_to_ba_rec(sr, rel, next_pos))
+            .collect()
+    };
+    match sr {
+        StreamRate::Raw(r) => {
+            let pos = *next_pos;
+            *next_pos += 1;
+            BARate::Raw(r.clone(), pos)
+        }
+        // TODO: I actually think this should be And for both...
+        StreamRate::Sum(srs) => match rel {
+            SubRel::Lhs => BARate::Or(convert_all(srs)),
+            SubRel::Rhs => BARate::And(convert_all(srs)),
+        },
+        StreamRate::Par(srs) => BARate::Par(convert_all(srs)),
+        StreamRate::Concat(srs) => match rel {
+            SubRel::Lhs => BARate::LConcat(convert_all(srs)),
+            SubRel::Rhs => BARate::RConcat(convert_all(srs)),
+        },
+        StreamRate::Star(box_sr) => {
+            let body = Box::new(convert_to_ba_rec(box_sr, rel, next_pos));
+            match rel {
+                SubRel::Lhs => BARate::LStar(body, min_length(box_sr)),
+                SubRel::Rhs => BARate::RStar(body, min_length(box_sr)),
+            }
+        }
+    }
+}
+
+// NOTE: The second return value is a bool indicating whether the reduction step
+// actually changed the BARate. This is used in fixpoint computation, i.e. we
+// stop when the BARate rewrites stop changing.
+fn reduce_ba(bar: BARate) -> (BARate, bool) {
+    match bar {
+        // BARate::Sym(_) => (bar, false),
+        BARate::Raw(..) => (bar, false),
+        // S1 || S2* => S1 || S2 and S1 || (S2 . S3) => S1 || (S2 AND S3) on
+        // the Rhs, where those are subtypes, since rate_symbolize can't do
+        // anything with an RStar or RConcat.
+        BARate::Par(bars)
+            if bars
+                .iter()
+                .any(|bar| matches!(bar, BARate::RStar(..) | BARate::RConcat(_))) =>
+        {
+            let bars = bars
+                .into_iter()
+                .map(|bar| match bar {
+                    BARate::RStar(body, _) => *body,
+                    BARate::RConcat(bars) => BARate::And(bars),
+                    bar => bar,
+                })
+                .collect();
+            (BARate::Par(bars), true)
+        }
+        // S1 || (S2 OR S3) || S4 <=> (S1 || S2 || S4) OR (S1 || S3 || S4)
+        // S1 || (S2 AND S3) || S4 <=> (S1 || S2 || S4) AND (S1 || S3 || S4)
+        BARate::Par(bars) => match distribute(bars, BARate::Par) {
+            Ok(distributed) => (distributed, true),
+            Err(bars) => reduce_nary(bars, BARate::Par, |bar| match bar {
+                BARate::Par(bars) => Ok(bars),
+                bar => Err(bar),
+            }),
+        },
+        // S1 . (S2 OR S3) . S4 <=> (S1 . S2 . S4) OR (S1 . S3 . S4), and the
+        // same for AND.
+        // TODO: This has always distributed into Pars rather than LConcats.
+        // Par is looser than LConcat on the Lhs, so it's sound, but we lose
+        // precision.
+        BARate::LConcat(bars) => match distribute(bars, BARate::Par) {
+            Ok(distributed) => (distributed, true),
+            Err(bars) => reduce_nary(bars, BARate::LConcat, |bar| match bar {
+                BARate::LConcat(bars) => Ok(bars),
+                bar => Err(bar),
+            }),
+        },
+        BARate::LStar(bar, min_length) => {
+            match *bar {
+                // (S1 OR S2)* => S1* OR S2*
+                // NOTE: An Or on the Lhs only needs one side to be a subtype,
+                // i.e. it behaves like an intersection, and repeating something
+                // in both S1 and S2 gives something in both S1* and S2*, with
+                // repetitions as short as the ones of (S1 OR S2)*, which is
+                // why the min_length stays the same.
+                BARate::Or(bars) => (
+                    BARate::Or(
+                        bars.into_iter()
+                            .map(|bar| BARate::LStar(Box::new(bar), min_length))
+                            .collect(),
+                    ),
+                    true,
+                ),
+                // (S1 AND S2)* => (S1 || S2)*
+                // NOTE: This loses precision, but it's sound: S1 || S2 allows
+                // at least as many events in any window as either side does.
+                // We shouldn't see And on the Lhs anyways.
+                BARate::And(bars) => (BARate::LStar(Box::new(BARate::Par(bars)), min_length), true),
+                // NOTE: S** stays as it is. It isn't S*: a repetition of the
+                // inner Star can start right where one of the outer Star ends,
+                // however soon the last one of the inner Star started, so
+                // e.g. (* (* 1/2)) allows 1.9, 2, 2 and (* 1/2) doesn't.
+                b => {
+                    let (reduced_b, has_change) = reduce_ba(b);
+                    (BARate::LStar(Box::new(reduced_b), min_length), has_change)
+                }
+            }
+        }
+        // NOTE: S** stays as it is on the Rhs too, so that it still lines up
+        // with one on the Lhs (see star_sub_with).
+        BARate::RStar(bar, min_length) => {
+            let (reduced_bar, has_change) = reduce_ba(*bar);
+            (BARate::RStar(Box::new(reduced_bar), min_length), has_change)
+        }
+        // NOTE: Unlike an LConcat, an RConcat inside another stays where it
+        // is, since its phases have to line up with the Lhs's (see
+        // concat_sub_with), and flattening changes when they can start.
+        BARate::RConcat(bars) => reduce_nary(bars, BARate::RConcat, Err),
+        BARate::Or(bars) => reduce_nary(bars, BARate::Or, |bar| match bar {
+            BARate::Or(bars) => Ok(bars),
+            bar => Err(bar),
+        }),
+        BARate::And(bars) => reduce_nary(bars, BARate::And, |bar| match bar {
+            BARate::And(bars) => Ok(bars),
+            bar => Err(bar),
+        }),
+    }
+}
+
+// Pushes the first Or/And among bars out over the rest, rebuilding each
+// alternative with op. Gives bars back untouched if there isn't one.
+fn distribute(mut bars: Vec<BARate>, op: fn(Vec<BARate>) -> BARate) -> Result<BARate, Vec<BARate>> {
+    let Some(i) = bars
+        .iter()
+        .position(|bar| matches!(bar, BARate::Or(_) | BARate::And(_)))
+    else {
+        return Err(bars);
+    };
+    let distribute_over = |alts: Vec<BARate>, rest: &Vec<BARate>| -> Vec<BARate> {
+        alts.into_iter()
+            .map(|alt| {
+                let mut with_alt = rest.clone();
+                with_alt.insert(i, alt);
+                op(with_alt)
+            })
+            .collect()
+    };
+    match bars.remove(i) {
+        BARate::Or(alts) => Ok(BARate::Or(distribute_over(alts, &bars))),
+        BARate::And(alts) => Ok(BARate::And(distribute_over(alts, &bars))),
+        _ => unreachable!(),
+    }
+}
+
+// Reduces each operand of an n-ary node, flattening out operands that turn out
+// to be the same operator (unnest pulls those apart), and collapsing the node
+// down to its operand if there's just the one.
+fn reduce_nary(
+    bars: Vec<BARate>,
+    op: fn(Vec<BARate>) -> BARate,
+    unnest: fn(BARate) -> Result<Vec<BARate>, BARate>,
+) -> (BARate, bool) {
+    let mut has_change = false;
+    let mut reduced_bars = Vec::new();
+    for bar in bars {
+        let (reduced, changed) = reduce_ba(bar);
+        has_change |= changed;
+        match unnest(reduced) {
+            Ok(inner) => {
+                has_change = true;
+                reduced_bars.extend(inner);
+            }
+            Err(reduced) => reduced_bars.push(reduced),
+        }
+    }
+    if reduced_bars.len() == 1 {
+        return (reduced_bars.pop().unwrap(), true);
+    }
+    (op(reduced_bars), has_change)
+}
+
+// NOTE/TODO: Should prove termination, just for sanity. Should also prove
+// existence and structure of normal form. For now, there's only
+// test_reduce_ba_fixpoint_terminates, which checks both on generated BARates.
+fn reduce_ba_fixpoint(bar: BARate) -> BARate {
+    let (reduced, has_change) = reduce_ba(bar);
+    if has_change {
+        reduce_ba_fixpoint(reduced)
+    } else {
         reduced
     }
 }
 
+/// Decides whether sr1 <: sr2, i.e. whether every stream that sr1 allows is
+/// also allowed by sr2. False if the solver gave up; see stream_sub_explain to
+/// tell that apart from a refutation.
 pub fn stream_sub(sr1: &StreamRate, sr2: &StreamRate) -> bool {
-    let norm_ba_lhs = reduce_ba_fixpoint(convert_to_ba(sr1, &SubRel::Lhs));
-    let norm_ba_rhs = reduce_ba_fixpoint(convert_to_ba(sr2, &SubRel::Rhs));
-    ba_rate_sub(&norm_ba_lhs, &norm_ba_rhs)
+    stream_sub_explain(sr1, sr2).holds()
+}
+
+/// How a subtyping check came out. W is what a refutation comes with, which is
+/// a Counterexample everywhere in the public API.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubResult<W = Counterexample> {
+    /// The relation holds.
+    Holds,
+    /// The relation doesn't hold (as far as stream_sub can tell, see the
+    /// crate docs), and here's why.
+    Refuted(W),
+    /// Z3 gave up before deciding some case, e.g. because it hit the timeout
+    /// or resource limit in the SolverConfig. This is Z3's reason, plus the
+    /// limits, when Z3 doesn't say which one it hit.
+    Unknown(String),
+}
+
+impl<W> SubResult<W> {
+    pub fn holds(&self) -> bool {
+        matches!(self, SubResult::Holds)
+    }
+
+    /// The witness, if the relation was refuted.
+    pub fn counterexample(&self) -> Option<&W> {
+        match self {
+            SubResult::Refuted(witness) => Some(witness),
+            SubResult::Holds | SubResult::Unknown(_) => None,
+        }
+    }
+}
+
+/// Like stream_sub, but on failure returns a counterexample: a window size, the
+/// rate each leaf takes at that window size, and an arrival trace. If the
+/// solver gave up instead, returns why.
+pub fn stream_sub_explain(sr1: &StreamRate, sr2: &StreamRate) -> SubResult {
+    stream_sub_report(sr1, sr2).result
+}
+
+/// What stream_sub found out along the way: the normal forms it compared
+/// (printed, since BARates are internal), how many cases it handed to the
+/// solver, and how it came out.
+#[derive(Clone, Debug)]
+pub struct SubReport {
+    pub lhs_normal: String,
+    pub rhs_normal: String,
+    pub smt_cases: usize,
+    pub result: SubResult,
+}
+
+impl SubReport {
+    pub fn holds(&self) -> bool {
+        self.result.holds()
+    }
+}
+
+/// How stream_sub runs the solver.
+#[derive(Clone, Debug)]
+pub struct SolverConfig {
+    /// How many threads to solve a pair's cases on. Each thread gets its own
+    /// Z3 context, and 1 (the default) solves everything on the calling
+    /// thread.
+    pub threads: usize,
+    /// How long Z3 gets for each query before it gives up with Unknown.
+    /// Z3 counts in whole milliseconds.
+    pub timeout: Option<Duration>,
+    /// Z3's resource limit (rlimit) for each query. Unlike the timeout, this
+    /// gives the same answer on every machine.
+    pub rlimit: Option<u32>,
+}
+
+impl Default for SolverConfig {
+    /// One thread, and no limits.
+    fn default() -> Self {
+        SolverConfig {
+            threads: 1,
+            timeout: None,
+            rlimit: None,
+        }
+    }
+}
+
+impl SolverConfig {
+    // A solver on the current thread's context, with our limits set.
+    fn solver(&self) -> Solver {
+        let solver = Solver::new();
+        let mut params = Params::new();
+        if let Some(timeout) = self.timeout {
+            let ms = timeout.as_millis().clamp(1, u32::MAX as u128);
+            params.set_u32("timeout", ms as u32);
+        }
+        if let Some(rlimit) = self.rlimit {
+            params.set_u32("rlimit", rlimit);
+        }
+        solver.set_params(&params);
+        solver
+    }
+
+    // Why Z3 gave up on the last check. NOTE: Once a solver has had a push
+    // (see solve_case), Z3 just says "canceled" when it runs into either
+    // limit, so we say what the limits were.
+    fn unknown_reason(&self, solver: &Solver) -> String {
+        let reason = solver
+            .get_reason_unknown()
+            .unwrap_or_else(|| "unknown".to_string());
+        if reason != "canceled" && reason != "unknown" {
+            return reason;
+        }
+        let mut limits = Vec::new();
+        if let Some(timeout) = self.timeout {
+            limits.push(format!("timeout of {}ms", timeout.as_millis()));
+        }
+        if let Some(rlimit) = self.rlimit {
+            limits.push(format!("rlimit of {}", rlimit));
+        }
+        if limits.is_empty() {
+            reason
+        } else {
+            format!("{}, hit the {}", reason, limits.join(" or the "))
+        }
+    }
+}
+
+/// Like stream_sub_explain, but returns everything in a SubReport.
+pub fn stream_sub_report(sr1: &StreamRate, sr2: &StreamRate) -> SubReport {
+    stream_sub_report_with(sr1, sr2, &SolverConfig::default())
+}
+
+/// Like stream_sub_report, but with the given SolverConfig. Without limits,
+/// whether the relation holds doesn't depend on the config, but when several
+/// cases fail, which counterexample comes back (and smt_cases) can. With
+/// limits, some cases can come back Unknown instead, and the first one stops
+/// the rest, so the result can be Unknown even if a case we didn't get to
+/// would have been refuted.
+pub fn stream_sub_report_with(
+    sr1: &StreamRate,
+    sr2: &StreamRate,
+    config: &SolverConfig,
+) -> SubReport {
+    let (lhs_normal, rhs_normal) = printed_normal_forms(sr1, sr2);
+    let state = CheckState::new(config);
+    let result = match normal_form_sub(sr1, sr2, &state) {
+        SubResult::Refuted(cex) => SubResult::Refuted(checked_witness(sr1, sr2, cex)),
+        result => result,
+    };
+    SubReport {
+        lhs_normal,
+        rhs_normal,
+        smt_cases: state.smt_cases.get(),
+        result,
+    }
+}
+
+fn normal_form_sub(sr1: &StreamRate, sr2: &StreamRate, state: &CheckState) -> SubResult {
+    let Some((ba_lhs, ba_rhs, scale)) = scaled_forms(sr1, sr2) else {
+        return SubResult::Unknown(UNSCALABLE.to_string());
+    };
+    let result = ba_rate_sub_with(&ba_lhs, &ba_rhs, &|r1, r2| rate_sub_explain(r1, r2, state));
+    match result {
+        SubResult::Refuted(cex) => SubResult::Refuted(unscale_counterexample(cex, scale)),
+        SubResult::Holds => SubResult::Holds,
+        SubResult::Unknown(reason) => SubResult::Unknown(reason),
+    }
+}
+
+// Keeps the counterexample's events only if they really are a witness for the
+// original rates: trace_satisfies has to accept them for sr1 and not for sr2.
+// The trace comes from the leaves of one case of the normal forms, so it can
+// miss either way, e.g. for (* 1/5) <: 1/5 the case has 2 events in a window
+// of 5, but there's no trace of (* 1/5) that does that.
+// NOTE: Checking a trace can take exponential time (see trace_satisfies), so if
+// it takes too long, we'd rather not have a trace than not have an answer.
+// Every event takes at least one state, so traces longer than the budget don't
+// get built in the first place.
+const WITNESS_BUDGET: usize = 10_000;
+
+fn checked_witness(sr1: &StreamRate, sr2: &StreamRate, cex: Counterexample) -> Counterexample {
+    let real = cex.events.as_ref().is_some_and(|events| {
+        trace_satisfies_within(sr1, events, WITNESS_BUDGET).is_some_and(|fits| fits.is_ok())
+            && matches!(
+                trace_satisfies_within(sr2, events, WITNESS_BUDGET),
+                Some(Err(TraceError::Violation(_)))
+            )
+    });
+    Counterexample {
+        events: cex.events.filter(|_| real),
+        ..cex
+    }
+}
+
+// The normal forms for a SubReport. NOTE: These are printed before scaling, so
+// they're in the same time units as the input.
+pub(crate) fn printed_normal_forms(sr1: &StreamRate, sr2: &StreamRate) -> (String, String) {
+    (
+        reduce_ba_fixpoint(convert_to_ba(sr1, &SubRel::Lhs)).to_string(),
+        reduce_ba_fixpoint(convert_to_ba(sr2, &SubRel::Rhs)).to_string(),
+    )
+}
+
+/// Like stream_sub, but on failure returns the Raw leaves to blame, as
+/// positions (counting from 0, left to right) in each side. The blamed leaves
+/// come from a minimal unsat core, so dropping any one of their constraints
+/// would make the failing case go through. Returns None if the subtyping
+/// relation holds. If the windows are too far apart to compare at all (see
+/// SubResult::Unknown), every leaf gets the blame.
+pub fn stream_sub_blame(sr1: &StreamRate, sr2: &StreamRate) -> Option<Blame> {
+    stream_sub_blame_with(sr1, sr2, &SolverConfig::default())
+}
+
+/// Like stream_sub_blame, but with the given SolverConfig's limits on each
+/// query. A case Z3 gives up on doesn't get blamed for anything, so this can
+/// return None even if stream_sub_report_with only says Unknown.
+pub fn stream_sub_blame_with(
+    sr1: &StreamRate,
+    sr2: &StreamRate,
+    config: &SolverConfig,
+) -> Option<Blame> {
+    let Some((ba_lhs, ba_rhs, _)) = scaled_forms(sr1, sr2) else {
+        return Some(Blame {
+            lhs: (0..leaf_count(sr1)).collect(),
+            rhs: (0..leaf_count(sr2)).collect(),
+        });
+    };
+    let blame = ba_rate_sub_with(&ba_lhs, &ba_rhs, &|r1, r2| {
+        rate_sub_blame(r1, r2, config).map_or(SubResult::Holds, SubResult::Refuted)
+    });
+    blame.counterexample().cloned()
+}
+
+/// The SMT-LIB queries that stream_sub hands to Z3 for sr1 <: sr2, one for
+/// every case of every pair of normalized subterms it compares. Each query is
+/// satisfiable iff its case holds. Pairs of Raw rates are decided in closed
+/// form, so they don't get one. Pairs of Pars of Raw rates are decided without
+/// the solver too, but still get their queries here, since those are what the
+/// direct decision is meant to agree with. If any window isn't a whole number, every
+/// window in the queries is scaled up by the same factor, which each query
+/// notes in a comment. There are none if that factor would overflow.
+pub fn stream_sub_smt(sr1: &StreamRate, sr2: &StreamRate) -> Vec<String> {
+    let Some((ba_lhs, ba_rhs, scale)) = scaled_forms(sr1, sr2) else {
+        return Vec::new();
+    };
+    let mut pairs = Vec::new();
+    ba_leaf_pairs(&ba_lhs, &ba_rhs, &mut pairs);
+    // NOTE: The queries only get printed, never checked, so there are no
+    // limits to set.
+    let solver = Solver::new();
+    let memo = SymMemo::default();
+    let mut queries = Vec::new();
+    for (rate1, rate2) in pairs.iter() {
+        if let (BARate::Raw(_, _), BARate::Raw(_, _)) = (rate1, rate2) {
+            continue;
+        }
+        for (i, case) in rate_sub_symbolize(rate1, rate2, &memo)
+            .cases
+            .iter()
+            .enumerate()
+        {
+            solver.reset();
+            case.assert_constraints(&solver);
+            solver.assert(case.lhs_events.le(&case.rhs_events));
+            let scale_note = if scale > 1 {
+                format!("; windows scaled by {}\n", scale)
+            } else {
+                String::new()
+            };
+            queries.push(format!(
+                "; {} <: {}, case {}\n{}{}",
+                rate1,
+                rate2,
+                i,
+                scale_note,
+                solver.to_smt2()
+            ));
+        }
+    }
+    queries
+}
+
+// All the pairs that ba_rate_sub_with could hand to its check, in the same
+// order, without stopping early. They're owned, since the ones from the normal
+// forms only live as long as those do.
+fn ba_leaf_pairs(ba_rate1: &BARate, ba_rate2: &BARate, pairs: &mut Vec<(BARate, BARate)>) {
+    match (ba_rate1, ba_rate2) {
+        (r, BARate::And(bars)) => {
+            for bar in bars.iter() {
+                ba_leaf_pairs(r, bar, pairs);
+            }
+        }
+        (BARate::Or(bars), r) => {
+            for bar in bars.iter() {
+                ba_leaf_pairs(bar, r, pairs);
+            }
+        }
+        (r, BARate::RStar(body, min_length)) => {
+            ba_leaf_pairs(r, body, pairs);
+            if let Some(r_body) = repetitions(r, min_length) {
+                ba_leaf_pairs(r_body, body, pairs);
+            }
+        }
+        (r, BARate::RConcat(bars)) => {
+            for bar in bars.iter() {
+                ba_leaf_pairs(r, bar, pairs);
+            }
+            for (r_bar, bar) in phases(r, bars).into_iter().flatten() {
+                ba_leaf_pairs(r_bar, bar, pairs);
+            }
+        }
+        (BARate::Par(bars1), BARate::Par(bars2)) => {
+            normal_leaf_pairs(ba_rate1, ba_rate2, pairs);
+            if bars1.len() == bars2.len() {
+                for bar1 in bars1.iter() {
+                    for bar2 in bars2.iter() {
+                        ba_leaf_pairs(bar1, bar2, pairs);
+                    }
+                }
+            }
+        }
+        (r1, r2) => normal_leaf_pairs(r1, r2, pairs),
+    }
+}
+
+// The same for normal_form_sub_with.
+fn normal_leaf_pairs(ba_rate1: &BARate, ba_rate2: &BARate, pairs: &mut Vec<(BARate, BARate)>) {
+    fn walk(norm1: &BARate, norm2: &BARate, pairs: &mut Vec<(BARate, BARate)>) {
+        match (norm1, norm2) {
+            (r, BARate::And(bars)) | (r, BARate::Or(bars)) => {
+                for bar in bars.iter() {
+                    walk(r, bar, pairs);
+                }
+            }
+            (BARate::Or(bars), r) | (BARate::And(bars), r) => {
+                for bar in bars.iter() {
+                    walk(bar, r, pairs);
+                }
+            }
+            (_, BARate::RStar(..) | BARate::RConcat(_)) => ba_leaf_pairs(norm1, norm2, pairs),
+            (r1, r2) => pairs.push((r1.clone(), r2.clone())),
+        }
+    }
+    let norm1 = reduce_ba_fixpoint(ba_rate1.clone());
+    let norm2 = reduce_ba_fixpoint(ba_rate2.clone());
+    walk(&norm1, &norm2, pairs);
+}
+
+/// Decides whether sr1 and sr2 are the same type, i.e. whether each is a
+/// subtype of the other.
+pub fn stream_equiv(sr1: &StreamRate, sr2: &StreamRate) -> bool {
+    // NOTE: Equal canonical forms are the same type, and that doesn't need the
+    // solver.
+    canonicalize(sr1) == canonicalize(sr2) || (stream_sub(sr1, sr2) && stream_sub(sr2, sr1))
+}
+
+/// Rewrites sr into a canonical form, so that (many) equivalent types compare
+/// equal structurally. In the canonical form:
+/// - nested Pars and Sums are flattened, and single operands are pulled out,
+/// - the operands of Par and Sum are sorted,
+/// - Raw operands of a Par with the same window are merged into one (unless
+///   their events add up to more than a usize holds),
+/// - Raw operands of a Sum that are dominated by another Raw operand (i.e.
+///   looser than it) with a window no longer than theirs are dropped, as are
+///   duplicate operands.
+///
+/// Concats and Stars keep their shape. A Concat phase (or Star repetition)
+/// only has to last as long as its shortest leaf window, so e.g. the last
+/// phase of (. (. 0/5 0/1) 1/1) can start at 1, but flattened out, it
+/// couldn't start before 6. For the same reason, dropping a Sum operand can't
+/// leave the Sum's shortest window any longer.
+///
+/// canonicalize(sr1) == canonicalize(sr2) implies stream_equiv(sr1, sr2), but
+/// not the other way around.
+pub fn canonicalize(sr: &StreamRate) -> StreamRate {
+    match sr {
+        StreamRate::Raw(r) => StreamRate::Raw(r.clone()),
+        StreamRate::Star(body) => StreamRate::Star(Box::new(canonicalize(body))),
+        StreamRate::Concat(srs) => {
+            rebuild(srs.iter().map(canonicalize).collect(), StreamRate::Concat)
+        }
+        StreamRate::Par(srs) => {
+            let mut parts = canonical_operands(srs, |sr| match sr {
+                StreamRate::Par(srs) => Ok(srs),
+                sr => Err(sr),
+            });
+            parts.sort();
+            // Parallel leaves with the same window (and units) just add up.
+            // Sorting them by window (stably, so the rest stays in order) puts
+            // them next to each other.
+            parts.sort_by_key(|part| match part {
+                StreamRate::Raw(r) => Some((r.window, r.timed)),
+                _ => None,
+            });
+            let mut merged: Vec<StreamRate> = Vec::new();
+            for part in parts {
+                if let (Some(StreamRate::Raw(last)), StreamRate::Raw(r)) =
+                    (merged.last_mut(), &part)
+                    && last.window == r.window
+                    && last.timed == r.timed
+                    && let Some(events) = last.events.checked_add(r.events)
+                {
+                    last.events = events;
+                    continue;
+                }
+                merged.push(part);
+            }
+            merged.sort();
+            rebuild(merged, StreamRate::Par)
+        }
+        StreamRate::Sum(srs) => {
+            let mut parts = canonical_operands(srs, |sr| match sr {
+                StreamRate::Sum(srs) => Ok(srs),
+                sr => Err(sr),
+            });
+            parts.sort();
+            parts.dedup();
+            // Under the Or/And rules, a Sum only ever gets as loose as its
+            // tightest operand, so any Raw that's looser than another Raw is
+            // dead weight, as long as it isn't the one that lets a phase end
+            // sooner.
+            let mut kept: Vec<StreamRate> = Vec::new();
+            for part in parts {
+                if let StreamRate::Raw(r) = &part {
+                    let dominated = kept.iter().any(|k| match k {
+                        StreamRate::Raw(k) => k.window <= r.window && raw_sub(k, r),
+                        _ => false,
+                    });
+                    if dominated {
+                        continue;
+                    }
+                    kept.retain(|k| match k {
+                        StreamRate::Raw(k) => !(r.window <= k.window && raw_sub(r, k)),
+                        _ => true,
+                    });
+                }
+                kept.push(part);
+            }
+            rebuild(kept, StreamRate::Sum)
+        }
+    }
+}
+
+// The canonicalized operands of an n-ary node, in order. Operands that are
+// (or turn into, once canonicalized) the same operator get spliced in, which
+// unnest takes care of pulling apart.
+fn canonical_operands(
+    srs: &[StreamRate],
+    unnest: fn(StreamRate) -> Result<Vec<StreamRate>, StreamRate>,
+) -> Vec<StreamRate> {
+    let mut operands = Vec::new();
+    for sr in srs.iter() {
+        match unnest(canonicalize(sr)) {
+            Ok(inner) => operands.extend(inner),
+            Err(canon) => operands.push(canon),
+        }
+    }
+    operands
+}
+
+// Builds an n-ary node out of parts, unless there's only one of them.
+fn rebuild(mut parts: Vec<StreamRate>, op: fn(Vec<StreamRate>) -> StreamRate) -> StreamRate {
+    if parts.len() == 1 {
+        parts.pop().unwrap()
+    } else {
+        op(parts)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::strategies::{self, Shape};
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
+
+    fn raw(events: usize, window: usize) -> StreamRate {
+        StreamRate::Raw(Rate::new(events, window))
+    }
 
-    // TODO: Consider using a property based testing library here to at least
-    // test termination on generated BARates. Generally, some random generation
-    // library would be nice to generate well-formed types to use in tests.
+    fn ba_raw(events: usize, window: usize, pos: usize) -> BARate {
+        BARate::Raw(Rate::new(events, window), pos)
+    }
+
+    // NOTE: test_reduce_ba_fixpoint_terminates checks termination (and the
+    // normal form) on generated BARates too.
     #[test]
     fn test_reduce_ba_fixpoint() {
-        let testba1 = BARate::Raw(Rate {
-            events: 10,
-            window: 20,
-        });
+        assert_eq!(reduce_ba_fixpoint(ba_raw(10, 20, 0)), ba_raw(10, 20, 0));
+        let testba2 = BARate::Par(vec![
+            BARate::Or(vec![ba_raw(10, 20, 0), ba_raw(50, 55, 1)]),
+            BARate::And(vec![ba_raw(30, 5, 2), ba_raw(1000, 5, 3)]),
+        ]);
         assert_eq!(
-            reduce_ba_fixpoint(testba1),
-            BARate::Raw(Rate {
-                events: 10,
-                window: 20
-            })
-        );
-        let testba2 = BARate::Par(
-            Box::new(BARate::Or(
-                Box::new(BARate::Raw(Rate {
-                    events: 10,
-                    window: 20,
-                })),
-                Box::new(BARate::Raw(Rate {
-                    events: 50,
-                    window: 55,
-                })),
-            )),
-            Box::new(BARate::And(
-                Box::new(BARate::Raw(Rate {
-                    events: 30,
-                    window: 5,
-                })),
-                Box::new(BARate::Raw(Rate {
-                    events: 1000,
-                    window: 5,
-                })),
-            )),
+            reduce_ba_fixpoint(testba2),
+            BARate::Or(vec![
+                BARate::And(vec![
+                    BARate::Par(vec![ba_raw(10, 20, 0), ba_raw(30, 5, 2)]),
+                    BARate::Par(vec![ba_raw(10, 20, 0), ba_raw(1000, 5, 3)]),
+                ]),
+                BARate::And(vec![
+                    BARate::Par(vec![ba_raw(50, 55, 1), ba_raw(30, 5, 2)]),
+                    BARate::Par(vec![ba_raw(50, 55, 1), ba_raw(1000, 5, 3)]),
+                ]),
+            ])
         );
+        // Nested operators of the same kind flatten out, and singletons go
+        // away.
+        let testba3 = BARate::Par(vec![
+            BARate::Par(vec![ba_raw(1, 2, 0), ba_raw(3, 4, 1)]),
+            BARate::LConcat(vec![ba_raw(5, 6, 2)]),
+        ]);
         assert_eq!(
-            reduce_ba_fixpoint(testba2),
-            BARate::Or(
-                Box::new(BARate::And(
-                    Box::new(BARate::Par(
-                        Box::new(BARate::Raw(Rate {
-                            events: 30,
-                            window: 5
-                        })),
-                        Box::new(BARate::Raw(Rate {
-                            events: 10,
-                            window: 20
-                        }))
-                    )),
-                    Box::new(BARate::Par(
-                        Box::new(BARate::Raw(Rate {
-                            events: 1000,
-                            window: 5
-                        })),
-                        Box::new(BARate::Raw(Rate {
-                            events: 10,
-                            window: 20
-                        }))
-                    ))
-                )),
-                Box::new(BARate::And(
-                    Box::new(BARate::Par(
-                        Box::new(BARate::Raw(Rate {
-                            events: 30,
-                            window: 5
-                        })),
-                        Box::new(BARate::Raw(Rate {
-                            events: 50,
-                            window: 55
-                        }))
-                    )),
-                    Box::new(BARate::Par(
-                        Box::new(BARate::Raw(Rate {
-                            events: 1000,
-                            window: 5
-                        })),
-                        Box::new(BARate::Raw(Rate {
-                            events: 50,
-                            window: 55
-                        }))
-                    ))
-                ))
-            )
+            reduce_ba_fixpoint(testba3),
+            BARate::Par(vec![ba_raw(1, 2, 0), ba_raw(3, 4, 1), ba_raw(5, 6, 2)])
         );
     }
 
     #[test]
-    fn test_convert_to_ba() {
-        let sr1 = StreamRate::Raw(Rate {
-            events: 10,
-            window: 12,
-        });
+    fn test_canonicalize() {
+        let canon = |s: &str| canonicalize(&crate::parse::parse_side(s, 0).unwrap()).to_string();
+        assert_eq!(canon("(|| (|| 3/4 1/2) 5/6)"), "(|| 1/2 3/4 5/6)");
+        assert_eq!(canon("(|| 3/4 (. 1/1 2/2) 1/4)"), "(|| 4/4 (. 1/1 2/2))");
+        assert_eq!(canon("(|| (|| 2/1 3/4) 1/4)"), "(|| 2/1 4/4)");
+        assert_eq!(canon("(+ 10/5 (+ 20/5 3/1))"), "(+ 3/1 10/5)");
+        assert_eq!(canon("(+ (. 1/1 2/2) (. 1/1 2/2))"), "(. 1/1 2/2)");
+        // Concats and Stars stay nested, since a phase only has to last as long
+        // as its shortest window: the last phase here can start at 1, but not
+        // in (. 0/5 0/1 1/1). And 1/1 is looser than 0/3, but it's what lets
+        // the phase end at 1.
+        assert_eq!(canon("(. (. 0/5 0/1) 1/1)"), "(. (. 0/5 0/1) 1/1)");
+        assert_eq!(canon("(* (* (|| 2/2 1/1)))"), "(* (* (|| 1/1 2/2)))");
+        assert_eq!(canon("(. (+ 1/1 0/3) 2/1)"), "(. (+ 0/3 1/1) 2/1)");
+        // Events that don't add up in a usize stay apart.
         assert_eq!(
-            convert_to_ba(&sr1, &SubRel::Lhs),
-            BARate::Raw(Rate {
-                events: 10,
-                window: 12,
-            })
+            canon(&format!("(|| {}/5 (|| 1/5 2/5))", usize::MAX)),
+            format!("(|| 3/5 {}/5)", usize::MAX)
         );
+        let a = crate::parse::parse_side("(+ (|| 1/2 3/4) 50/5)", 0).unwrap();
+        let b = crate::parse::parse_side("(+ 60/5 (|| 3/4 1/2) 50/5)", 0).unwrap();
+        assert_eq!(canonicalize(&a), canonicalize(&b));
+        assert!(stream_equiv(&a, &b));
+    }
+
+    #[test]
+    fn test_stream_equiv() {
+        let sr = |s: &str| crate::parse::parse_side(s, 0).unwrap();
+        assert!(stream_equiv(&sr("(|| 10/5 10/5)"), &sr("20/5")));
+        assert!(stream_equiv(&sr("(* 10/5)"), &sr("(* 10/5)")));
+        assert!(!stream_equiv(&sr("10/5"), &sr("20/5")));
+        assert!(stream_equiv(&sr("(|| 1/1 1/2)"), &sr("(|| 1/2 1/1)")));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let sr = crate::parse::parse_side("(. 10/5 (|| 1/2 (* 3/4)))", 0).unwrap();
+        let json = r#"{"concat":[{"raw":{"events":10,"window":5}},{"par":[{"raw":{"events":1,"window":2}},{"star":{"raw":{"events":3,"window":4}}}]}]}"#;
+        assert_eq!(serde_json::to_string(&sr).unwrap(), json);
+        assert_eq!(serde_json::from_str::<StreamRate>(json).unwrap(), sr);
+        assert!(serde_json::from_str::<StreamRate>(r#"{"par":[{"raw":{"events":1}}]}"#).is_err());
+        let rational = crate::parse::parse_side("3/1.5", 0).unwrap();
+        let json = r#"{"raw":{"events":3,"window":[3,2]}}"#;
+        assert_eq!(serde_json::to_string(&rational).unwrap(), json);
+        assert_eq!(serde_json::from_str::<StreamRate>(json).unwrap(), rational);
+        for bad in ["[3,0]", "0", "[0,2]"] {
+            let json = format!(r#"{{"raw":{{"events":3,"window":{}}}}}"#, bad);
+            assert!(serde_json::from_str::<StreamRate>(&json).is_err());
+        }
+        for bad in [r#"{"sum":[]}"#, r#"{"par":[]}"#, r#"{"concat":[]}"#] {
+            assert!(serde_json::from_str::<StreamRate>(bad).is_err());
+        }
+        let nested = r#"{"star":{"par":[{"raw":{"events":1,"window":2}},{"concat":[]}]}}"#;
+        assert!(serde_json::from_str::<StreamRate>(nested).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let sr = StreamRate::Sum(vec![
+            StreamRate::Concat(vec![raw(10, 5), raw(2, 1)]),
+            StreamRate::Star(Box::new(raw(3, 4))),
+        ]);
+        assert_eq!(sr.to_string(), "(+ (. 10/5 2/1) (* 3/4))");
         assert_eq!(
-            convert_to_ba(&sr1, &SubRel::Rhs),
-            BARate::Raw(Rate {
-                events: 10,
-                window: 12,
-            })
+            reduce_ba_fixpoint(convert_to_ba(&sr, &SubRel::Lhs)).to_string(),
+            "(or (. 10/5 2/1) (* 3/4))"
         );
-        let sr2 = StreamRate::Concat(
-            Box::new(StreamRate::Par(
-                Box::new(StreamRate::Raw(Rate {
-                    events: 5,
-                    window: 10,
-                })),
-                Box::new(StreamRate::Raw(Rate {
-                    events: 100,
-                    window: 40,
-                })),
-            )),
-            Box::new(StreamRate::Par(
-                Box::new(StreamRate::Raw(Rate {
-                    events: 7,
-                    window: 8,
-                })),
-                Box::new(StreamRate::Raw(Rate {
-                    events: 42,
-                    window: 88,
-                })),
-            )),
+        assert_eq!(
+            reduce_ba_fixpoint(convert_to_ba(&sr, &SubRel::Rhs)).to_string(),
+            "(and (. 10/5 2/1) (* 3/4))"
         );
+    }
+
+    #[test]
+    fn test_convert_to_ba() {
+        let sr1 = raw(10, 12);
+        assert_eq!(convert_to_ba(&sr1, &SubRel::Lhs), ba_raw(10, 12, 0));
+        assert_eq!(convert_to_ba(&sr1, &SubRel::Rhs), ba_raw(10, 12, 0));
+        let sr2 = StreamRate::Concat(vec![
+            StreamRate::Par(vec![raw(5, 10), raw(100, 40)]),
+            StreamRate::Par(vec![raw(7, 8), raw(42, 88), raw(1, 1)]),
+        ]);
         assert_eq!(
             convert_to_ba(&sr2, &SubRel::Lhs),
-            BARate::LConcat(
-                Box::new(BARate::Par(
-                    Box::new(BARate::Raw(Rate {
-                        events: 5,
-                        window: 10,
-                    })),
-                    Box::new(BARate::Raw(Rate {
-                        events: 100,
-                        window: 40,
-                    }))
-                )),
-                Box::new(BARate::Par(
-                    Box::new(BARate::Raw(Rate {
-                        events: 7,
-                        window: 8,
-                    })),
-                    Box::new(BARate::Raw(Rate {
-                        events: 42,
-                        window: 88,
-                    }))
-                ))
-            )
+            BARate::LConcat(vec![
+                BARate::Par(vec![ba_raw(5, 10, 0), ba_raw(100, 40, 1)]),
+                BARate::Par(vec![ba_raw(7, 8, 2), ba_raw(42, 88, 3), ba_raw(1, 1, 4)]),
+            ])
         );
         assert_eq!(
             convert_to_ba(&sr2, &SubRel::Rhs),
-            BARate::And(
-                Box::new(BARate::Par(
-                    Box::new(BARate::Raw(Rate {
-                        events: 5,
-                        window: 10,
-                    })),
-                    Box::new(BARate::Raw(Rate {
-                        events: 100,
-                        window: 40,
-                    }))
-                )),
-                Box::new(BARate::Par(
-                    Box::new(BARate::Raw(Rate {
-                        events: 7,
-                        window: 8,
-                    })),
-                    Box::new(BARate::Raw(Rate {
-                        events: 42,
-                        window: 88,
-                    }))
-                ))
-            )
-        );
-        let sr3 = StreamRate::Sum(
-            Box::new(StreamRate::Concat(
-                Box::new(StreamRate::Raw(Rate {
-                    events: 5,
-                    window: 10,
-                })),
-                Box::new(StreamRate::Raw(Rate {
-                    events: 100,
-                    window: 40,
-                })),
-            )),
-            Box::new(StreamRate::Par(
-                Box::new(StreamRate::Raw(Rate {
-                    events: 7,
-                    window: 8,
-                })),
-                Box::new(StreamRate::Raw(Rate {
-                    events: 42,
-                    window: 88,
-                })),
-            )),
+            BARate::RConcat(vec![
+                BARate::Par(vec![ba_raw(5, 10, 0), ba_raw(100, 40, 1)]),
+                BARate::Par(vec![ba_raw(7, 8, 2), ba_raw(42, 88, 3), ba_raw(1, 1, 4)]),
+            ])
         );
+        let sr3 = StreamRate::Sum(vec![
+            StreamRate::Concat(vec![raw(5, 10), raw(100, 40)]),
+            StreamRate::Par(vec![raw(7, 8), raw(42, 88)]),
+        ]);
         assert_eq!(
             convert_to_ba(&sr3, &SubRel::Lhs),
-            BARate::Or(
-                Box::new(BARate::LConcat(
-                    Box::new(BARate::Raw(Rate {
-                        events: 5,
-                        window: 10,
-                    })),
-                    Box::new(BARate::Raw(Rate {
-                        events: 100,
-                        window: 40,
-                    }))
-                )),
-                Box::new(BARate::Par(
-                    Box::new(BARate::Raw(Rate {
-                        events: 7,
-                        window: 8,
-                    })),
-                    Box::new(BARate::Raw(Rate {
-                        events: 42,
-                        window: 88,
-                    }))
-                ))
-            )
+            BARate::Or(vec![
+                BARate::LConcat(vec![ba_raw(5, 10, 0), ba_raw(100, 40, 1)]),
+                BARate::Par(vec![ba_raw(7, 8, 2), ba_raw(42, 88, 3)]),
+            ])
         );
         assert_eq!(
             convert_to_ba(&sr3, &SubRel::Rhs),
-            BARate::And(
-                Box::new(BARate::And(
-                    Box::new(BARate::Raw(Rate {
-                        events: 5,
-                        window: 10,
-                    })),
-                    Box::new(BARate::Raw(Rate {
-                        events: 100,
-                        window: 40,
-                    }))
-                )),
-                Box::new(BARate::Par(
-                    Box::new(BARate::Raw(Rate {
-                        events: 7,
-                        window: 8,
-                    })),
-                    Box::new(BARate::Raw(Rate {
-                        events: 42,
-                        window: 88,
-                    }))
-                ))
-            )
+            BARate::And(vec![
+                BARate::RConcat(vec![ba_raw(5, 10, 0), ba_raw(100, 40, 1)]),
+                BARate::Par(vec![ba_raw(7, 8, 2), ba_raw(42, 88, 3)]),
+            ])
         );
     }
 
     #[test]
     fn test_subtyping_constraint_generation() {
-        let sub1_left = StreamRate::Par(
-            Box::new(StreamRate::Raw(Rate {
-                events: 5,
-                window: 10,
-            })),
-            Box::new(StreamRate::Raw(Rate {
-                events: 7,
-                window: 5,
-            })),
+        let sub1_left = StreamRate::Par(vec![raw(5, 10), raw(7, 5)]);
+        let sub1_right = StreamRate::Par(vec![raw(38, 30), raw(2, 1)]);
+        assert!(!stream_sub(&sub1_left, &sub1_right));
+        let sub2_left = StreamRate::Par(vec![raw(10, 3), raw(12, 5)]);
+        let sub2_right = StreamRate::Par(vec![raw(40, 4), raw(10, 5)]);
+        assert!(stream_sub(&sub2_left, &sub2_right));
+        let sub3_left = StreamRate::Par(vec![raw(5, 10), raw(7, 5)]);
+        let sub3_right = StreamRate::Par(vec![raw(38000500, 100000), raw(250940989, 85823490)]);
+        assert!(stream_sub(&sub3_left, &sub3_right));
+        let sub4_left = StreamRate::Concat(vec![
+            StreamRate::Par(vec![raw(5, 10), raw(7, 5)]),
+            StreamRate::Par(vec![
+                raw(60, 200),
+                StreamRate::Par(vec![raw(10, 80), raw(42, 30)]),
+            ]),
+        ]);
+        let sub4_right = StreamRate::Par(vec![raw(600, 10000), raw(1000, 9000)]);
+        assert!(!stream_sub(&sub4_left, &sub4_right));
+        // A three-way Par is the same as the nested one.
+        let sub5_flat = StreamRate::Par(vec![raw(1, 5), raw(2, 5), raw(3, 5)]);
+        let sub5_nested =
+            StreamRate::Par(vec![raw(3, 5), StreamRate::Par(vec![raw(1, 5), raw(2, 5)])]);
+        assert!(stream_sub(&sub5_flat, &sub5_nested));
+        assert!(stream_sub(&sub5_nested, &sub5_flat));
+        // Every phase of a three-phase Concat has to fit on its own, and so
+        // does every run of neighbouring phases.
+        let sub6_left = StreamRate::Concat(vec![raw(10, 5), raw(1, 5), raw(10, 5)]);
+        assert!(stream_sub(&sub6_left, &raw(30, 5)));
+        assert!(!stream_sub(&sub6_left, &raw(5, 5)));
+        // A window that spans a run of phases has to fit whatever each of
+        // them could do in a window that size, since phases don't have to be
+        // as long as their windows: 0, 2, 2 fits (. 1/2 1/2) and has 3 events
+        // in [0, 3), and 0, 1, 1 fits (. 1/1 1/1) and has 3 in [0, 2).
+        let sub7_left = StreamRate::Concat(vec![raw(1, 2), raw(1, 2)]);
+        assert!(!stream_sub(&sub7_left, &raw(2, 3)));
+        assert!(stream_sub(&sub7_left, &raw(4, 3)));
+        let sub8_left = StreamRate::Concat(vec![raw(1, 1), raw(1, 1)]);
+        assert!(!stream_sub(&sub8_left, &raw(2, 2)));
+        assert!(stream_sub(&sub8_left, &raw(4, 2)));
+        // Nesting a Concat doesn't stop a run of phases from fitting a Raw
+        // with room to spare.
+        let sub9_left = StreamRate::Concat(vec![
+            raw(10, 5),
+            raw(10, 5),
+            StreamRate::Concat(vec![raw(10, 5), raw(10, 5), raw(10, 5)]),
+            raw(2, 3),
+        ]);
+        assert!(stream_sub(&sub9_left, &raw(100000000000000, 5)));
+        // That's 6 runs, but still just the 4 leaves, which get asserted once
+        // for all of them.
+        let memo = SymMemo::default();
+        let lhs = convert_to_ba(&sub6_left, &SubRel::Lhs);
+        let symbolized = rate_sub_symbolize(&lhs, &convert_to_ba(&raw(5, 5), &SubRel::Rhs), &memo);
+        assert_eq!(symbolized.cases.len(), 6);
+        assert_eq!(symbolized.leaves.len(), 4);
+        // Comparing the same Lhs against something else reuses its
+        // symbolization, leaves and all. That's one entry for the Concat and
+        // one for each of its phases, plus one for each Rhs.
+        let again = rate_sub_symbolize(&lhs, &convert_to_ba(&raw(30, 5), &SubRel::Rhs), &memo);
+        assert_eq!(memo.0.borrow().len(), 6);
+        assert_eq!(again.leaves[..3], symbolized.leaves[..3]);
+    }
+
+    #[test]
+    fn test_stream_sub_explain() {
+        // Raw-Raw failures are explained in closed form: two bursts of 10
+        // events, 5 ticks apart, both land in a single window of 7.
+        let raw_left = raw(10, 5);
+        let raw_right = raw(15, 7);
+        let cex = stream_sub_explain(&raw_left, &raw_right)
+            .counterexample()
+            .cloned()
+            .unwrap();
+        assert_eq!(cex.window, Window::from_integer(7));
+        assert_eq!(cex.lhs_events, 20);
+        assert_eq!(cex.rhs_events, 15);
+        let events = cex.events.unwrap();
+        assert_eq!(events.len(), 20);
+        assert!(events.iter().all(|e| *e < Window::from_integer(7)));
+        assert!(stream_sub_explain(&raw_left, &raw_left).holds());
+        // Anything else goes through the solver, and the witness comes from
+        // the model. The trace here would be each leaf's bursts, and those fit
+        // the right-hand side, so there isn't one.
+        let par_left = StreamRate::Par(vec![raw(5, 10), raw(7, 5)]);
+        let par_right = StreamRate::Par(vec![raw(38, 30), raw(2, 1)]);
+        let cex = stream_sub_explain(&par_left, &par_right)
+            .counterexample()
+            .cloned()
+            .unwrap();
+        assert!(cex.lhs_events > cex.rhs_events);
+        assert_eq!(cex.lhs_leaves.len(), 2);
+        assert_eq!(cex.rhs_leaves.len(), 2);
+        for leaf in cex.lhs_leaves.iter().chain(cex.rhs_leaves.iter()) {
+            assert_eq!(leaf.assigned.window, cex.window);
+        }
+        assert_eq!(
+            cex.lhs_leaves
+                .iter()
+                .map(|l| l.assigned.events)
+                .sum::<usize>(),
+            cex.lhs_events
+        );
+        assert_eq!(cex.events, None);
+        // Every trace that does come back has to really be one: (* 1/5) can't
+        // put 2 events in a window of 5 any more than 1/5 can, and the bursts
+        // of (. 10/5 2/5) all start at 0.
+        let trace_of = |lhs: &str, rhs: &str| {
+            let (lhs, rhs) = crate::parse::parse(&format!("{} <: {}", lhs, rhs)).unwrap();
+            let cex = stream_sub_explain(&lhs, &rhs).counterexample().cloned();
+            cex.unwrap().events
+        };
+        assert_eq!(trace_of("(* 1/5)", "1/5"), None);
+        assert_eq!(trace_of("(. 10/5 2/5)", "11/5"), None);
+        let events = trace_of("(. 10/5 3/5)", "3/5").unwrap();
+        assert!(crate::trace::trace_satisfies(&raw(10, 5), &events).is_ok());
+        assert!(crate::trace::trace_satisfies(&raw(3, 5), &events).is_err());
+    }
+
+    #[test]
+    fn test_stream_sub_blame() {
+        let raw_left = raw(10, 5);
+        let raw_right = raw(15, 7);
+        assert_eq!(
+            stream_sub_blame(&raw_left, &raw_right),
+            Some(Blame {
+                lhs: vec![0],
+                rhs: vec![0],
+            })
         );
-        let sub1_right = StreamRate::Par(
-            Box::new(StreamRate::Raw(Rate {
-                events: 38,
-                window: 30,
-            })),
-            Box::new(StreamRate::Raw(Rate {
-                events: 2,
-                window: 1,
-            })),
+        assert_eq!(stream_sub_blame(&raw_left, &raw_left), None);
+        // The right-hand side is a Sum (so an And of its two branches), and
+        // only the second branch fails. Leaves are numbered across the whole
+        // side, so the blame should point at leaves 2 and 3 only.
+        let par_left = StreamRate::Par(vec![raw(5, 10), raw(7, 5)]);
+        let sum_right = StreamRate::Sum(vec![
+            StreamRate::Par(vec![raw(500, 30), raw(200, 1)]),
+            StreamRate::Par(vec![raw(38, 30), raw(2, 1)]),
+        ]);
+        let blame = stream_sub_blame(&par_left, &sum_right).unwrap();
+        assert!(!blame.rhs.is_empty());
+        assert!(blame.lhs.iter().all(|pos| *pos < 2));
+        assert!(blame.rhs.iter().all(|pos| *pos == 2 || *pos == 3));
+    }
+
+    #[test]
+    fn test_star() {
+        let sr = |s: &str| crate::parse::parse_side(s, 0).unwrap();
+        let star = StreamRate::Star(Box::new(StreamRate::Sum(vec![raw(10, 5), raw(3, 2)])));
+        // Splitting the Or doesn't make the repetitions any longer than the
+        // 2 the Sum takes.
+        assert_eq!(
+            reduce_ba_fixpoint(convert_to_ba(&star, &SubRel::Lhs)),
+            BARate::Or(vec![
+                BARate::LStar(Box::new(ba_raw(10, 5, 0)), Window::from_integer(2)),
+                BARate::LStar(Box::new(ba_raw(3, 2, 1)), Window::from_integer(2)),
+            ])
         );
-        assert_eq!(stream_sub(&sub1_left, &sub1_right), false);
-        let sub2_left = StreamRate::Par(
-            Box::new(StreamRate::Raw(Rate {
-                events: 10,
-                window: 3,
-            })),
-            Box::new(StreamRate::Raw(Rate {
-                events: 12,
-                window: 5,
-            })),
+        assert_eq!(
+            reduce_ba_fixpoint(convert_to_ba(&star, &SubRel::Rhs)),
+            BARate::RStar(
+                Box::new(BARate::And(vec![ba_raw(10, 5, 0), ba_raw(3, 2, 1)])),
+                Window::from_integer(2)
+            )
         );
-        let sub2_right = StreamRate::Par(
-            Box::new(StreamRate::Raw(Rate {
-                events: 40,
-                window: 4,
-            })),
-            Box::new(StreamRate::Raw(Rate {
-                events: 10,
-                window: 5,
-            })),
+        // A window of 5 can only touch two repetitions of 10/5, since each
+        // repetition is at least 5 long.
+        let star_raw = StreamRate::Star(Box::new(raw(10, 5)));
+        assert!(stream_sub(&star_raw, &raw(20, 5)));
+        assert!(!stream_sub(&star_raw, &raw(19, 5)));
+        // But as long as a repetition of the Sum can be, not as long as one of
+        // 2/1 can: 0, 1, 2, 3, 4 is a repetition for each event, and has 5 in
+        // [0, 5). Nor can a Star of a Star be flattened: 1.9, 2, 2 fits
+        // (* (* 1/2)), but puts 3 events in [1.9, 2.9).
+        assert!(!stream_sub(&sr("(* (+ 2/1 1/5))"), &sr("2/5")));
+        assert!(!stream_sub(&sr("(* (* 1/2))"), &sr("2/1")));
+        assert!(stream_sub(&sr("(* (* 1/2))"), &sr("6/1")));
+        // On the Rhs, a Star goes repetition by repetition when the Lhs is
+        // one too, and its repetitions last as long.
+        assert!(stream_sub(&star_raw, &star_raw));
+        assert!(stream_sub(&star_raw, &sr("(* 20/5)")));
+        assert!(stream_sub(&sr("(* 1/2)"), &sr("(* 1/1)")));
+        assert!(stream_sub(&sr("(+ (* 1/3) 2/1)"), &sr("(* 1/3)")));
+        assert!(!stream_sub(&sr("(* (+ 1/3 5/1))"), &sr("(* 1/3)")));
+        // Otherwise it's just as good as its body.
+        assert!(stream_sub(&raw(10, 5), &star_raw));
+        assert!(!stream_sub(&raw(11, 5), &star_raw));
+        assert!(stream_sub(&sr("(* 1/1)"), &sr("(|| (* 3/1) 1/1)")));
+        assert!(!stream_sub(
+            &StreamRate::Concat(vec![raw(10, 5), star_raw.clone()]),
+            &raw(10, 5)
+        ));
+    }
+
+    #[test]
+    fn test_same_operator() {
+        let sr = |s: &str| crate::parse::parse_side(s, 0).unwrap();
+        // None of these hold on the normal forms alone, since those only line
+        // up the leaves window by window.
+        for s in [
+            "(+ 1/1 2/3)",
+            "(|| 1/1 1/2)",
+            "(. 1/1 1/2)",
+            "(* (* 1/1))",
+            "(* (+ 1/1 2/3))",
+            "(* (. 1/1 1/2))",
+            "(|| (+ 1/1 2/3) 1/2)",
+            "(. (+ 1/1 1/2) 1/2)",
+        ] {
+            assert!(stream_sub(&sr(s), &sr(s)), "{} <: {}", s, s);
+        }
+        assert!(stream_sub(&sr("(|| 1/1 1/2)"), &sr("(|| 1/2 1/1)")));
+        assert!(!stream_sub(&sr("(|| 1/1 1/2)"), &sr("(|| 1/1 1/3)")));
+        assert!(stream_sub(&sr("(. 1/1 1/2)"), &sr("(. 2/1 1/2)")));
+        // A phase that can end sooner lets the next one start sooner: 0, 1, 3
+        // fits the Lhs, but the Rhs can't start its second phase before 3.
+        assert!(!stream_sub(&sr("(. (+ 1/3 5/1) 1/2)"), &sr("(. 1/3 1/2)")));
+    }
+
+    #[test]
+    fn test_solver_threads() {
+        // Concats give plenty of cases to spread around. Every thread count
+        // has to agree on whether the relation holds, and when it does, every
+        // case got checked.
+        let sr = |s: &str| crate::parse::parse_side(s, 0).unwrap();
+        let lhs = sr("(. 10/5 (|| 1/1 2/2) 3/3)");
+        for (rhs, holds) in [("(|| 300/50 40/10)", true), ("(|| 3/50 4/10)", false)] {
+            let rhs = sr(rhs);
+            let reports: Vec<SubReport> = [1, 2, 8]
+                .into_iter()
+                .map(|threads| {
+                    stream_sub_report_with(
+                        &lhs,
+                        &rhs,
+                        &SolverConfig {
+                            threads,
+                            ..SolverConfig::default()
+                        },
+                    )
+                })
+                .collect();
+            for report in reports.iter() {
+                assert_eq!(report.holds(), holds);
+                assert!(report.smt_cases > 0);
+                if holds {
+                    assert_eq!(report.smt_cases, reports[0].smt_cases);
+                }
+                if let Some(cex) = report.result.counterexample() {
+                    assert!(cex.lhs_events > cex.rhs_events);
+                }
+            }
+        }
+        // The same check over and over on one thread: a failure interrupts the
+        // other workers, and that mustn't carry over to the next check.
+        let (rhs, config) = (
+            sr("(|| 3/50 4/10)"),
+            SolverConfig {
+                threads: 3,
+                ..SolverConfig::default()
+            },
         );
-        assert_eq!(stream_sub(&sub2_left, &sub2_right), true);
-        let sub3_left = StreamRate::Par(
-            Box::new(StreamRate::Raw(Rate {
-                events: 5,
-                window: 10,
-            })),
-            Box::new(StreamRate::Raw(Rate {
-                events: 7,
-                window: 5,
-            })),
+        for _ in 0..8 {
+            assert!(!stream_sub_report_with(&lhs, &rhs, &config).holds());
+        }
+        // The other workers get the cases translated to their own contexts,
+        // which has to give back the same variables, and the same verdicts.
+        let cases = rate_sub_symbolize(
+            &BARate::LConcat(vec![ba_raw(10, 5, 0), ba_raw(1, 1, 1)]),
+            &BARate::Par(vec![ba_raw(3, 50, 0), ba_raw(4, 10, 1)]),
+            &SymMemo::default(),
         );
-        let sub3_right = StreamRate::Par(
-            Box::new(StreamRate::Raw(Rate {
-                events: 38000500,
-                window: 100000,
-            })),
-            Box::new(StreamRate::Raw(Rate {
-                events: 250940989,
-                window: 85823490,
-            })),
+        let verdicts = |cases: &SubCases| -> Vec<(String, String, bool)> {
+            let solver = Solver::new();
+            cases.assert_leaves(&solver);
+            cases
+                .cases
+                .iter()
+                .map(|case| {
+                    let res = solve_case(&solver, case, &SolverConfig::default());
+                    (
+                        case.window.to_string(),
+                        case.lhs_events.to_string(),
+                        res.holds(),
+                    )
+                })
+                .collect()
+        };
+        let shared = cases.synchronized();
+        let translated =
+            thread::scope(|scope| scope.spawn(|| verdicts(&shared.recover())).join().unwrap());
+        assert_eq!(translated, verdicts(&cases));
+        assert!(translated.iter().any(|(_, _, holds)| !holds));
+    }
+
+    #[test]
+    fn test_solver_limits() {
+        // With next to no resources, Z3 gives up, and we say so instead of
+        // claiming the relation doesn't hold.
+        let sr = |s: &str| crate::parse::parse_side(s, 0).unwrap();
+        let (lhs, rhs) = (sr("(. 10/5 (|| 1/1 2/2))"), sr("(|| 300/50 40/10)"));
+        let config = SolverConfig {
+            rlimit: Some(1),
+            ..SolverConfig::default()
+        };
+        let report = stream_sub_report_with(&lhs, &rhs, &config);
+        assert!(
+            matches!(&report.result, SubResult::Unknown(reason) if reason.contains("rlimit of 1"))
         );
-        assert_eq!(stream_sub(&sub3_left, &sub3_right), true);
-        let sub4_left = StreamRate::Concat(
-            Box::new(StreamRate::Par(
-                Box::new(StreamRate::Raw(Rate {
-                    events: 5,
-                    window: 10,
-                })),
-                Box::new(StreamRate::Raw(Rate {
-                    events: 7,
-                    window: 5,
-                })),
-            )),
-            Box::new(StreamRate::Par(
-                Box::new(StreamRate::Raw(Rate {
-                    events: 60,
-                    window: 200,
-                })),
-                Box::new(StreamRate::Par(
-                    Box::new(StreamRate::Raw(Rate {
-                        events: 10,
-                        window: 80,
-                    })),
-                    Box::new(StreamRate::Raw(Rate {
-                        events: 42,
-                        window: 30,
-                    })),
-                )),
-            )),
+        assert!(!report.holds());
+        // Same on one thread and on several, where the first Unknown stops
+        // the rest.
+        for threads in [1, 4] {
+            let config = SolverConfig {
+                threads,
+                ..config.clone()
+            };
+            let report = stream_sub_report_with(&lhs, &rhs, &config);
+            assert!(
+                matches!(&report.result, SubResult::Unknown(reason) if reason.contains("rlimit of 1"))
+            );
+            if threads == 1 {
+                assert_eq!(report.smt_cases, 1);
+            }
+        }
+        assert_eq!(stream_sub_explain(&lhs, &rhs), SubResult::Holds);
+        // Blame gets the same limits, and a case Z3 gives up on doesn't get
+        // blamed for anything, since it might hold.
+        assert_eq!(stream_sub_blame(&lhs, &rhs), None);
+        assert_eq!(stream_sub_blame_with(&lhs, &rhs, &config), None);
+        // Closed-form decisions don't care about limits.
+        let report = stream_sub_report_with(&raw(10, 5), &raw(5, 5), &config);
+        assert!(report.result.counterexample().is_some());
+        // An Or holds if anything does, and is only refuted if nothing's
+        // unknown. An And is refuted if anything is, and only holds if
+        // nothing's unknown.
+        let unknown = || SubResult::<()>::Unknown("timeout".to_string());
+        let results = || [SubResult::Refuted(()), unknown(), SubResult::Holds];
+        assert_eq!(any_holds(results().into_iter()), SubResult::Holds);
+        assert_eq!(any_holds(results().into_iter().take(2)), unknown());
+        assert_eq!(all_hold(results().into_iter()), SubResult::Refuted(()));
+        assert_eq!(all_hold(results().into_iter().skip(1)), unknown());
+    }
+
+    #[test]
+    fn test_rational_windows() {
+        let rate = |events, numer, denom| Rate {
+            events,
+            window: Window::new(numer, denom),
+            timed: false,
+        };
+        assert_eq!(rate(3, 3, 2).to_string(), "3/1.5");
+        assert_eq!(rate(1000, 1, 4000).to_string(), "1000/0.00025");
+        assert_eq!(rate(1, 1, 3).to_string(), "1/[1/3]");
+        let sr = |s: &str| crate::parse::parse_side(s, 0).unwrap();
+        // Raw-Raw, in closed form.
+        assert!(stream_sub(&sr("3/1.5"), &sr("3/1")));
+        assert!(!stream_sub(&sr("3/1.5"), &sr("2/1")));
+        assert!(stream_sub(&sr("3/1.5"), &sr("6/3")));
+        // Through the solver, a rational judgment should come out the same
+        // as the same judgment with every window doubled, and so should its
+        // counterexample (in the original time units).
+        let halved = stream_sub_explain(&sr("(|| 3/1.5 1/0.5)"), &sr("4/1.5"));
+        let doubled = stream_sub_explain(&sr("(|| 3/3 1/1)"), &sr("4/3"));
+        assert_eq!(halved.holds(), doubled.holds());
+        let (halved, doubled) = (
+            halved.counterexample().unwrap(),
+            doubled.counterexample().unwrap(),
+        );
+        assert_eq!(halved.window * 2, doubled.window);
+        assert_eq!(halved.lhs_events, doubled.lhs_events);
+        assert_eq!(halved.lhs_leaves[0].source, rate(3, 3, 2));
+        assert!(stream_sub(&sr("(|| 3/1.5 1/0.5)"), &sr("(|| 3/1.5 3/1.5)")));
+        assert!(
+            stream_sub_smt(&sr("(|| 3/1.5 1/0.5)"), &sr("4/1.5"))[0]
+                .contains("windows scaled by 2")
         );
-        let sub4_right = StreamRate::Par(
-            Box::new(StreamRate::Raw(Rate {
-                events: 600,
-                window: 10000,
-            })),
-            Box::new(StreamRate::Raw(Rate {
-                events: 1000,
-                window: 9000,
-            })),
+    }
+
+    #[test]
+    fn test_time_units() {
+        let sr = |s: &str| crate::parse::parse_side(s, 0).unwrap();
+        assert!(stream_equiv(&sr("60/1m"), &sr("60/60s")));
+        assert!(stream_sub(&sr("1/1s"), &sr("60/1m")));
+        assert!(!stream_sub(&sr("60/1m"), &sr("1/1s")));
+        assert!(stream_sub(&sr("5/250ms"), &sr("20/1s")));
+        assert!(!stream_sub(&sr("(|| 5/250ms 1/1s)"), &sr("20/1s")));
+        assert_eq!(sr("100/60s").to_string(), "100/1m");
+        assert_eq!(
+            sr("(|| 1/1500ms 1/0.5ms)").to_string(),
+            "(|| 1/1500ms 1/500us)"
+        );
+        // Too small to write in days without overflowing, and not a whole
+        // number of any unit.
+        assert_eq!(
+            Rate::timed(1, Window::new(1, usize::MAX)).to_string(),
+            format!("1/[1/{}]s", usize::MAX)
         );
-        assert_eq!(stream_sub(&sub4_left, &sub4_right), false);
+        // Counterexamples come back in seconds.
+        let cex = stream_sub_explain(&sr("60/1m"), &sr("1/1s"))
+            .counterexample()
+            .cloned()
+            .unwrap();
+        assert_eq!(cex.window, Window::from_integer(1));
+        assert_eq!(
+            cex.lhs_leaves[0].source,
+            Rate::timed(60, Window::from_integer(60))
+        );
+        assert!(cex.lhs_leaves[0].assigned.timed);
+        // Days and fractions of a nanosecond don't fit in one scale, so that's
+        // Unknown, rather than an overflow.
+        let (lhs, rhs) = (sr("(|| 1/1d 1/1s)"), sr("1/0.000001ns"));
+        assert!(matches!(
+            stream_sub_explain(&lhs, &rhs),
+            SubResult::Unknown(reason) if reason == UNSCALABLE
+        ));
+        let blame = stream_sub_blame(&lhs, &rhs).unwrap();
+        assert_eq!((blame.lhs, blame.rhs), (vec![0, 1], vec![0]));
+        assert!(stream_sub_smt(&lhs, &rhs).is_empty());
+        // A witness that long isn't worth building, let alone checking.
+        let cex = stream_sub_explain(&sr("1/1ns"), &sr("1/1d"))
+            .counterexample()
+            .cloned()
+            .unwrap();
+        assert_eq!(cex.lhs_events, 86_400_000_000_000);
+        assert_eq!(cex.events, None);
+    }
+
+    #[test]
+    fn test_par_sub_decide() {
+        let sr = |s: &str| crate::parse::parse_side(s, 0).unwrap();
+        // Par-of-Raw pairs never make it to the solver.
+        let report = stream_sub_report(&sr("(|| 10/3 12/5)"), &sr("(|| 40/4 10/5)"));
+        assert!(report.holds());
+        assert_eq!(report.smt_cases, 0);
+        let report = stream_sub_report(&sr("(|| 5/10 7/5)"), &sr("(|| 38/30 2/1)"));
+        assert!(!report.holds());
+        assert_eq!(report.smt_cases, 0);
+        // But anything with a Concat or Star in it still does.
+        let report = stream_sub_report(&sr("(. 5/10 7/5)"), &sr("(|| 38/30 2/1)"));
+        assert!(report.smt_cases > 0);
+    }
+
+    #[test]
+    fn test_raw_rounding() {
+        let sr = |s: &str| crate::parse::parse_side(s, 0).unwrap();
+        // A bigger window on the Lhs gets a whole burst for every window it
+        // touches, and a smaller one on the Rhs only its share, rounded down.
+        assert_eq!(leaf_events_at(&Rate::new(2, 2), &SubRel::Lhs, 3), 4);
+        assert_eq!(leaf_events_at(&Rate::new(2, 2), &SubRel::Lhs, 4), 4);
+        assert_eq!(leaf_events_at(&Rate::new(1, 2), &SubRel::Rhs, 1), 0);
+        assert_eq!(leaf_events_at(&Rate::new(5, 3), &SubRel::Rhs, 2), 2);
+        assert_eq!(leaf_events_at(&Rate::new(6, 3), &SubRel::Rhs, 1), 2);
+        // Neither of these holds: 0, 0, 1, 2, 2 fits the Lhs of the first and
+        // has 5 events in [0, 3), and 0, 1, 2, 3 fits 1/1 but can't be shared
+        // out between 1/2 and 1/3.
+        assert!(!stream_sub(&sr("(|| 2/2 1/3)"), &sr("4/3")));
+        assert!(!stream_sub(&sr("1/1"), &sr("(|| 1/2 1/3)")));
+        assert!(stream_sub(&sr("(|| 2/2 1/3)"), &sr("5/3")));
+        // The solver has to round the same way.
+        let config = SolverConfig {
+            threads: 1,
+            ..SolverConfig::default()
+        };
+        let state = CheckState::new(&config);
+        let smt = rate_sub_solve(&ba_par(&[(2, 2), (1, 3)]), &ba_raw(4, 3, 0), &state);
+        assert!(!smt.holds());
+        let smt = rate_sub_solve(&ba_raw(1, 1, 0), &ba_par(&[(1, 2), (1, 3)]), &state);
+        assert!(!smt.holds());
+        let smt = rate_sub_solve(&ba_par(&[(2, 2), (1, 3)]), &ba_raw(5, 3, 0), &state);
+        assert!(smt.holds());
+    }
+
+    fn ba_par(leaves: &[(usize, usize)]) -> BARate {
+        let mut bars: Vec<BARate> = leaves
+            .iter()
+            .enumerate()
+            .map(|(pos, (e, w))| ba_raw(*e, *w, pos))
+            .collect();
+        if bars.len() == 1 {
+            bars.pop().unwrap()
+        } else {
+            BARate::Par(bars)
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+        // The direct decision for Pars of Raws has to agree with the solver,
+        // counterexample totals included. NOTE: Each case is a (slow,
+        // nonlinear) solver call, hence so few of them.
+        #[test]
+        fn test_par_sub_decide_agrees(
+            lhs in prop::collection::vec((0..30usize, 1..30usize), 1..4),
+            rhs in prop::collection::vec((0..30usize, 1..30usize), 1..4),
+        ) {
+            let (lhs, rhs) = (ba_par(&lhs), ba_par(&rhs));
+            let direct = par_sub_decide(
+                &par_leaves(&lhs).unwrap(),
+                &par_leaves(&rhs).unwrap(),
+            );
+            let config = SolverConfig {
+                threads: 1,
+                ..SolverConfig::default()
+            };
+            let smt = rate_sub_solve(&lhs, &rhs, &CheckState::new(&config));
+            prop_assert_eq!(direct.is_none(), smt.holds());
+            if let Some(cex) = direct {
+                prop_assert!(cex.lhs_events > cex.rhs_events);
+                prop_assert_eq!(cex.events.map(|events| events.len()), Some(cex.lhs_events));
+            }
+        }
+    }
+
+    // BARates with every kind of node the Lhs has, and Ors and Ands, anywhere
+    // (not just where convert_to_ba would put them), and leaves numbered left
+    // to right.
+    fn arb_ba_rate(shape: Shape) -> impl Strategy<Value = BARate> {
+        let width = 2..=shape.width;
+        let leaf = strategies::arb_rate(0..30, 1..30usize).prop_map(|r| BARate::Raw(r, 0));
+        leaf.prop_recursive(shape.depth, shape.size, shape.width as u32, move |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), width.clone()).prop_map(BARate::Par),
+                prop::collection::vec(inner.clone(), width.clone()).prop_map(BARate::LConcat),
+                prop::collection::vec(inner.clone(), width.clone()).prop_map(BARate::Or),
+                prop::collection::vec(inner.clone(), width.clone()).prop_map(BARate::And),
+                (inner, 1..30usize).prop_map(|(bar, min_length)| {
+                    BARate::LStar(Box::new(bar), Window::from_integer(min_length))
+                }),
+            ]
+        })
+        .prop_map(|mut bar| {
+            number_leaves(&mut bar, &mut 0);
+            bar
+        })
+    }
+
+    fn number_leaves(bar: &mut BARate, next_pos: &mut usize) {
+        match bar {
+            BARate::Raw(_, pos) => {
+                *pos = *next_pos;
+                *next_pos += 1;
+            }
+            BARate::Par(bars)
+            | BARate::LConcat(bars)
+            | BARate::RConcat(bars)
+            | BARate::Or(bars)
+            | BARate::And(bars) => bars.iter_mut().for_each(|bar| number_leaves(bar, next_pos)),
+            BARate::LStar(bar, _) | BARate::RStar(bar, _) => number_leaves(bar, next_pos),
+        }
+    }
+
+    fn leaf_positions(bar: &BARate, positions: &mut BTreeSet<usize>) {
+        match bar {
+            BARate::Raw(_, pos) => {
+                positions.insert(*pos);
+            }
+            BARate::Par(bars)
+            | BARate::LConcat(bars)
+            | BARate::RConcat(bars)
+            | BARate::Or(bars)
+            | BARate::And(bars) => bars.iter().for_each(|bar| leaf_positions(bar, positions)),
+            BARate::LStar(bar, _) | BARate::RStar(bar, _) => leaf_positions(bar, positions),
+        }
+    }
+
+    // Whether bar is in the normal form that reduce_ba_fixpoint is supposed to
+    // reach: Ors, Ands, RStars and RConcats only above everything else, no
+    // operator directly inside another of the same kind (except for a Star or
+    // an RConcat, which keep their shape), and no operator with a single
+    // operand.
+    fn is_normal(bar: &BARate, below_or_and: bool) -> bool {
+        let same = |bars: &Vec<BARate>, kind: fn(&BARate) -> bool| {
+            bars.len() >= 2 && !bars.iter().any(kind)
+        };
+        match bar {
+            BARate::Raw(..) => true,
+            BARate::Or(bars) => {
+                !below_or_and
+                    && same(bars, |bar| matches!(bar, BARate::Or(_)))
+                    && bars.iter().all(|bar| is_normal(bar, false))
+            }
+            BARate::And(bars) => {
+                !below_or_and
+                    && same(bars, |bar| matches!(bar, BARate::And(_)))
+                    && bars.iter().all(|bar| is_normal(bar, false))
+            }
+            BARate::Par(bars) => {
+                same(bars, |bar| matches!(bar, BARate::Par(_)))
+                    && bars.iter().all(|bar| is_normal(bar, true))
+            }
+            BARate::LConcat(bars) => {
+                same(bars, |bar| matches!(bar, BARate::LConcat(_)))
+                    && bars.iter().all(|bar| is_normal(bar, true))
+            }
+            BARate::LStar(bar, _) => is_normal(bar, true),
+            BARate::RStar(bar, _) => !below_or_and && is_normal(bar, false),
+            BARate::RConcat(bars) => {
+                !below_or_and && bars.len() >= 2 && bars.iter().all(|bar| is_normal(bar, false))
+            }
+        }
+    }
+
+    fn check_config() -> SolverConfig {
+        SolverConfig {
+            threads: 1,
+            ..SolverConfig::default()
+        }
+    }
+
+    // Small rates with small windows, so that the solver doesn't take forever.
+    fn arb_small_stream_rate() -> impl Strategy<Value = StreamRate> {
+        let shape = Shape {
+            depth: 2,
+            size: 4,
+            width: 2,
+        };
+        strategies::arb_stream_rate(strategies::arb_rate(1..4, 1..5usize), shape)
+    }
+
+    proptest! {
+        // Every rewrite has to get somewhere, so the fixpoint is reached in
+        // a bounded number of steps, and it's in normal form when it is.
+        #[test]
+        fn test_reduce_ba_fixpoint_terminates(bar in arb_ba_rate(Shape { depth: 4, size: 16, width: 3 })) {
+            let mut positions = BTreeSet::new();
+            leaf_positions(&bar, &mut positions);
+            let mut steps = 0;
+            let mut reduced = bar;
+            loop {
+                let (next, changed) = reduce_ba(reduced);
+                reduced = next;
+                if !changed {
+                    break;
+                }
+                steps += 1;
+                prop_assert!(steps < 100, "still rewriting after {} steps: {}", steps, reduced);
+            }
+            prop_assert!(is_normal(&reduced, false), "not normal: {}", reduced);
+            let mut reduced_positions = BTreeSet::new();
+            leaf_positions(&reduced, &mut reduced_positions);
+            prop_assert_eq!(positions, reduced_positions);
+            // And once there, there's nothing left to do.
+            let (again, changed) = reduce_ba(reduced.clone());
+            prop_assert!(!changed);
+            prop_assert_eq!(&reduce_ba_fixpoint(again), &reduced);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+        #[test]
+        fn test_raw_sub_agrees(
+            r1 in strategies::arb_rate(0..30, 1..30usize),
+            r2 in strategies::arb_rate(0..30, 1..30usize),
+        ) {
+            let smt = rate_sub_solve(
+                &BARate::Raw(r1.clone(), 0),
+                &BARate::Raw(r2.clone(), 0),
+                &CheckState::new(&check_config()),
+            );
+            prop_assert_eq!(raw_sub(&r1, &r2), smt.holds(), "{} <: {}", r1, r2);
+        }
+
+        // Reflexivity, and a bit more: a rate is a subtype of itself with its
+        // leaves loosened (by nothing, now and then).
+        #[test]
+        fn test_stream_sub_reflexive(
+            (a, b) in arb_small_stream_rate().prop_flat_map(|a| (Just(a.clone()), strategies::loosen(a)))
+        ) {
+            let report = stream_sub_report_with(&a, &b, &check_config());
+            prop_assert!(report.holds(), "{} <: {}: {:?}", a, b, report.result);
+        }
+
+        // NOTE: Random triples hardly ever line up, so b and c are made
+        // looser than a and b, and b is canonicalized so that it's not always
+        // the same shape as a.
+        #[test]
+        fn test_stream_sub_transitive(
+            (a, b, c) in arb_small_stream_rate()
+                .prop_flat_map(|a| (Just(a.clone()), strategies::loosen(a)))
+                .prop_flat_map(|(a, b)| {
+                    let b = canonicalize(&b);
+                    let c = prop_oneof![strategies::loosen(b.clone()), arb_small_stream_rate()];
+                    (Just(a), Just(b), c)
+                })
+        ) {
+            if stream_sub(&a, &b) && stream_sub(&b, &c) {
+                prop_assert!(stream_sub(&a, &c), "{} <: {} <: {}", a, b, c);
+            }
+        }
     }
 }
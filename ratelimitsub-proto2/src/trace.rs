@@ -0,0 +1,731 @@
+use crate::streamrate::Rate;
+use crate::streamrate::StreamRate;
+use crate::streamrate::Window;
+use crate::streamrate::checked_add;
+use crate::streamrate::decimal;
+use crate::streamrate::leaf_count;
+use crate::streamrate::min_length;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+
+// Checking a recorded trace (e.g. from gateway logs) against a StreamRate.
+//
+// A trace is in a type if it can be split up the way the type says: shared
+// out between the branches of a Par, cut into consecutive blocks for the phases
+// of a Concat or the repetitions of a Star, with every Raw leaf getting at most
+// n events in any window of t. As with the Enforcer, a Sum needs every side to
+// hold (that's how stream_sub reads it), and we don't ask streams to last as
+// long as their windows, since a log can stop anywhere. What we do ask for,
+// like the Enforcer, is that a Concat phase (or Star repetition) lasts as long
+// as its shortest leaf window before the next one starts, and the next one
+// starts then (or at the last event before it, if that's later). That's what
+// stream_sub relies on, e.g. for (* 2/5) <: 4/5.
+//
+// We go through the events in order, keeping track of every state the stream
+// could be in so far (which events each leaf has had in its last window, which
+// phase each Concat is in, and so on), and the trace fits if there's still one
+// left at the end. That's exact, and would be hopeless, except that we drop
+// any state that another one beats: e.g. a Concat in the same phase as
+// another, with fewer events so far and free to move on no later. (States in
+// different phases are both kept, since a phase has to last its window before
+// the next one can start.)
+// NOTE: What's left can still grow with the number of ways a Par's branches
+// could have shared out their last window's worth of events.
+
+/// A point in time, in the same units as the rate's windows (seconds for
+/// timed rates, ticks otherwise).
+pub type Timestamp = Window;
+
+/// Where a trace stops fitting a [`StreamRate`]: a window that has more events
+/// than a Raw leaf allows.
+///
+/// For a Raw rate, that's just the first window with too many events in it.
+/// Otherwise, it's the first point where the trace can't be split up any more:
+/// everything before the last event in the window fits the type, and leaf is
+/// one that the last event would have had to go to. If it could have gone to
+/// several (e.g. either branch of a Par), it's the one whose window starts
+/// first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// The leaf's position, numbered like [`crate::Blame`].
+    pub leaf: usize,
+    pub rate: Rate,
+    /// The window is [start, end), with end - start the leaf's window.
+    pub start: Timestamp,
+    pub end: Timestamp,
+    /// How many of the leaf's events are in the window (more than
+    /// rate.events).
+    pub events: usize,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} events in [{}, {}), but leaf {} ({}) allows {}",
+            self.events, self.start, self.end, self.leaf, self.rate, self.rate.events
+        )
+    }
+}
+
+impl Error for Violation {}
+
+/// A time that doesn't fit in a [`Timestamp`]: too late, or too fine-grained
+/// (e.g. an event plus a window with a huge coprime denominator).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimestampOverflow;
+
+impl fmt::Display for TimestampOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a time doesn't fit in a timestamp")
+    }
+}
+
+impl Error for TimestampOverflow {}
+
+/// Why [`trace_satisfies_within`] turned a trace down: it doesn't fit, or we
+/// couldn't tell, since some time we needed (an event plus a window, or when a
+/// Concat phase could end) doesn't fit in a [`Timestamp`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceError {
+    Violation(Violation),
+    Overflow(TimestampOverflow),
+}
+
+impl From<TimestampOverflow> for TraceError {
+    fn from(overflow: TimestampOverflow) -> TraceError {
+        TraceError::Overflow(overflow)
+    }
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::Violation(violation) => violation.fmt(f),
+            TraceError::Overflow(overflow) => overflow.fmt(f),
+        }
+    }
+}
+
+impl Error for TraceError {}
+
+/// Writes a timestamp the way [`crate::parse_timestamp`] reads it: as a
+/// decimal if it can be written exactly that way, and as a fraction like 1/3
+/// otherwise.
+pub fn format_timestamp(t: &Timestamp) -> String {
+    decimal(t).unwrap_or_else(|| t.to_string())
+}
+
+/// Whether a trace of event times conforms to sr. The trace doesn't have to be
+/// sorted, and can have several events at the same time.
+///
+/// Panics if some time we need (an event plus a window, or when a Concat
+/// phase could end) doesn't fit in a [`Timestamp`], which only happens for
+/// times or windows near the limits of one. [`trace_satisfies_within`] says
+/// so instead.
+///
+/// ```
+/// use num_rational::Ratio;
+/// use ratelimitsub_proto2::{parse_side, trace_satisfies};
+///
+/// let sr = parse_side("(. 2/5 (|| 1/1 1/10))", 0).unwrap();
+/// let trace = |ts: &[usize]| ts.iter().map(|t| Ratio::from(*t)).collect::<Vec<_>>();
+/// assert!(trace_satisfies(&sr, &trace(&[0, 1, 6, 6, 7])).is_ok());
+/// // The second phase only takes one event at 5.
+/// let violation = trace_satisfies(&sr, &trace(&[0, 0, 5, 5, 5, 5, 5])).unwrap_err();
+/// assert_eq!(violation.leaf, 1);
+/// assert_eq!(violation.start, Ratio::from(5));
+/// ```
+pub fn trace_satisfies(sr: &StreamRate, trace: &[Timestamp]) -> Result<(), Violation> {
+    match trace_satisfies_within(sr, trace, usize::MAX).expect("no budget to run out of") {
+        Ok(()) => Ok(()),
+        Err(TraceError::Violation(violation)) => Err(violation),
+        Err(TraceError::Overflow(overflow)) => panic!("{}", overflow),
+    }
+}
+
+/// Like [`trace_satisfies`], but gives up (with None) after looking at budget
+/// states, for callers that would rather not know than wait. It can take
+/// exponentially many, e.g. for a Par with several leaves that are all nearly
+/// full. Times that don't fit in a [`Timestamp`] come back as a
+/// [`TraceError::Overflow`], rather than a panic.
+pub fn trace_satisfies_within(
+    sr: &StreamRate,
+    trace: &[Timestamp],
+    mut budget: usize,
+) -> Option<Result<(), TraceError>> {
+    let mut events = trace.to_vec();
+    events.sort();
+    let mut frontier = match State::new(sr, Timestamp::from_integer(0)) {
+        Ok(state) => vec![state],
+        Err(overflow) => return Some(Err(overflow.into())),
+    };
+    for e in events {
+        let mut next: Vec<State> = Vec::new();
+        for state in frontier.iter() {
+            let steps = match state.step(sr, e) {
+                Ok(steps) => steps,
+                Err(overflow) => return Some(Err(overflow.into())),
+            };
+            for mut s in steps {
+                budget = budget.checked_sub(1)?;
+                s.prune(sr, e);
+                // Only keep the states that nothing else we have can beat.
+                if next.iter().any(|kept| kept.covers(sr, &s)) {
+                    continue;
+                }
+                next.retain(|kept| !s.covers(sr, kept));
+                next.push(s);
+            }
+        }
+        if next.is_empty() {
+            // Every state we could be in is stuck, but they can be stuck on
+            // different leaves, so blame whichever window starts first.
+            let blames: Result<Vec<Violation>, _> =
+                frontier.iter().map(|state| state.blame(sr, 0, e)).collect();
+            return Some(match blames {
+                Ok(blames) => Err(TraceError::Violation(
+                    earliest(blames).expect("there was a state before the event"),
+                )),
+                Err(overflow) => Err(overflow.into()),
+            });
+        }
+        frontier = next;
+    }
+    Some(Ok(()))
+}
+
+// Where a stream of some StreamRate could be, after some events.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum State {
+    // The leaf's events from the last window, oldest first.
+    Raw(VecDeque<Timestamp>),
+    Sum(Vec<State>),
+    Par(Vec<State>),
+    // Which phase we're in, the earliest the next one could start, and where
+    // the current one is.
+    Concat(usize, Timestamp, Box<State>),
+    // The earliest the next repetition could start, and where the current one
+    // is.
+    Star(Timestamp, Box<State>),
+}
+
+impl State {
+    // Before any events, for a stream starting at at.
+    fn new(sr: &StreamRate, at: Timestamp) -> Result<State, TimestampOverflow> {
+        let states = |srs: &[StreamRate]| {
+            srs.iter()
+                .map(|sr| State::new(sr, at))
+                .collect::<Result<_, _>>()
+        };
+        Ok(match sr {
+            StreamRate::Raw(_) => State::Raw(VecDeque::new()),
+            StreamRate::Sum(srs) => State::Sum(states(srs)?),
+            StreamRate::Par(srs) => State::Par(states(srs)?),
+            StreamRate::Concat(srs) => State::Concat(
+                0,
+                add(&at, &min_length(&srs[0]))?,
+                Box::new(State::new(&srs[0], at)?),
+            ),
+            StreamRate::Star(sr) => {
+                State::Star(add(&at, &min_length(sr))?, Box::new(State::new(sr, at)?))
+            }
+        })
+    }
+
+    // Every state we could be in after one more event, at e.
+    fn step(&self, sr: &StreamRate, e: Timestamp) -> Result<Vec<State>, TimestampOverflow> {
+        Ok(match (sr, self) {
+            (StreamRate::Raw(r), State::Raw(recent)) => {
+                let mut in_window = 0;
+                for t in recent {
+                    if add(t, &r.window)? > e {
+                        in_window += 1;
+                    }
+                }
+                if in_window >= r.events {
+                    return Ok(Vec::new());
+                }
+                let mut recent = recent.clone();
+                recent.push_back(e);
+                vec![State::Raw(recent)]
+            }
+            // Every side takes the event, in any way it can.
+            (StreamRate::Sum(srs), State::Sum(states)) => {
+                let mut combos = vec![Vec::new()];
+                for (sr, state) in srs.iter().zip(states) {
+                    let nexts = state.step(sr, e)?;
+                    combos = combos
+                        .iter()
+                        .flat_map(|combo| {
+                            nexts.iter().map(move |next| {
+                                let mut combo = combo.clone();
+                                combo.push(next.clone());
+                                combo
+                            })
+                        })
+                        .collect();
+                }
+                combos.into_iter().map(State::Sum).collect()
+            }
+            // Any one branch takes the event.
+            (StreamRate::Par(srs), State::Par(states)) => {
+                let mut nexts = Vec::new();
+                for (i, (sr, state)) in srs.iter().zip(states).enumerate() {
+                    // Two identical branches in the same state are the same
+                    // choice, so only try the first.
+                    if (0..i).any(|j| srs[j] == *sr && states[j] == *state) {
+                        continue;
+                    }
+                    for next in state.step(sr, e)? {
+                        let mut states = states.clone();
+                        states[i] = next;
+                        nexts.push(State::Par(states));
+                    }
+                }
+                nexts
+            }
+            // The current phase takes the event, or a later one does, if it
+            // can have started by now. Each phase starts once the one before it
+            // could end, and phases in between are just empty (but still have
+            // to last).
+            (StreamRate::Concat(srs), State::Concat(phase, ready, state)) => {
+                let mut nexts: Vec<State> = state
+                    .step(&srs[*phase], e)?
+                    .into_iter()
+                    .map(|next| State::Concat(*phase, (*ready).max(e), Box::new(next)))
+                    .collect();
+                let mut start = *ready;
+                for (later, sr) in srs.iter().enumerate().skip(phase + 1) {
+                    if e < start {
+                        break;
+                    }
+                    let ready = add(&start, &min_length(sr))?.max(e);
+                    nexts.extend(
+                        State::new(sr, start)?
+                            .step(sr, e)?
+                            .into_iter()
+                            .map(|next| State::Concat(later, ready, Box::new(next))),
+                    );
+                    start = add(&start, &min_length(sr))?;
+                }
+                nexts
+            }
+            // The current repetition takes the event, or a new one does, if
+            // it can have started by now.
+            (StreamRate::Star(body), State::Star(ready, state)) => {
+                let mut nexts: Vec<State> = state
+                    .step(body, e)?
+                    .into_iter()
+                    .map(|next| State::Star((*ready).max(e), Box::new(next)))
+                    .collect();
+                if e >= *ready {
+                    let next_ready = add(ready, &min_length(body))?.max(e);
+                    nexts.extend(
+                        State::new(body, *ready)?
+                            .step(body, e)?
+                            .into_iter()
+                            .map(|next| State::Star(next_ready, Box::new(next))),
+                    );
+                }
+                nexts
+            }
+            _ => unreachable!("a state always has the same shape as its rate"),
+        })
+    }
+
+    // Forget the events that are more than a window before now, which can't
+    // matter any more (the events come in order). An event whose window end
+    // doesn't fit in a Timestamp is kept, which is only ever slower.
+    fn prune(&mut self, sr: &StreamRate, now: Timestamp) {
+        match (sr, self) {
+            (StreamRate::Raw(r), State::Raw(recent)) => {
+                while recent
+                    .front()
+                    .and_then(|t| checked_add(t, &r.window))
+                    .is_some_and(|end| end <= now)
+                {
+                    recent.pop_front();
+                }
+            }
+            (StreamRate::Sum(srs), State::Sum(states))
+            | (StreamRate::Par(srs), State::Par(states)) => {
+                for (sr, state) in srs.iter().zip(states.iter_mut()) {
+                    state.prune(sr, now);
+                }
+            }
+            (StreamRate::Concat(srs), State::Concat(phase, _, state)) => {
+                state.prune(&srs[*phase], now)
+            }
+            (StreamRate::Star(body), State::Star(_, state)) => state.prune(body, now),
+            _ => unreachable!("a state always has the same shape as its rate"),
+        }
+    }
+
+    // Whether every way of carrying on from other also works from here (as
+    // far as we can easily tell).
+    fn covers(&self, sr: &StreamRate, other: &State) -> bool {
+        match (sr, self, other) {
+            // Fewer events, and none of them newer than the other's, counting
+            // back from the newest.
+            (StreamRate::Raw(_), State::Raw(mine), State::Raw(theirs)) => {
+                mine.len() <= theirs.len()
+                    && mine
+                        .iter()
+                        .rev()
+                        .zip(theirs.iter().rev())
+                        .all(|(m, t)| m <= t)
+            }
+            (StreamRate::Sum(srs), State::Sum(mine), State::Sum(theirs))
+            | (StreamRate::Par(srs), State::Par(mine), State::Par(theirs)) => srs
+                .iter()
+                .zip(mine.iter().zip(theirs))
+                .all(|(sr, (m, t))| m.covers(sr, t)),
+            // The same phase, and it can move on no later.
+            // NOTE: An earlier phase doesn't cover a later one, even though it
+            // could skip ahead, since it can't always do that right away: the
+            // phases in between still have to last.
+            (
+                StreamRate::Concat(srs),
+                State::Concat(p, my_ready, mine),
+                State::Concat(q, their_ready, theirs),
+            ) => p == q && my_ready <= their_ready && mine.covers(&srs[*p], theirs),
+            (
+                StreamRate::Star(body),
+                State::Star(my_ready, mine),
+                State::Star(their_ready, theirs),
+            ) => my_ready <= their_ready && mine.covers(body, theirs),
+            _ => false,
+        }
+    }
+
+    // Find the leaf to blame, when this state can't take an event at e. first
+    // is the position of sr's first leaf.
+    fn blame(
+        &self,
+        sr: &StreamRate,
+        first: usize,
+        e: Timestamp,
+    ) -> Result<Violation, TimestampOverflow> {
+        // The position of each operand's first leaf.
+        let offsets = |srs: &[StreamRate]| {
+            srs.iter()
+                .scan(first, |pos, sr| {
+                    let this = *pos;
+                    *pos += leaf_count(sr);
+                    Some(this)
+                })
+                .collect::<Vec<_>>()
+        };
+        match (sr, self) {
+            // Everything from a window before e is in the way, so the first
+            // window with too many events starts at the oldest of those.
+            (StreamRate::Raw(r), State::Raw(recent)) => {
+                let mut in_window = Vec::new();
+                for t in recent {
+                    if add(t, &r.window)? > e {
+                        in_window.push(*t);
+                    }
+                }
+                let start = in_window.first().copied().unwrap_or(e);
+                Ok(Violation {
+                    leaf: first,
+                    rate: r.clone(),
+                    start,
+                    end: add(&start, &r.window)?,
+                    events: in_window.len() + 1,
+                })
+            }
+            // Some side can't take it (and no branch can, for a Par), so
+            // blame the one of those whose window starts first.
+            (StreamRate::Sum(srs), State::Sum(states))
+            | (StreamRate::Par(srs), State::Par(states)) => {
+                let offsets = offsets(srs);
+                let mut blames = Vec::new();
+                for (i, (sr, state)) in srs.iter().zip(states).enumerate() {
+                    if state.step(sr, e)?.is_empty() {
+                        blames.push(state.blame(sr, offsets[i], e)?);
+                    }
+                }
+                Ok(earliest(blames).expect("some operand can't take the event"))
+            }
+            // Nor can any later phase (or a new repetition), so blame the one
+            // we're in.
+            (StreamRate::Concat(srs), State::Concat(phase, _, state)) => {
+                state.blame(&srs[*phase], offsets(srs)[*phase], e)
+            }
+            (StreamRate::Star(body), State::Star(_, state)) => state.blame(body, first, e),
+            _ => unreachable!("a state always has the same shape as its rate"),
+        }
+    }
+}
+
+// The violation whose window starts first, or ends first if several start
+// together. Ties after that go to the first leaf, so which one we get doesn't
+// depend on the order the states came in.
+fn earliest(violations: Vec<Violation>) -> Option<Violation> {
+    violations
+        .into_iter()
+        .min_by_key(|v| (v.start, v.end, v.leaf))
+}
+
+// a + b, for times that have to fit in a Timestamp.
+fn add(a: &Timestamp, b: &Window) -> Result<Timestamp, TimestampOverflow> {
+    checked_add(a, b).ok_or(TimestampOverflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_side;
+    use crate::strategies::{self, Shape};
+    use crate::streamrate::{canonicalize, stream_sub};
+    use proptest::prelude::*;
+
+    fn trace(times: &[usize]) -> Vec<Timestamp> {
+        times.iter().map(|t| Window::from_integer(*t)).collect()
+    }
+
+    fn check(sr: &str, times: &[usize]) -> Result<(), Violation> {
+        trace_satisfies(&parse_side(sr, 0).unwrap(), &trace(times))
+    }
+
+    #[test]
+    fn test_trace_satisfies() {
+        assert_eq!(check("2/5", &[0, 0, 5, 5, 10]), Ok(()));
+        let violation = check("2/5", &[0, 1, 5, 6, 6]).unwrap_err();
+        assert_eq!(
+            violation,
+            Violation {
+                leaf: 0,
+                rate: Rate::new(2, 5),
+                start: Window::from_integer(5),
+                end: Window::from_integer(10),
+                events: 3,
+            }
+        );
+        assert_eq!(
+            violation.to_string(),
+            "3 events in [5, 10), but leaf 0 (2/5) allows 2"
+        );
+        // Order doesn't matter.
+        assert_eq!(check("2/5", &[5, 0, 10, 5, 0]), Ok(()));
+        assert_eq!(check("0/5", &[]), Ok(()));
+
+        // The first phase can still take 10 at 5, when the second one starts.
+        let sr = "(. 10/5 (|| 45/5 50/100))";
+        let mut times = vec![0; 10];
+        times.extend(vec![5; 105]);
+        assert_eq!(check(sr, &times), Ok(()));
+        times.extend([6, 7]);
+        let violation = check(sr, &times).unwrap_err();
+        assert_eq!(violation.leaf, 1);
+        assert_eq!(violation.events, 46);
+
+        // A Par has to share the events out right: 0 can't go to 1/10, since
+        // then 1/1 would have to take both 2s.
+        assert_eq!(check("(|| 1/10 1/1)", &[0, 2, 2]), Ok(()));
+        assert_eq!(check("(|| 2/1 2/10)", &[0, 0, 1, 2, 2, 2]), Ok(()));
+        // The last 2 could have gone to either branch, and the window of 10
+        // it breaks starts first.
+        let violation = check("(|| 2/1 2/10)", &[0, 0, 0, 2, 2, 2, 2]).unwrap_err();
+        assert_eq!((violation.leaf, violation.events), (1, 3));
+        assert_eq!(violation.start, Window::from_integer(0));
+
+        // Every side of a Sum has to hold.
+        assert_eq!(check("(+ 3/5 2/2)", &[0, 0, 3]), Ok(()));
+        assert_eq!(check("(+ 3/5 2/2)", &[0, 0, 1]).unwrap_err().leaf, 1);
+        // A Par that can't take an event blames one of its own branches.
+        assert_eq!(
+            check("(+ 5/1 (|| 1/1 1/1))", &[0, 0, 0]).unwrap_err().leaf,
+            1
+        );
+
+        // Stars take as many repetitions as they need, but each one has to fit,
+        // and last as long as its window before the next one starts. So
+        // (* 2/5) is never more than 4 in a window of 5, same as stream_sub
+        // says. The 4 here still belongs to the first repetition.
+        assert_eq!(check("(* 2/5)", &[0, 0, 5, 5, 10, 10]), Ok(()));
+        assert_eq!(check("(* 2/5)", &[0, 0, 4, 5, 5]).unwrap_err().events, 3);
+        let violation = check("(* 2/5)", &[0, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!((violation.leaf, violation.events), (0, 3));
+        assert!(stream_sub(
+            &parse_side("(* 2/5)", 0).unwrap(),
+            &parse_side("4/5", 0).unwrap()
+        ));
+        assert!(check("4/5", &[0, 0, 0, 0, 0]).is_err());
+        assert_eq!(check("(* (|| 0/1 1/1))", &[0, 1]), Ok(()));
+        assert!(check("(* (|| 0/1 1/1))", &[0, 0]).is_err());
+        assert_eq!(check("(* 0/1)", &[3]).unwrap_err().leaf, 0);
+        // The same goes for Concat phases, including the ones it skips.
+        assert_eq!(check("(. 1/2 1/3)", &[0, 2]), Ok(()));
+        assert!(check("(. 1/2 1/3)", &[0, 1]).is_err());
+        assert_eq!(check("(. 0/2 0/3 1/1)", &[5]), Ok(()));
+        assert!(check("(. 0/2 0/3 1/1)", &[4]).is_err());
+    }
+
+    #[test]
+    fn test_trace_satisfies_overflow() {
+        // When the first event's window ends doesn't fit in a Timestamp, so we
+        // can't tell whether the second is in it.
+        let max = usize::MAX;
+        let overflow = Some(Err(TraceError::Overflow(TimestampOverflow)));
+        let trace_satisfies =
+            |sr: &StreamRate, trace: &[Timestamp]| trace_satisfies_within(sr, trace, usize::MAX);
+        let sr = parse_side(&format!("1/{}", max), 0).unwrap();
+        assert_eq!(trace_satisfies(&sr, &trace(&[1])), Some(Ok(())));
+        assert_eq!(trace_satisfies(&sr, &trace(&[1, 2])), overflow);
+        let sr = parse_side(&format!("1/[1/{}]", max), 0).unwrap();
+        let t = Window::new(1, max - 1);
+        assert_eq!(trace_satisfies(&sr, &[t, t]), overflow);
+        assert_eq!(
+            TraceError::from(TimestampOverflow).to_string(),
+            "a time doesn't fit in a timestamp"
+        );
+
+        // Nor when a phase could end.
+        let sr = parse_side(&format!("(. 1/{} 1/1)", max), 0).unwrap();
+        assert_eq!(trace_satisfies(&sr, &trace(&[1, max])), overflow);
+        let sr = parse_side(&format!("(* 1/{})", max), 0).unwrap();
+        assert_eq!(trace_satisfies(&sr, &trace(&[0])), Some(Ok(())));
+        assert_eq!(trace_satisfies(&sr, &trace(&[1, max])), overflow);
+    }
+
+    #[test]
+    fn test_trace_satisfies_blame() {
+        // Either branch could have had the 0 (and the other the 2), and either
+        // way both are full at 3, but each way blames different windows. We
+        // go with the one that starts first, whichever order the branches
+        // are in.
+        let violation = check("(|| 1/4 1/6)", &[0, 2, 3]).unwrap_err();
+        assert_eq!(violation.leaf, 0);
+        assert_eq!(violation.rate, Rate::new(1, 4));
+        assert_eq!(violation.start, Window::from_integer(0));
+        assert_eq!(violation.end, Window::from_integer(4));
+        let swapped = check("(|| 1/6 1/4)", &[0, 2, 3]).unwrap_err();
+        assert_eq!(
+            swapped,
+            Violation {
+                leaf: 1,
+                ..violation
+            }
+        );
+    }
+
+    #[test]
+    fn test_trace_satisfies_long() {
+        // Long enough that anything quadratic (or recursive) would show.
+        let times: Vec<usize> = (0..20_000).map(|i| i / 3).collect();
+        assert_eq!(check("(|| 1/1 1/1 1/1)", &times), Ok(()));
+        assert_eq!(check("(. 3/1 (* (|| 2/1 1/1)))", &times), Ok(()));
+        let violation = check("(|| 1/1 1/1)", &times).unwrap_err();
+        assert_eq!(violation.start, Window::from_integer(0));
+    }
+
+    // The most events in any window [e, e + window).
+    fn brute_max_in_window(events: &[usize], window: usize) -> usize {
+        events
+            .iter()
+            .map(|e| {
+                events
+                    .iter()
+                    .filter(|f| *e <= **f && **f < e + window)
+                    .count()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Whether sorted events fit sr, starting at since, by trying every way of
+    // splitting them up. A phase (or repetition) starts once the one before it
+    // has lasted its min_length, or at that one's last event if that's later.
+    fn brute_fits(sr: &StreamRate, events: &[usize], since: usize) -> bool {
+        let min = |sr: &StreamRate| min_length(sr).to_integer();
+        match sr {
+            StreamRate::Raw(r) => brute_max_in_window(events, r.window.to_integer()) <= r.events,
+            StreamRate::Sum(srs) => srs.iter().all(|sr| brute_fits(sr, events, since)),
+            StreamRate::Par(srs) => match srs.split_first() {
+                Some((first, [])) => brute_fits(first, events, since),
+                Some((first, rest)) => (0..1u32 << events.len()).any(|mask| {
+                    let (mine, others): (Vec<_>, Vec<_>) =
+                        (0..events.len()).partition(|i| mask & (1 << i) != 0);
+                    let pick = |is: Vec<usize>| is.iter().map(|i| events[*i]).collect::<Vec<_>>();
+                    brute_fits(first, &pick(mine), since)
+                        && brute_fits(&StreamRate::Par(rest.to_vec()), &pick(others), since)
+                }),
+                None => events.is_empty(),
+            },
+            StreamRate::Concat(srs) => match srs.split_first() {
+                Some((first, [])) => brute_fits(first, events, since),
+                Some((first, _)) => (0..=events.len()).any(|i| {
+                    if !brute_fits(first, &events[..i], since) {
+                        return false;
+                    }
+                    if i == events.len() {
+                        return true;
+                    }
+                    // Phase k gets events[i] first, after the empty ones
+                    // between.
+                    let last = if i > 0 { events[i - 1] } else { since };
+                    let mut start = (since + min(first)).max(last);
+                    (1..srs.len()).any(|k| {
+                        let rest = StreamRate::Concat(srs[k..].to_vec());
+                        let fits = events[i] >= start && brute_fits(&rest, &events[i..], start);
+                        start += min(&srs[k]);
+                        fits
+                    })
+                }),
+                None => events.is_empty(),
+            },
+            StreamRate::Star(body) => {
+                events.is_empty()
+                    || (1..=events.len()).any(|i| {
+                        let start = (since + min(body)).max(events[i - 1]);
+                        brute_fits(body, &events[..i], since)
+                            && (i == events.len()
+                                || (events[i] >= start && brute_fits(sr, &events[i..], start)))
+                    })
+            }
+        }
+    }
+
+    fn arb_stream_rate() -> impl Strategy<Value = StreamRate> {
+        strategies::arb_stream_rate(strategies::arb_rate(0..4, 1..7usize), Shape::default())
+    }
+
+    proptest! {
+        // The greedy splitting (and the search) have to agree with trying
+        // everything, and a violation has to be real.
+        #[test]
+        fn test_trace_satisfies_brute_force(
+            sr in arb_stream_rate(),
+            mut times in prop::collection::vec(0..12usize, 0..10),
+        ) {
+            times.sort();
+            let result = trace_satisfies(&sr, &trace(&times));
+            prop_assert_eq!(result.is_ok(), brute_fits(&sr, &times, 0));
+            if let Err(violation) = result {
+                prop_assert!(violation.leaf < leaf_count(&sr));
+                prop_assert!(violation.events > violation.rate.events);
+                prop_assert_eq!(violation.end - violation.start, violation.rate.window);
+            }
+        }
+
+        // Equal canonical forms have to be the same type, since stream_equiv
+        // and SubtypeCache rely on it.
+        #[test]
+        fn test_canonicalize_keeps_traces(
+            sr in arb_stream_rate(),
+            mut times in prop::collection::vec(0..12usize, 0..10),
+        ) {
+            times.sort();
+            let canonical = canonicalize(&sr);
+            prop_assert_eq!(
+                trace_satisfies(&sr, &trace(&times)).is_ok(),
+                trace_satisfies(&canonical, &trace(&times)).is_ok(),
+                "{} and {} disagree", sr, canonical
+            );
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use ratelimitsub_proto2::{Timestamp, parse_timestamp};
+
+// Trace files for the trace subcommand: one timestamp per line, e.g.
+//
+//   # Comments start with #, and blank lines are skipped.
+//   0
+//   0.5
+//   1.25
+//
+// or CSV, with the timestamps in the first column, or in a column called
+// time or timestamp if there's a header line.
+pub fn read_trace(input: &str) -> Result<Vec<Timestamp>, String> {
+    let mut trace = Vec::new();
+    let mut column = 0;
+    let mut seen_line = false;
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line
+            .split(',')
+            .map(|field| field.trim().trim_matches('"'))
+            .collect();
+        // A header has no timestamps in it.
+        if !seen_line && fields.iter().all(|field| parse_timestamp(field).is_none()) {
+            column = fields
+                .iter()
+                .position(|field| {
+                    field.eq_ignore_ascii_case("time") || field.eq_ignore_ascii_case("timestamp")
+                })
+                .unwrap_or(0);
+            seen_line = true;
+            continue;
+        }
+        seen_line = true;
+        let Some(field) = fields.get(column) else {
+            return Err(format!("line {}: no column {}", i + 1, column + 1));
+        };
+        match parse_timestamp(field) {
+            Some(t) => trace.push(t),
+            None => {
+                return Err(format!(
+                    "line {}: expected a timestamp, found {:?}",
+                    i + 1,
+                    field
+                ));
+            }
+        }
+    }
+    Ok(trace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(ts: &[usize]) -> Vec<Timestamp> {
+        ts.iter().map(|t| Timestamp::from_integer(*t)).collect()
+    }
+
+    #[test]
+    fn test_read_trace() {
+        assert_eq!(
+            read_trace("# a trace\n0\n\n1\n 2 \n"),
+            Ok(times(&[0, 1, 2]))
+        );
+        assert_eq!(read_trace("0,GET\n1,POST\n"), Ok(times(&[0, 1])));
+        assert_eq!(
+            read_trace("path,Timestamp\n/a,\"3\"\n/b,4\n"),
+            Ok(times(&[3, 4]))
+        );
+        assert_eq!(
+            read_trace("0\nsoon\n"),
+            Err("line 2: expected a timestamp, found \"soon\"".to_string())
+        );
+        assert_eq!(
+            read_trace("path,time\n/a\n"),
+            Err("line 2: no column 2".to_string())
+        );
+    }
+}